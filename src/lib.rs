@@ -0,0 +1,4205 @@
+//! The rename engine behind tui_rename: loading files, working out their new
+//! names from find and replace patterns, checking the renames, and making them.
+//!
+//! [`RenameEngine`] is the place to start. The functions it's built from are
+//! public too, for callers that need finer control.
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use regex::{Captures, Regex, RegexBuilder, Replacer};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use unicode_normalization::UnicodeNormalization;
+
+/// A file being renamed, with the name the patterns give it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenameItem {
+    pub original: String,
+    pub renamed: String,
+    pub file: PathBuf,
+    /// What the Original column shows, such as the path within a walked directory
+    pub display: String,
+    /// Set when `renamed` was typed in by hand, so the patterns leave it alone
+    pub manually_edited: bool,
+    /// Picked with the spacebar, for applying to the selection only
+    pub picked: bool,
+    /// Set when `renamed` is a whole new path from matching the full path, rather
+    /// than a name within the file's directory
+    pub whole_path: bool,
+    /// Why the last apply couldn't rename the file, until it's given another name
+    pub error: Option<String>,
+    /// Set when the patterns gave a name longer than `max_name_length`, which was
+    /// cut to fit
+    pub truncated: bool,
+    /// The file's size in bytes when it was loaded, if that could be read
+    pub size: Option<u64>,
+    /// When the file was last modified as it was loaded, if that could be read
+    pub modified: Option<SystemTime>,
+}
+
+impl RenameItem {
+    /// An item for the file at `path`, or `None` if the path has no file name to
+    /// rename, such as `/` or `..`.
+    pub fn new(path: PathBuf) -> Option<Self> {
+        let original = path.file_name()?.to_string_lossy().to_string();
+        let metadata = path.metadata().ok();
+        Some(RenameItem {
+            renamed: original.clone(),
+            display: original.clone(),
+            original,
+            file: path,
+            manually_edited: false,
+            picked: false,
+            whole_path: false,
+            error: None,
+            truncated: false,
+            size: metadata.as_ref().map(|metadata| metadata.len()),
+            modified: metadata.and_then(|metadata| metadata.modified().ok()),
+        })
+    }
+
+    /// Renames using the find pattern then each chained rule, with the replacements
+    /// already expanded by `RenamePatterns::replacements`.
+    pub fn set_pattern(&mut self, patterns: &RenamePatterns, replacements: &[String]) {
+        let path = self.file.to_string_lossy();
+        let source = if patterns.full_path {
+            &path
+        } else {
+            self.original.as_str()
+        };
+        let (stem, extension) = if patterns.stem_only {
+            split_extension(source)
+        } else {
+            (source, None)
+        };
+
+        let range = char_range(stem, patterns.range_start, patterns.range_length);
+        let mut renamed = stem[range.clone()].to_string();
+        for ((find_pat, _), replacement) in patterns.all_rules().zip(replacements) {
+            // An empty pattern matches between every character, which would put
+            // the replacement between them all
+            if find_pat.as_str().is_empty() {
+                continue;
+            }
+            let case_parts = split_case_escapes(replacement);
+            let occurrence = patterns.occurrence;
+            renamed = if patterns.literal || case_parts.len() < 2 {
+                replace_occurrence(find_pat, &renamed, occurrence, replacement.as_str())
+            } else {
+                replace_occurrence(find_pat, &renamed, occurrence, |caps: &Captures| {
+                    expand_with_case(caps, &case_parts)
+                })
+            }
+            .into_owned();
+        }
+        let mut renamed = format!("{}{}{}", &stem[..range.start], renamed, &stem[range.end..]);
+        if let Some(width) = patterns.pad_numbers {
+            renamed = pad_numbers(&renamed, width);
+        }
+        // Only the stem is tidied, so the extension's dot is kept
+        let renamed = tidy_whitespace(&renamed, patterns);
+        let renamed = match extension {
+            Some(extension) => format!("{}.{}", renamed, extension),
+            None => renamed.to_string(),
+        };
+        let mut renamed = renamed;
+        if patterns.ascii_fold {
+            renamed = ascii_fold(&renamed);
+        }
+        // After folding, which can spell a letter in mixed case, such as `Ж` as `Zh`
+        let renamed = change_extension_case(change_name_case(renamed, patterns), patterns);
+        let mut renamed = if patterns.normalize_unicode {
+            renamed.nfc().collect()
+        } else {
+            renamed
+        };
+        let truncated = match patterns.max_name_length {
+            Some(limit) => truncate_name(&mut renamed, limit, patterns.truncate_with_ellipsis),
+            None => false,
+        };
+        self.rename_to(renamed);
+        self.truncated = truncated;
+        self.whole_path = patterns.full_path;
+    }
+
+    /// Gives the item a new name, forgetting why the last one failed if it's different.
+    pub fn rename_to(&mut self, renamed: String) {
+        if renamed != self.renamed {
+            self.error = None;
+        }
+        self.renamed = renamed;
+        self.truncated = false;
+    }
+
+    /// Shows the file by its path within `base`. Files outside `base` keep showing
+    /// what they did.
+    pub fn display_relative_to(&mut self, base: &Path) {
+        let absolute = base.join(&self.file);
+        if let Ok(relative) = absolute.strip_prefix(base) {
+            if relative
+                .components()
+                .all(|part| matches!(part, std::path::Component::Normal(_)))
+            {
+                self.display = relative.to_string_lossy().to_string();
+            }
+        }
+    }
+
+    /// Puts back the original name, undoing the patterns.
+    pub fn reset(&mut self) {
+        self.rename_to(self.original.clone());
+        self.whole_path = false;
+    }
+
+    /// The path the file will end up at, next to the original unless `renamed`
+    /// moves it.
+    pub fn destination(&self) -> PathBuf {
+        self.destination_for(&self.renamed)
+    }
+
+    /// Where the file would end up if it was renamed to `renamed` instead.
+    fn destination_for(&self, renamed: &str) -> PathBuf {
+        if self.whole_path {
+            return PathBuf::from(renamed);
+        }
+        match self.file.parent() {
+            Some(parent) => parent.join(renamed),
+            None => PathBuf::from(renamed),
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        self.file.parent().unwrap_or(Path::new(""))
+    }
+
+    pub fn is_changed(&self) -> bool {
+        if self.whole_path {
+            self.renamed != self.file.to_string_lossy()
+        } else {
+            self.renamed != self.original
+        }
+    }
+
+    /// Whether the file already has its new name, as after applying patterns that
+    /// haven't changed since.
+    pub fn is_in_place(&self) -> bool {
+        self.is_changed() && self.destination() == self.file
+    }
+
+    /// Whether the rename moves the file into another directory.
+    pub fn is_moved(&self) -> bool {
+        if self.whole_path {
+            Path::new(&self.renamed).parent() != self.file.parent()
+        } else {
+            has_separator(&self.renamed)
+        }
+    }
+
+    /// Files we can't inspect are treated as read-only, since renaming them will fail.
+    /// Files that are gone aren't, so applying reports them as gone.
+    pub fn is_read_only(&self) -> bool {
+        match self.file.metadata() {
+            Ok(meta) => meta.permissions().readonly(),
+            Err(err) => err.kind() != io::ErrorKind::NotFound,
+        }
+    }
+}
+
+/// The bytes of `text` from character `start`, counting from 1, that are `length`
+/// characters long. Either can be left out for the start or end of `text`, and a
+/// range past the end is cut short there.
+fn char_range(text: &str, start: Option<usize>, length: Option<usize>) -> Range<usize> {
+    let byte_at = |chars: usize| {
+        text.char_indices()
+            .nth(chars)
+            .map_or(text.len(), |(index, _)| index)
+    };
+    let first = start.unwrap_or(1).saturating_sub(1);
+    let begin = byte_at(first);
+    let end = match length {
+        Some(length) => byte_at(first.saturating_add(length)),
+        None => text.len(),
+    };
+    begin..end
+}
+
+/// Zero-pads every run of digits in `name` that's shorter than `width`.
+fn pad_numbers(name: &str, width: usize) -> String {
+    let mut padded = String::with_capacity(name.len());
+    let mut digits = String::new();
+    for c in name.chars().map(Some).chain([None]) {
+        match c {
+            Some(c) if c.is_ascii_digit() => digits.push(c),
+            _ => {
+                if !digits.is_empty() {
+                    padded.push_str(&format!("{:0>width$}", digits, width = width));
+                    digits.clear();
+                }
+                padded.extend(c);
+            }
+        }
+    }
+    padded
+}
+
+/// Trims and collapses the whitespace in a new name, as the settings ask.
+fn tidy_whitespace(name: &str, patterns: &RenamePatterns) -> String {
+    let name = if patterns.trim_whitespace {
+        name.trim_matches(|c: char| c.is_ascii_whitespace())
+    } else {
+        name
+    };
+    if !patterns.collapse_spaces {
+        return name.to_string();
+    }
+
+    let mut collapsed = String::with_capacity(name.len());
+    let mut in_whitespace = false;
+    for c in name.chars() {
+        if c.is_ascii_whitespace() {
+            if !in_whitespace {
+                collapsed.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            collapsed.push(c);
+            in_whitespace = false;
+        }
+    }
+    collapsed
+}
+
+/// Spells a new name in ASCII: accents are stripped, so `café` becomes `cafe`, and
+/// other scripts are transliterated by `deunicode`, so `Москва` becomes `Moskva`.
+/// Characters with no ASCII spelling are dropped, and separators a transliteration
+/// would add become `-`. Words transliterated from scripts without spaces,
+/// like `東京`, are spaced apart as `Dong Jing`.
+fn ascii_fold(name: &str) -> String {
+    let mut folded = String::with_capacity(name.len());
+    let mut space_before_word = false;
+    for c in name.chars() {
+        if c.is_ascii() {
+            if space_before_word && c.is_ascii_alphanumeric() {
+                folded.push(' ');
+            }
+            folded.push(c);
+            space_before_word = false;
+            continue;
+        }
+        let spelling = deunicode::deunicode_char(c)
+            .unwrap_or("")
+            .replace(['/', '\\'], "-");
+        let word = spelling.trim();
+        if word.is_empty() {
+            continue;
+        }
+        if space_before_word && word.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            folded.push(' ');
+        }
+        folded.push_str(word);
+        space_before_word = spelling.ends_with(' ');
+    }
+    folded
+}
+
+/// Lowercases or uppercases the whole of a new name, as the settings ask. With
+/// `stem_only` the extension is left alone, and with `full_path` so are the
+/// directories the file is in.
+///
+/// Case changes follow Unicode's rules rather than any language's, so `I`
+/// lowercases to `i` even for Turkish names, where it would be `ı`, and `İ`
+/// lowercases to `i` followed by a combining dot above. Some letters change
+/// length, such as `ß`, which uppercases to `SS`.
+fn change_name_case(name: String, patterns: &RenamePatterns) -> String {
+    if !patterns.lowercase_name && !patterns.uppercase_name {
+        return name;
+    }
+    let start = if patterns.full_path {
+        name.rfind(std::path::is_separator)
+            .map_or(0, |index| index + 1)
+    } else {
+        0
+    };
+    let end = if patterns.stem_only {
+        start + split_extension(&name[start..]).0.len()
+    } else {
+        name.len()
+    };
+    let changed = if patterns.lowercase_name {
+        name[start..end].to_lowercase()
+    } else {
+        name[start..end].to_uppercase()
+    };
+    format!("{}{}{}", &name[..start], changed, &name[end..])
+}
+
+/// Cuts the file name at the end of `name` to `limit` characters if it's longer,
+/// keeping its extension unless that alone doesn't fit. With `ellipsis` the cut is
+/// marked with `…`, which counts towards the limit. Returns whether it was cut.
+fn truncate_name(name: &mut String, limit: usize, ellipsis: bool) -> bool {
+    let start = name
+        .rfind(std::path::is_separator)
+        .map_or(0, |index| index + 1);
+    let file_name = &name[start..];
+    if file_name.chars().count() <= limit {
+        return false;
+    }
+    let suffix = match split_extension(file_name) {
+        (_, Some(extension)) => format!(".{}", extension),
+        (_, None) => String::new(),
+    };
+    let marker = if ellipsis { "…" } else { "" };
+    let kept = suffix.chars().count() + marker.chars().count();
+    let shortened: String = if kept < limit {
+        let stem = &file_name[..file_name.len() - suffix.len()];
+        let stem: String = stem.chars().take(limit - kept).collect();
+        format!("{}{}{}", stem, marker, suffix)
+    } else {
+        file_name.chars().take(limit).collect()
+    };
+    name.replace_range(start.., &shortened);
+    true
+}
+
+/// Lowercases or uppercases the final extension of a new name, as the settings
+/// ask. Names without an extension are left alone.
+fn change_extension_case(name: String, patterns: &RenamePatterns) -> String {
+    if !patterns.lowercase_extension && !patterns.uppercase_extension {
+        return name;
+    }
+    match split_extension(&name) {
+        (stem, Some(extension)) => {
+            let extension = if patterns.lowercase_extension {
+                extension.to_lowercase()
+            } else {
+                extension.to_uppercase()
+            };
+            format!("{}.{}", stem, extension)
+        }
+        (_, None) => name,
+    }
+}
+
+/// Splits a file name or path into everything before the final extension and the
+/// extension, as `Path` sees them. Dotfiles such as `.bashrc` have no extension.
+pub fn split_extension(name: &str) -> (&str, Option<&str>) {
+    match Path::new(name).extension() {
+        Some(extension) => {
+            let stem_len = name.len() - extension.len() - 1;
+            (&name[..stem_len], Some(&name[stem_len + 1..]))
+        }
+        None => (name, None),
+    }
+}
+
+/// The find and replace patterns, and the settings for how they make new names.
+pub struct RenamePatterns {
+    // Not always synced with find_pat
+    pub find_pat_raw: String,
+    pub find_pat: Regex,
+    pub replace_pat: String,
+    pub case_insensitive: bool,
+    pub multi_line: bool,
+    pub dot_matches_new_line: bool,
+    /// Treat the find and replace patterns as plain text
+    pub literal: bool,
+    /// Only rename the stem, keeping the extension as is
+    pub stem_only: bool,
+    /// Restart the `${n}` counter in each directory
+    pub counter_per_directory: bool,
+    /// Restart the `${n}` counter for each original extension, ignoring its case
+    pub counter_per_extension: bool,
+    /// What `${n}` is for the first item
+    pub counter_start: i64,
+    /// How much `${n}` goes up by for each item after the first
+    pub counter_step: i64,
+    /// Remove whitespace from the start and end of the new name
+    pub trim_whitespace: bool,
+    /// Replace each run of whitespace in the new name with a single space
+    pub collapse_spaces: bool,
+    /// Only rename the picked items, leaving the others as they are
+    pub selection_only: bool,
+    /// Match against each file's whole path as given, so the result is its new
+    /// path. Relative paths stay relative to the working directory, and moves onto
+    /// another filesystem fall back to copying.
+    pub full_path: bool,
+    /// Create the directories new paths go into when applying. Otherwise renames
+    /// into a directory that doesn't exist are stopped before anything is renamed.
+    pub create_directories: bool,
+    /// Convert new names to the composed (NFC) Unicode form, so names that look
+    /// the same are the same
+    pub normalize_unicode: bool,
+    /// Lowercase the extension of new names, after the patterns
+    pub lowercase_extension: bool,
+    /// Uppercase the extension of new names, after the patterns. Only one of the
+    /// two is set at a time.
+    pub uppercase_extension: bool,
+    /// Lowercase the whole new name, after the patterns
+    pub lowercase_name: bool,
+    /// Uppercase the whole new name, after the patterns. Only one of the two is set
+    /// at a time.
+    pub uppercase_name: bool,
+    /// Copy each file to its new name, leaving the original in place
+    pub copy_files: bool,
+    /// The longest destination path allowed, if the platform has a limit
+    pub max_path_length: Option<usize>,
+    /// Cut new file names longer than this many characters, keeping the extension
+    pub max_name_length: Option<usize>,
+    /// Mark where names were cut to `max_name_length` with `…`
+    pub truncate_with_ellipsis: bool,
+    /// Which match of each pattern to replace, counting from 1, rather than all
+    pub occurrence: Option<usize>,
+    /// Only replace within the name from this character, counting from 1
+    pub range_start: Option<usize>,
+    /// Only replace within this many characters of the name, from `range_start`
+    pub range_length: Option<usize>,
+    /// Zero-pad every number in new names to this many digits
+    pub pad_numbers: Option<usize>,
+    /// Write groups and tokens as `{name}` in replace patterns, instead of `${name}`
+    pub friendly_tokens: bool,
+    /// Treat names that only differ in case as the same when checking for
+    /// conflicts, as the file systems macOS and Windows use by default do
+    pub case_insensitive_conflicts: bool,
+    /// What applying does about names that conflict, rather than asking
+    pub conflict_policy: ConflictPolicy,
+    /// Spell new names in ASCII, stripping accents and transliterating other scripts
+    pub ascii_fold: bool,
+    /// Find and replace patterns applied in order after the main ones, each to the
+    /// output of the last. They keep the flags they were compiled with.
+    pub rules: Vec<(Regex, String)>,
+}
+
+impl Default for RenamePatterns {
+    fn default() -> Self {
+        RenamePatterns {
+            find_pat_raw: "".to_string(),
+            find_pat: Regex::new("").expect("Blank regex returns an error"),
+            replace_pat: "".to_string(),
+            case_insensitive: false,
+            multi_line: false,
+            dot_matches_new_line: false,
+            literal: false,
+            stem_only: true,
+            counter_per_directory: false,
+            counter_per_extension: false,
+            counter_start: 1,
+            counter_step: 1,
+            trim_whitespace: false,
+            collapse_spaces: false,
+            selection_only: false,
+            full_path: false,
+            create_directories: true,
+            normalize_unicode: false,
+            lowercase_extension: false,
+            uppercase_extension: false,
+            lowercase_name: false,
+            uppercase_name: false,
+            copy_files: false,
+            max_path_length: DEFAULT_MAX_PATH_LENGTH,
+            max_name_length: None,
+            truncate_with_ellipsis: false,
+            occurrence: None,
+            range_start: None,
+            range_length: None,
+            pad_numbers: None,
+            friendly_tokens: false,
+            case_insensitive_conflicts: cfg!(any(target_os = "macos", windows)),
+            conflict_policy: ConflictPolicy::Ask,
+            ascii_fold: false,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl RenamePatterns {
+    /// Compiles `find_pat_raw` with the currently selected flags.
+    pub fn compile(&self) -> Result<Regex, regex::Error> {
+        self.compile_pattern(&self.find_pat_raw)
+    }
+
+    /// Compiles a find pattern with the currently selected flags.
+    pub fn compile_pattern(&self, raw: &str) -> Result<Regex, regex::Error> {
+        let pattern = if self.literal {
+            Cow::Owned(regex::escape(raw))
+        } else {
+            Cow::Borrowed(raw)
+        };
+
+        RegexBuilder::new(&pattern)
+            .case_insensitive(self.case_insensitive)
+            .multi_line(self.multi_line)
+            .dot_matches_new_line(self.dot_matches_new_line)
+            .build()
+    }
+
+    /// The main find and replace patterns, followed by the chained rules.
+    fn all_rules(&self) -> impl Iterator<Item = (&Regex, &str)> {
+        std::iter::once((&self.find_pat, self.replace_pat.as_str())).chain(
+            self.rules
+                .iter()
+                .map(|(find, replace)| (find, replace.as_str())),
+        )
+    }
+
+    /// The replace pattern of each rule for `file` at position `counter`, as they
+    /// should be passed to the regex. Tokens are expanded, and `$` isn't treated as
+    /// a group reference in literal mode.
+    ///
+    /// `${stem}` and `${ext}` are always the original file name's, split at its
+    /// final extension without the dot, whatever the regex matched. `$0` is what
+    /// the regex matched, which in a chained rule is the name the rules before it
+    /// made. Tokens win over groups of the same name, so a group named `stem`
+    /// can't be referred to.
+    ///
+    /// `${env:NAME}` is the environment variable `NAME`, or nothing if it isn't set.
+    ///
+    /// `$${n}` is a literal `${n}`, and the same for any other token. The escape is
+    /// the regex crate's own `$$`, which is read before tokens are, so `$$$n` is a
+    /// `$` followed by the counter. In literal mode, where a lone `$` is already
+    /// plain text, only `$${` is an escape.
+    pub fn replacements(&self, counter: i64, file: &Path) -> Vec<String> {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let (stem, ext) = split_extension(&name);
+        self.all_rules()
+            .map(|(_, replace)| {
+                let replace = self.native_replacement(replace);
+                expand_tokens(&replace, self.literal, |name, arg| match name {
+                    "n" => counter_token(counter, arg),
+                    "stem" => Some(stem.to_string()),
+                    "ext" => Some(ext.unwrap_or_default().to_string()),
+                    "env" => arg.map(env_token),
+                    name if TIME_TOKENS.contains(&name) => {
+                        Some(time_token(file, name, arg).unwrap_or_default())
+                    }
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+
+    /// `replace` in the `$` syntax, converted from `{name}` tokens in friendly mode.
+    fn native_replacement<'a>(&self, replace: &'a str) -> Cow<'a, str> {
+        if self.friendly_tokens {
+            Cow::Owned(friendly_to_native(replace, self.literal))
+        } else {
+            Cow::Borrowed(replace)
+        }
+    }
+
+    /// The group references in the replace patterns that their find pattern has no
+    /// group for, like `$3` with only two groups. The regex quietly replaces those
+    /// with nothing.
+    pub fn unresolved_references(&self) -> Vec<String> {
+        let mut unresolved = Vec::new();
+        if self.literal {
+            return unresolved;
+        }
+        for (find_pat, replace) in self.all_rules() {
+            // Our own tokens aren't groups
+            let replace = expand_tokens(&self.native_replacement(replace), false, |name, _| {
+                let token = NAME_TOKENS.contains(&name) || TIME_TOKENS.contains(&name);
+                (token || name == "env").then(String::new)
+            });
+            for reference in group_references(&replace) {
+                let resolves = match reference.parse::<usize>() {
+                    Ok(index) => index < find_pat.captures_len(),
+                    Err(_) => find_pat
+                        .capture_names()
+                        .flatten()
+                        .any(|name| name == reference),
+                };
+                let reference = format!("${{{}}}", reference);
+                if !resolves && !unresolved.contains(&reference) {
+                    unresolved.push(reference);
+                }
+            }
+        }
+        unresolved
+    }
+
+    /// The variables the replace patterns' `${env:name}` tokens read that aren't set,
+    /// or aren't valid Unicode, so expand to nothing.
+    pub fn unset_variables(&self) -> Vec<String> {
+        let mut unset: Vec<String> = Vec::new();
+        for (_, replace) in self.all_rules() {
+            expand_tokens(
+                &self.native_replacement(replace),
+                self.literal,
+                |name, arg| {
+                    if let Some(variable) = arg.filter(|_| name == "env") {
+                        if std::env::var(variable).is_err() && !unset.iter().any(|v| v == variable)
+                        {
+                            unset.push(variable.to_string());
+                        }
+                    }
+                    None
+                },
+            );
+        }
+        unset
+    }
+
+    /// Whether any replace pattern has a token that reads the file's times.
+    fn uses_time_tokens(&self) -> bool {
+        let mut found = false;
+        for (_, replace) in self.all_rules() {
+            expand_tokens(
+                &self.native_replacement(replace),
+                self.literal,
+                |name, _| {
+                    found |= TIME_TOKENS.contains(&name);
+                    None
+                },
+            );
+        }
+        found
+    }
+}
+
+/// Expands our own `${name}` and `${name:arg}` tokens in a replace pattern, so the
+/// result can be passed to `Regex::replace_all`. `value` returns the expansion of
+/// a token, or `None` to leave it for the regex, as with named groups. Expanded
+/// values are escaped so the regex inserts them as is.
+///
+/// In literal mode every other `$` is escaped too, otherwise `$$` is passed through
+/// so that `$${n}` still means a literal `${n}`. Literal mode keeps that escape by
+/// reading `$${` as a literal `${`.
+fn expand_tokens<F>(replace_pat: &str, literal: bool, mut value: F) -> String
+where
+    F: FnMut(&str, Option<&str>) -> Option<String>,
+{
+    let mut expanded = String::with_capacity(replace_pat.len());
+    let push_text = |expanded: &mut String, text: &str| {
+        if literal {
+            expanded.push_str(&text.replace('$', "$$"));
+        } else {
+            expanded.push_str(text);
+        }
+    };
+
+    let mut rest = replace_pat;
+    while let Some(start) = rest.find('$') {
+        push_text(&mut expanded, &rest[..start]);
+        let tail = &rest[start..];
+
+        if !literal && tail.starts_with("$$") {
+            expanded.push_str("$$");
+            rest = &tail[2..];
+            continue;
+        }
+        if literal && tail.starts_with("$${") {
+            push_text(&mut expanded, "${");
+            rest = &tail[3..];
+            continue;
+        }
+
+        let token = tail
+            .strip_prefix("${")
+            .and_then(|token| token.find('}').map(|end| &token[..end]));
+        match token {
+            Some(token) => {
+                let (name, arg) = match token.split_once(':') {
+                    Some((name, arg)) => (name, Some(arg)),
+                    None => (token, None),
+                };
+                match value(name, arg) {
+                    Some(value) => expanded.push_str(&value.replace('$', "$$")),
+                    None => push_text(&mut expanded, &tail[..token.len() + 3]),
+                }
+                rest = &tail[token.len() + 3..];
+            }
+            None => {
+                push_text(&mut expanded, "$");
+                rest = &tail[1..];
+            }
+        }
+    }
+    push_text(&mut expanded, rest);
+
+    expanded
+}
+
+/// Converts `{name}`, `{1}` and `{n:03}` to `${name}`, `${1}` and `${n:03}`, with
+/// `{{` and `}}` for literal braces. A `$` is plain text in this syntax, so it's
+/// escaped, except in literal mode where `expand_tokens` does that already.
+fn friendly_to_native(replace_pat: &str, literal: bool) -> String {
+    let mut native = String::with_capacity(replace_pat.len());
+    let mut rest = replace_pat;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            native.push(c);
+            rest = &rest[2..];
+        } else if c == '{' {
+            match rest.find('}') {
+                Some(end) if !rest[1..end].contains('{') => {
+                    native.push('$');
+                    native.push_str(&rest[..=end]);
+                    rest = &rest[end + 1..];
+                }
+                _ => {
+                    native.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        } else {
+            if c == '$' && !literal {
+                native.push('$');
+            }
+            native.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    native
+}
+
+/// The group names and numbers `replace_pat` refers to, read the way the regex
+/// crate does: `$$` is a literal `$`, `${name}` is braced, and `$name` takes all the
+/// letters, digits and underscores that follow.
+fn group_references(replace_pat: &str) -> Vec<&str> {
+    let mut references = Vec::new();
+    let mut rest = replace_pat;
+    while let Some(start) = rest.find('$') {
+        let tail = &rest[start + 1..];
+        if let Some(after) = tail.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+        if let Some((name, after)) = tail.strip_prefix('{').and_then(|t| t.split_once('}')) {
+            if !name.is_empty() {
+                references.push(name);
+            }
+            rest = after;
+            continue;
+        }
+        let end = tail
+            .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+            .unwrap_or(tail.len());
+        if end > 0 {
+            references.push(&tail[..end]);
+        }
+        rest = &tail[end..];
+    }
+    references
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CaseMode {
+    Unchanged,
+    Upper,
+    Lower,
+}
+
+/// A piece of a replace pattern, split on sed/Perl style case escapes.
+#[derive(Debug, PartialEq)]
+enum CasePart<'a> {
+    /// Replacement text, which may contain group references
+    Text(&'a str),
+    /// `\U`, `\L` or `\E`, which change the case of everything after them
+    Mode(CaseMode),
+    /// `\u` or `\l`, which change the case of the next character only
+    Next(CaseMode),
+}
+
+fn split_case_escapes(replacement: &str) -> Vec<CasePart<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = replacement;
+    let mut searched = 0;
+
+    while let Some(offset) = rest[searched..].find('\\') {
+        let start = searched + offset;
+        let part = match rest[start + 1..].chars().next() {
+            Some('U') => CasePart::Mode(CaseMode::Upper),
+            Some('L') => CasePart::Mode(CaseMode::Lower),
+            Some('E') => CasePart::Mode(CaseMode::Unchanged),
+            Some('u') => CasePart::Next(CaseMode::Upper),
+            Some('l') => CasePart::Next(CaseMode::Lower),
+            // Any other backslash is literal
+            _ => {
+                searched = start + 1;
+                continue;
+            }
+        };
+
+        if start > 0 {
+            parts.push(CasePart::Text(&rest[..start]));
+        }
+        parts.push(part);
+        rest = &rest[start + 2..];
+        searched = 0;
+    }
+    if !rest.is_empty() {
+        parts.push(CasePart::Text(rest));
+    }
+
+    parts
+}
+
+/// Expands the replacement for a single match, changing the case of the expanded
+/// text as the escapes ask. Case changes end with each match.
+fn expand_with_case(caps: &Captures, parts: &[CasePart]) -> String {
+    let mut result = String::new();
+    let mut mode = CaseMode::Unchanged;
+    let mut next = None;
+
+    for part in parts {
+        match part {
+            CasePart::Mode(new_mode) => mode = *new_mode,
+            CasePart::Next(next_mode) => next = Some(*next_mode),
+            CasePart::Text(text) => {
+                let mut expanded = String::new();
+                caps.expand(text, &mut expanded);
+                for c in expanded.chars() {
+                    match next.take().unwrap_or(mode) {
+                        CaseMode::Unchanged => result.push(c),
+                        CaseMode::Upper => result.extend(c.to_uppercase()),
+                        CaseMode::Lower => result.extend(c.to_lowercase()),
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// The value of the environment variable `name` for an `${env:name}` token. Unset
+/// ones are replaced with nothing, and `RenamePatterns::unset_variables` reports
+/// them once rather than for every file.
+fn env_token(name: &str) -> String {
+    std::env::var(name).unwrap_or_default()
+}
+
+/// Tokens that aren't times: the counter and the parts of the original name
+const NAME_TOKENS: [&str; 3] = ["n", "stem", "ext"];
+
+/// Formats the `${n}` counter, where `${n:3}` zero-pads it to three digits.
+fn counter_token(counter: i64, arg: Option<&str>) -> Option<String> {
+    match arg {
+        None => Some(counter.to_string()),
+        Some(width) => {
+            let width: usize = width.parse().ok()?;
+            Some(format!("{:0width$}", counter, width = width))
+        }
+    }
+}
+
+/// Tokens expanding to one of the file's times, formatted by a strftime spec
+const TIME_TOKENS: [&str; 3] = ["mtime", "ctime", "atime"];
+
+/// Formats one of the file's times for a `TIME_TOKENS` token, as `${mtime:%Y}`
+/// says, defaulting to the date. `ctime` is the change time on unix and the
+/// creation time elsewhere.
+fn time_token(file: &Path, name: &str, spec: Option<&str>) -> io::Result<String> {
+    let metadata = file.metadata()?;
+    let time = match name {
+        "mtime" => metadata.modified()?,
+        "atime" => metadata.accessed()?,
+        _ => change_time(&metadata)?,
+    };
+
+    let spec = spec.unwrap_or("%Y-%m-%d");
+    // Formatting with an invalid spec panics, so those expand to nothing
+    if StrftimeItems::new(spec).any(|item| matches!(item, Item::Error)) {
+        return Ok(String::new());
+    }
+    Ok(DateTime::<Local>::from(time).format(spec).to_string())
+}
+
+#[cfg(unix)]
+fn change_time(metadata: &fs::Metadata) -> io::Result<SystemTime> {
+    use std::os::unix::fs::MetadataExt;
+    let since_epoch = Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32);
+    Ok(SystemTime::UNIX_EPOCH + since_epoch)
+}
+
+#[cfg(not(unix))]
+fn change_time(metadata: &fs::Metadata) -> io::Result<SystemTime> {
+    metadata.created()
+}
+
+/// A line for the failed items dialog, saying why `path` couldn't be added.
+pub fn failed_item(path: &Path, reason: &str) -> String {
+    format!("{} — {}", path.to_string_lossy(), reason)
+}
+
+/// Why a path can't be added, in words for the failed items dialog.
+fn describe_error(err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::NotFound => "doesn't exist".to_string(),
+        io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+        _ => err.to_string(),
+    }
+}
+
+/// Why `path` isn't a file we can rename.
+fn access_problem(path: &Path) -> String {
+    match fs::metadata(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound && path.is_symlink() => {
+            "broken symlink".to_string()
+        }
+        Err(err) => describe_error(&err),
+        Ok(metadata) if metadata.is_dir() => "is a directory".to_string(),
+        Ok(_) => "not a regular file".to_string(),
+    }
+}
+
+/// Adds the files named on the command line to `items`, and the directories inside
+/// them when `recursive`, leaving out ignored files if `respect_gitignore` and
+/// hidden ones unless `include_hidden`. Paths that don't exist, aren't regular
+/// files or have no file name to rename go in `failed_items`, along with why.
+pub fn load_paths(
+    filenames: &[String],
+    recursive: bool,
+    respect_gitignore: bool,
+    include_hidden: bool,
+    items: &mut Vec<RenameItem>,
+    failed_items: &mut Vec<String>,
+) {
+    for filename in filenames {
+        let path = PathBuf::from(filename);
+        if path.is_file() {
+            match RenameItem::new(path.clone()) {
+                Some(item) => items.push(item),
+                None => failed_items.push(failed_item(&path, "has no file name")),
+            }
+        } else if !path.is_dir() {
+            failed_items.push(failed_item(&path, &access_problem(&path)));
+        } else if path.file_name().is_none() && !recursive {
+            failed_items.push(failed_item(&path, "has no file name"));
+        } else if recursive {
+            walk_directory(
+                &path,
+                respect_gitignore,
+                include_hidden,
+                items,
+                failed_items,
+            );
+        } else {
+            log::debug!("Ignoring directory: {}", path.to_string_lossy());
+        }
+    }
+}
+
+/// Whether `path` is hidden: named with a leading dot, or on Windows also having
+/// the hidden attribute.
+pub fn is_hidden(path: &Path) -> bool {
+    let dotted = path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        let attribute = fs::symlink_metadata(path)
+            .is_ok_and(|meta| meta.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0);
+        dotted || attribute
+    }
+    #[cfg(not(windows))]
+    dotted
+}
+
+/// The `.gitignore` and `.ignore` rules that apply in a directory, outermost first.
+type IgnoreRules = Vec<Rc<Gitignore>>;
+
+/// The rules from the ignore files called `names` in `dir`, if it has any.
+fn directory_ignores(dir: &Path, names: &[&str]) -> Option<Rc<Gitignore>> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for name in names {
+        let file = dir.join(name);
+        if file.is_file() {
+            found = true;
+            if let Some(err) = builder.add(&file) {
+                log::warn!("Problem reading {}: {}", file.to_string_lossy(), err);
+            }
+        }
+    }
+    if !found {
+        return None;
+    }
+    match builder.build() {
+        Ok(rules) => Some(Rc::new(rules)),
+        Err(err) => {
+            log::warn!(
+                "Ignoring the ignore files in {}: {}",
+                dir.to_string_lossy(),
+                err
+            );
+            None
+        }
+    }
+}
+
+/// The `.gitignore` rules from the directories above `dir`, when it's inside a git
+/// repository, up to the repository's root. `dir` has to be canonical.
+fn ancestor_ignores(dir: &Path) -> IgnoreRules {
+    let mut rules = Vec::new();
+    for ancestor in dir.ancestors() {
+        // `dir`'s own rules are read as it's walked
+        if ancestor != dir {
+            rules.extend(directory_ignores(ancestor, &[".gitignore"]));
+        }
+        if ancestor.join(".git").exists() {
+            rules.reverse();
+            return rules;
+        }
+    }
+    // Not in a repository, so nothing above applies
+    Vec::new()
+}
+
+/// Whether the innermost rule that matches `path` or a directory it's in ignores
+/// it. A `!` rule deeper down can bring back what a rule further out ignores.
+fn is_ignored(rules: &[Rc<Gitignore>], path: &Path, is_dir: bool) -> bool {
+    rules
+        .iter()
+        .rev()
+        .map(|rules| rules.matched_path_or_any_parents(path, is_dir))
+        .find(|matched| !matched.is_none())
+        .is_some_and(|matched| matched.is_ignore())
+}
+
+/// Adds every file below `root`, displayed by its path within `root`. Directories
+/// are only visited once, so symlink loops don't recurse forever.
+///
+/// With `respect_gitignore`, files and directories matched by a `.gitignore` or
+/// `.ignore` file below `root` are left out, as are those matched by the
+/// `.gitignore` files above it within the same git repository. `.git` directories
+/// are always left out then.
+///
+/// Hidden files and directories below `root` are left out unless `include_hidden`.
+fn walk_directory(
+    root: &Path,
+    respect_gitignore: bool,
+    include_hidden: bool,
+    items: &mut Vec<RenameItem>,
+    failed_items: &mut Vec<String>,
+) {
+    let mut visited = HashSet::new();
+    let outer_rules = match root.canonicalize() {
+        Ok(canonical) if respect_gitignore => ancestor_ignores(&canonical),
+        _ => Vec::new(),
+    };
+    let mut to_visit = vec![(root.to_path_buf(), outer_rules)];
+
+    while let Some((dir, mut rules)) = to_visit.pop() {
+        let canonical = match dir.canonicalize() {
+            Ok(canonical) => {
+                if !visited.insert(canonical.clone()) {
+                    log::debug!(
+                        "Skipping already visited directory: {}",
+                        dir.to_string_lossy()
+                    );
+                    continue;
+                }
+                canonical
+            }
+            Err(err) => {
+                failed_items.push(failed_item(&dir, &describe_error(&err)));
+                continue;
+            }
+        };
+        if respect_gitignore {
+            rules.extend(directory_ignores(&canonical, &[".gitignore", ".ignore"]));
+        }
+
+        let mut entries: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| match entry {
+                    Ok(entry) => Some(entry.path()),
+                    Err(err) => {
+                        failed_items.push(failed_item(&dir, &describe_error(&err)));
+                        None
+                    }
+                })
+                .collect(),
+            Err(err) => {
+                failed_items.push(failed_item(&dir, &describe_error(&err)));
+                continue;
+            }
+        };
+        entries.sort();
+
+        let mut subdirectories = Vec::new();
+        for path in entries {
+            if !include_hidden && is_hidden(&path) {
+                log::debug!("Skipping hidden {}", path.to_string_lossy());
+                continue;
+            }
+            if respect_gitignore {
+                // Entries read from a directory always have a file name
+                let name = path.file_name().unwrap_or_default();
+                if name == ".git" || is_ignored(&rules, &canonical.join(name), path.is_dir()) {
+                    log::debug!("Skipping ignored {}", path.to_string_lossy());
+                    continue;
+                }
+            }
+            if path.is_file() {
+                // Entries read from a directory always have a file name
+                if let Some(mut item) = RenameItem::new(path) {
+                    if let Ok(relative) = item.file.strip_prefix(root) {
+                        item.display = relative.to_string_lossy().to_string();
+                    }
+                    items.push(item);
+                }
+            } else if path.is_dir() {
+                subdirectories.push((path, rules.clone()));
+            } else {
+                // Broken symlinks, or entries we aren't allowed to inspect
+                failed_items.push(failed_item(&path, &access_problem(&path)));
+            }
+        }
+        // Reversed so that the stack visits them in sorted order
+        to_visit.extend(subdirectories.into_iter().rev());
+    }
+}
+
+/// Replaces the `occurrence`th match of `find_pat` in `text`, counting from 1, or
+/// every match for `None`.
+fn replace_occurrence<'t, R: Replacer>(
+    find_pat: &Regex,
+    text: &'t str,
+    occurrence: Option<usize>,
+    mut replacement: R,
+) -> Cow<'t, str> {
+    match occurrence {
+        None => find_pat.replace_all(text, replacement),
+        Some(1) => find_pat.replacen(text, 1, replacement),
+        Some(n) => match find_pat.captures_iter(text).nth(n.saturating_sub(1)) {
+            Some(caps) => {
+                let whole = caps.get(0).unwrap();
+                let mut replaced = text[..whole.start()].to_string();
+                replacement.replace_append(&caps, &mut replaced);
+                replaced.push_str(&text[whole.end()..]);
+                Cow::Owned(replaced)
+            }
+            None => Cow::Borrowed(text),
+        },
+    }
+}
+
+/// Renames every item, numbering them by their position in `order` for the `${n}`
+/// token, either among all items or among those in the same directory. Items that
+/// were renamed by hand keep their name. When applying to the selection only,
+/// items that aren't picked keep their original name and aren't counted.
+///
+/// Sorting by a column the patterns change numbers by the names from before.
+///
+/// Returns how many items had time tokens that couldn't be read, which expand to
+/// nothing.
+pub fn apply_patterns(
+    items: &mut [RenameItem],
+    order: &[usize],
+    patterns: &RenamePatterns,
+) -> usize {
+    let mut counters: HashMap<(PathBuf, String), usize> = HashMap::new();
+    let uses_time_tokens = patterns.uses_time_tokens();
+    let mut unreadable = 0;
+
+    for &index in order {
+        let item = &mut items[index];
+        if patterns.selection_only && !item.picked {
+            item.reset();
+            continue;
+        }
+
+        let directory = if patterns.counter_per_directory {
+            item.file.parent().unwrap_or(Path::new("")).to_path_buf()
+        } else {
+            PathBuf::new()
+        };
+        let extension = match split_extension(&item.original) {
+            (_, Some(extension)) if patterns.counter_per_extension => extension.to_lowercase(),
+            _ => String::new(),
+        };
+        let counter = counters.entry((directory, extension)).or_insert(0);
+        *counter += 1;
+
+        if item.manually_edited {
+            continue;
+        }
+        if uses_time_tokens && item.file.metadata().is_err() {
+            unreadable += 1;
+        }
+        // Counters past what fits stay at the largest or smallest number
+        let steps = i64::try_from(*counter - 1).unwrap_or(i64::MAX);
+        let value = patterns
+            .counter_start
+            .saturating_add(steps.saturating_mul(patterns.counter_step));
+        let replacements = patterns.replacements(value, &item.file);
+        item.set_pattern(patterns, &replacements);
+    }
+    unreadable
+}
+
+/// The problems `check_renames` found with the new names, as messages naming the
+/// files.
+#[derive(Debug, Default, Serialize)]
+pub struct CheckResult {
+    /// New names that more than one file is being renamed to
+    pub conflicting_names: Vec<String>,
+    /// New names that a file staying where it is already has, either because it
+    /// keeps its name or because it can't be renamed. No order of moves avoids
+    /// replacing those files.
+    pub unchanged_collisions: Vec<String>,
+    pub permission_problems: Vec<String>,
+    pub overwrite_targets: Vec<String>,
+    /// Names the platform doesn't allow, which can't be renamed to at all
+    pub invalid_names: Vec<String>,
+    /// Names containing a path separator, which move the file into another directory
+    pub moved_names: Vec<String>,
+    /// Files whose new name is empty or only whitespace, which can't be renamed
+    pub empty_names: Vec<String>,
+    /// Destinations longer than `max_path_length`, which the platform can't open
+    pub too_long: Vec<String>,
+    /// Directories new paths go into that don't exist, when they won't be created
+    pub missing_directories: Vec<String>,
+}
+
+impl CheckResult {
+    /// Whether any of the renames can't be made at all, so applying stops before
+    /// anything is renamed. The other problems are warnings that can be continued
+    /// past.
+    pub fn is_blocked(&self) -> bool {
+        !self.invalid_names.is_empty()
+            || !self.empty_names.is_empty()
+            || !self.too_long.is_empty()
+            || !self.missing_directories.is_empty()
+    }
+
+    /// Whether any of the renames need the user's go-ahead, such as ones that
+    /// overwrite a file or give two files the same name.
+    pub fn has_warnings(&self) -> bool {
+        !self.conflicting_names.is_empty()
+            || !self.unchanged_collisions.is_empty()
+            || !self.permission_problems.is_empty()
+            || !self.overwrite_targets.is_empty()
+            || !self.moved_names.is_empty()
+    }
+}
+
+/// Whether `name` contains a path separator for the platform, or `/` which is one
+/// everywhere.
+fn has_separator(name: &str) -> bool {
+    name.contains(['/', std::path::MAIN_SEPARATOR])
+}
+
+/// Whether the platform allows `name` as a file name. Path separators are allowed,
+/// since those are reported by `has_separator` instead.
+pub fn is_valid_name(name: &str) -> bool {
+    if cfg!(windows) {
+        !name.contains(|c: char| c.is_control() || "<>:\"|?*".contains(c))
+            && !name.ends_with(['.', ' '])
+    } else {
+        !name.contains('\0')
+    }
+}
+
+pub fn check_renames(items: &[RenameItem], patterns: &RenamePatterns) -> CheckResult {
+    // Items by where they end up, telling apart the ones being renamed. Read-only
+    // files are left out of the moves, so they end up where they are.
+    let mut by_destination = BTreeMap::<String, (usize, usize, BTreeSet<String>)>::new();
+    for item in items {
+        let moves = item.is_changed() && !item.is_read_only();
+        let destination = if moves {
+            item.destination()
+        } else {
+            item.file.clone()
+        };
+        let spelling = destination_spelling(&destination, patterns);
+        let key = conflict_key(&spelling, patterns);
+        let (changed, unchanged, spellings) = by_destination.entry(key).or_default();
+        spellings.insert(spelling);
+        if moves {
+            *changed += 1;
+        } else {
+            *unchanged += 1;
+        }
+    }
+
+    let mut conflicting_names = Vec::new();
+    let mut unchanged_collisions = Vec::new();
+    for (changed, unchanged, spellings) in by_destination.into_values() {
+        if changed > 1 {
+            conflicting_names.extend(spellings.iter().cloned());
+        }
+        if changed > 0 && unchanged > 0 {
+            unchanged_collisions.extend(spellings);
+        }
+    }
+
+    let permission_problems = items
+        .iter()
+        .filter(|item| item.is_changed() && item.is_read_only())
+        .map(|item| item.file.to_string_lossy().to_string())
+        .collect();
+
+    // Targets that are one of our own files will be moved out of the way first,
+    // unless we're copying and so leave the originals where they are
+    let originals: HashSet<&Path> = if patterns.copy_files {
+        HashSet::new()
+    } else {
+        items.iter().map(|item| item.file.as_path()).collect()
+    };
+    let overwrite_targets = items
+        .iter()
+        .filter(|item| item.is_changed())
+        .map(|item| item.destination())
+        .filter(|dest| !originals.contains(dest.as_path()) && dest.exists())
+        .map(|dest| dest.to_string_lossy().to_string())
+        .collect();
+
+    let (empty, named): (Vec<&RenameItem>, Vec<&RenameItem>) = items
+        .iter()
+        .partition(|item| item.renamed.trim().is_empty());
+    let empty_names = empty.iter().map(|item| item.display.clone()).collect();
+
+    let invalid_names = named
+        .iter()
+        .filter(|item| {
+            !Path::new(&item.renamed)
+                .components()
+                .all(|part| match part {
+                    std::path::Component::Normal(name) => is_valid_name(&name.to_string_lossy()),
+                    _ => true,
+                })
+        })
+        .map(|item| item.renamed.clone())
+        .collect();
+
+    let moved_names = items
+        .iter()
+        .filter(|item| item.is_moved())
+        .map(|item| item.renamed.clone())
+        .collect();
+
+    let too_long = match patterns.max_path_length {
+        Some(limit) => items
+            .iter()
+            .filter(|item| item.is_changed())
+            .map(|item| item.destination())
+            .filter(|dest| path_length(dest) > limit)
+            .map(|dest| dest.to_string_lossy().to_string())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let mut missing_directories = Vec::new();
+    if !patterns.create_directories {
+        for item in items.iter().filter(|item| item.is_changed()) {
+            if let Some(parent) = missing_parent(&item.destination()) {
+                let parent = parent.to_string_lossy().to_string();
+                if !missing_directories.contains(&parent) {
+                    missing_directories.push(parent);
+                }
+            }
+        }
+    }
+
+    CheckResult {
+        conflicting_names,
+        unchanged_collisions,
+        permission_problems,
+        overwrite_targets,
+        invalid_names,
+        moved_names,
+        empty_names,
+        too_long,
+        missing_directories,
+    }
+}
+
+/// `destination` as `check_renames` reports it. Names that only differ in their
+/// Unicode form look the same, so they're normalized when that's turned on.
+fn destination_spelling(destination: &Path, patterns: &RenamePatterns) -> String {
+    let destination = destination.to_string_lossy();
+    if patterns.normalize_unicode {
+        destination.nfc().collect()
+    } else {
+        destination.to_string()
+    }
+}
+
+/// What destinations are compared by to find conflicts. Names that only differ
+/// in case clash too, where the file system ignores it.
+fn conflict_key(spelling: &str, patterns: &RenamePatterns) -> String {
+    if patterns.case_insensitive_conflicts {
+        spelling.to_lowercase()
+    } else {
+        spelling.to_string()
+    }
+}
+
+/// Gives the items `check_renames` finds moving to the same destination distinct
+/// names, adding ` (1)`, ` (2)` and so on before the extension as file managers
+/// do. The first in `order` keeps the name, unless a file staying where it is, or
+/// one that isn't being renamed, already has it. Suffixes that another file has
+/// or is getting are skipped.
+///
+/// The new names are kept as manual edits, so the patterns don't undo them.
+/// Returns how many items were given one.
+pub fn deduplicate_names(
+    items: &mut [RenameItem],
+    order: &[usize],
+    patterns: &RenamePatterns,
+) -> usize {
+    let key = |path: &Path| conflict_key(&destination_spelling(path, patterns), patterns);
+    let moves = |item: &RenameItem| item.is_changed() && !item.is_read_only();
+
+    let staying: HashSet<String> = items
+        .iter()
+        .filter(|item| !moves(item))
+        .map(|item| key(&item.file))
+        .collect();
+    // Files being moved away free their names, unless they're copied
+    let vacated: HashSet<String> = if patterns.copy_files {
+        HashSet::new()
+    } else {
+        items
+            .iter()
+            .filter(|item| moves(item))
+            .map(|item| key(&item.file))
+            .collect()
+    };
+    let mut taken: HashSet<String> = items.iter().map(|item| key(&item.file)).collect();
+    let mut groups = BTreeMap::<String, Vec<usize>>::new();
+    for &index in order {
+        if moves(&items[index]) {
+            let destination = key(&items[index].destination());
+            taken.insert(destination.clone());
+            groups.entry(destination).or_default().push(index);
+        }
+    }
+
+    let mut renamed = 0;
+    for (destination, group) in groups {
+        let occupied = staying.contains(&destination)
+            || (!vacated.contains(&destination) && items[group[0]].destination().exists());
+        if group.len() < 2 && !occupied {
+            continue;
+        }
+        let first = if occupied { 0 } else { 1 };
+        for &index in &group[first..] {
+            let item = &mut items[index];
+            let (stem, extension) = match split_extension(&item.renamed) {
+                (stem, Some(extension)) => (stem.to_string(), format!(".{}", extension)),
+                (stem, None) => (stem.to_string(), String::new()),
+            };
+            for counter in 1.. {
+                let candidate = format!("{} ({}){}", stem, counter, extension);
+                let destination = item.destination_for(&candidate);
+                if !destination.exists() && taken.insert(key(&destination)) {
+                    item.rename_to(candidate);
+                    item.manually_edited = true;
+                    break;
+                }
+            }
+            renamed += 1;
+        }
+    }
+    renamed
+}
+
+/// What applying does about new names that conflict, with each other or with
+/// files already there, by the name used in the config.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Warn about them and let the user decide
+    #[default]
+    Ask,
+    /// Leave out the files that would conflict
+    Skip,
+    /// Rename anyway, replacing the files already there. Of files renamed to the
+    /// same name, only the first can be.
+    Overwrite,
+    /// Give the conflicting names numbered suffixes, as `deduplicate_names` does
+    Suffix,
+}
+
+impl ConflictPolicy {
+    pub const ALL: [ConflictPolicy; 4] = [
+        ConflictPolicy::Ask,
+        ConflictPolicy::Skip,
+        ConflictPolicy::Overwrite,
+        ConflictPolicy::Suffix,
+    ];
+
+    /// The policy's name in the config and for `--on-conflict`
+    pub fn name(self) -> &'static str {
+        match self {
+            ConflictPolicy::Ask => "ask",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::Suffix => "suffix",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        ConflictPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.name() == name)
+    }
+
+    /// How the policy is shown in the settings
+    pub fn label(self) -> &'static str {
+        match self {
+            ConflictPolicy::Ask => "Ask",
+            ConflictPolicy::Skip => "Skip conflicting",
+            ConflictPolicy::Overwrite => "Overwrite",
+            ConflictPolicy::Suffix => "Auto-suffix",
+        }
+    }
+}
+
+/// Deals with the conflicts `check` found as `patterns.conflict_policy` says,
+/// clearing them from `check` so they aren't asked about. Returns the
+/// destinations to skip, as `planned_moves` takes them. With `Suffix` the
+/// items are given new names and checked again.
+pub fn settle_conflicts(
+    items: &mut [RenameItem],
+    order: &[usize],
+    patterns: &RenamePatterns,
+    check: &mut CheckResult,
+) -> HashSet<PathBuf> {
+    let mut skipped = HashSet::new();
+    match patterns.conflict_policy {
+        ConflictPolicy::Ask => return skipped,
+        ConflictPolicy::Skip => {
+            let key = |spelling: &str| conflict_key(spelling, patterns);
+            let clashing: HashSet<String> = check
+                .conflicting_names
+                .iter()
+                .chain(&check.unchanged_collisions)
+                .map(|spelling| key(spelling))
+                .collect();
+            let overwritten: HashSet<&str> =
+                check.overwrite_targets.iter().map(String::as_str).collect();
+            for item in items.iter().filter(|item| item.is_changed()) {
+                let destination = item.destination();
+                if clashing.contains(&key(&destination_spelling(&destination, patterns)))
+                    || overwritten.contains(destination.to_string_lossy().as_ref())
+                {
+                    skipped.insert(destination);
+                }
+            }
+        }
+        ConflictPolicy::Overwrite => {}
+        ConflictPolicy::Suffix => {
+            deduplicate_names(items, order, patterns);
+            *check = check_renames(items, patterns);
+        }
+    }
+    check.conflicting_names.clear();
+    check.unchanged_collisions.clear();
+    check.overwrite_targets.clear();
+    skipped
+}
+
+/// The longest path the platform opens by default. Windows needs long path
+/// support turned on for more than `MAX_PATH`, unless paths use the `\\?\` prefix.
+#[cfg(windows)]
+pub const DEFAULT_MAX_PATH_LENGTH: Option<usize> = Some(260);
+#[cfg(not(windows))]
+pub const DEFAULT_MAX_PATH_LENGTH: Option<usize> = None;
+
+/// How long `path` is once made absolute, as the platform counts it. Paths with
+/// the `\\?\` prefix have no limit, so count as empty.
+fn path_length(path: &Path) -> usize {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let absolute = absolute.to_string_lossy();
+    if absolute.starts_with(r"\\?\") {
+        0
+    } else if cfg!(windows) {
+        absolute.encode_utf16().count()
+    } else {
+        absolute.len()
+    }
+}
+
+/// Picks an unused name next to `file` to park it under while breaking a cycle.
+fn temporary_path(file: &Path) -> PathBuf {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let mut attempt = 0;
+    loop {
+        let candidate = file.with_file_name(format!(
+            ".{}.tui_rename-{}-{}",
+            name,
+            std::process::id(),
+            attempt
+        ));
+        if !candidate.exists() {
+            return candidate;
+        }
+        attempt += 1;
+    }
+}
+
+/// Moves each `(from, to)` pair, returning the outcome of each move.
+///
+/// Moves are ordered so that no file is moved onto another that hasn't moved yet.
+/// When the remaining moves form cycles (eg. swapping `a` and `b`), one file is
+/// parked under a temporary name to break the cycle. Each round of moves that
+/// don't depend on each other is run by `move_batch`.
+///
+/// When `copy` is set each file is copied to its target instead, keeping the
+/// original. Cycles are then broken by parking a copy, which is moved into place.
+///
+/// `on_moved` is called after each file is processed. Once `cancelled` is set, the
+/// remaining moves are given an `Interrupted` error, apart from files parked under
+/// a temporary name which are still moved to their target.
+///
+/// Only targets in `existing_targets` are overwritten, as the check warned about
+/// those. Files that turn up at any other target in the meantime are left alone,
+/// and each target takes one file, so moves sharing a target don't replace each other.
+fn perform_moves(
+    moves: &[(PathBuf, PathBuf)],
+    existing_targets: &HashSet<PathBuf>,
+    copy: bool,
+    cancelled: &AtomicBool,
+    on_moved: &(dyn Fn() + Sync),
+) -> Vec<io::Result<MoveKind>> {
+    let mut outcomes: Vec<io::Result<MoveKind>> =
+        moves.iter().map(|_| Ok(MoveKind::Renamed)).collect();
+    let mut current: Vec<PathBuf> = moves.iter().map(|(from, _)| from.clone()).collect();
+
+    let mut pending: Vec<usize> = (0..moves.len()).collect();
+    // Current locations of the pending files, which must not be moved over
+    let mut sources: HashSet<PathBuf> = current.iter().cloned().collect();
+    // Locations of files that failed to move, and so are still occupied
+    let mut stuck = HashSet::new();
+    // Targets a file has been moved onto, which no later move may replace
+    let mut landed = HashSet::new();
+
+    let never_cancelled = AtomicBool::new(false);
+
+    while !pending.is_empty() {
+        let parked = pending.iter().any(|&i| current[i] != moves[i].0);
+        if !parked && cancelled.load(Ordering::Relaxed) {
+            for i in pending.drain(..) {
+                outcomes[i] = Err(io::ErrorKind::Interrupted.into());
+            }
+            break;
+        }
+
+        // Moves whose target is free, taking only one move onto each target per round
+        let mut targets = HashSet::new();
+        let (ready, waiting): (Vec<usize>, Vec<usize>) = pending.iter().partition(|&&i| {
+            let (from, dest) = (&current[i], &moves[i].1);
+            (dest == from || !sources.contains(dest)) && targets.insert(dest)
+        });
+        pending = waiting;
+
+        let (blocked, batch): (Vec<usize>, Vec<usize>) = ready.into_iter().partition(|&i| {
+            let dest = &moves[i].1;
+            dest != &current[i] && (stuck.contains(dest) || landed.contains(dest))
+        });
+        for i in blocked {
+            outcomes[i] = Err(io::Error::other(if landed.contains(&moves[i].1) {
+                "another file was already renamed to the same name"
+            } else {
+                "target is still in use by a file that failed to move"
+            }));
+            on_moved();
+        }
+
+        // Parked files are always moved on, so none are left under a temporary name
+        let (batch_parked, batch_rest): (Vec<usize>, Vec<usize>) =
+            batch.into_iter().partition(|&i| current[i] != moves[i].0);
+        let batch_moves = |batch: &[usize]| -> Vec<(&Path, &Path)> {
+            batch
+                .iter()
+                .map(|&i| (current[i].as_path(), moves[i].1.as_path()))
+                .collect()
+        };
+        let mut batch_outcomes = move_batch(
+            &batch_moves(&batch_parked),
+            existing_targets,
+            false,
+            &never_cancelled,
+            on_moved,
+        );
+        batch_outcomes.extend(move_batch(
+            &batch_moves(&batch_rest),
+            existing_targets,
+            copy,
+            cancelled,
+            on_moved,
+        ));
+
+        let made_progress = !targets.is_empty();
+        for (i, outcome) in batch_parked
+            .into_iter()
+            .chain(batch_rest)
+            .zip(batch_outcomes)
+        {
+            sources.remove(&current[i]);
+            if outcome.is_err() {
+                stuck.insert(current[i].clone());
+            } else {
+                landed.insert(moves[i].1.clone());
+            }
+            outcomes[i] = outcome;
+        }
+
+        if !made_progress {
+            // Everything left is waiting on another pending move. Following what
+            // each waits on leads into a cycle, and one file on it is parked to
+            // break it. Files only waiting on the cycle are left where they are.
+            let (i, previous) = {
+                let by_source: HashMap<&Path, usize> =
+                    pending.iter().map(|&j| (current[j].as_path(), j)).collect();
+                let mut seen = HashSet::new();
+                let (mut node, mut previous) = (Some(pending[0]), None);
+                while let Some(i) = node.filter(|&i| seen.insert(i)) {
+                    previous = Some(i);
+                    node = by_source.get(moves[i].1.as_path()).copied();
+                }
+                (node, previous)
+            };
+            // Parked files have no moves onto them, so can't be on a cycle
+            let i = match i.filter(|&i| current[i] == moves[i].0) {
+                Some(i) => i,
+                None => {
+                    for i in pending.drain(..) {
+                        outcomes[i] = Err(io::Error::other(
+                            "couldn't be ordered with the other renames",
+                        ));
+                    }
+                    break;
+                }
+            };
+            // The move onto the parked file's place goes first, ahead of any others
+            // waiting on it
+            if let Some(previous) = previous {
+                pending.retain(|&j| j != previous);
+                pending.insert(0, previous);
+            }
+            let temp = temporary_path(&current[i]);
+            log::debug!(
+                "Moving {} to {} to break a rename cycle",
+                current[i].to_string_lossy(),
+                temp.to_string_lossy()
+            );
+
+            sources.remove(&current[i]);
+            let parked = if copy {
+                fs::copy(&current[i], &temp).map(|_| ())
+            } else {
+                fs::rename(&current[i], &temp)
+            };
+            match parked {
+                Ok(()) => {
+                    sources.insert(temp.clone());
+                    current[i] = temp;
+                }
+                Err(err) => {
+                    if copy {
+                        let _ = fs::remove_file(&temp);
+                    }
+                    outcomes[i] = Err(err);
+                    stuck.insert(current[i].clone());
+                    pending.retain(|&j| j != i);
+                }
+            }
+        }
+    }
+
+    outcomes
+}
+
+/// Moves files that don't depend on each other, in parallel when the `parallel`
+/// feature is enabled.
+#[cfg(feature = "parallel")]
+fn move_batch(
+    moves: &[(&Path, &Path)],
+    existing_targets: &HashSet<PathBuf>,
+    copy: bool,
+    cancelled: &AtomicBool,
+    on_moved: &(dyn Fn() + Sync),
+) -> Vec<io::Result<MoveKind>> {
+    use rayon::prelude::*;
+    moves
+        .par_iter()
+        .map(|(from, to)| move_file(from, to, existing_targets, copy, cancelled, on_moved))
+        .collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn move_batch(
+    moves: &[(&Path, &Path)],
+    existing_targets: &HashSet<PathBuf>,
+    copy: bool,
+    cancelled: &AtomicBool,
+    on_moved: &(dyn Fn() + Sync),
+) -> Vec<io::Result<MoveKind>> {
+    moves
+        .iter()
+        .map(|(from, to)| move_file(from, to, existing_targets, copy, cancelled, on_moved))
+        .collect()
+}
+
+/// How a file was moved to its new name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MoveKind {
+    Renamed,
+    /// The target is on another filesystem, so the file was copied there and the
+    /// original removed
+    CrossDevice,
+    /// Copied to the target as asked, keeping the original
+    Copied,
+}
+
+fn move_file(
+    from: &Path,
+    to: &Path,
+    existing_targets: &HashSet<PathBuf>,
+    copy: bool,
+    cancelled: &AtomicBool,
+    on_moved: &(dyn Fn() + Sync),
+) -> io::Result<MoveKind> {
+    if cancelled.load(Ordering::Relaxed) {
+        return Err(io::ErrorKind::Interrupted.into());
+    }
+    let outcome = if let Err(err) = verify_move(from, to, existing_targets) {
+        Err(err)
+    } else if copy {
+        fs::copy(from, to).map(|_| MoveKind::Copied)
+    } else {
+        match fs::rename(from, to) {
+            Ok(()) => Ok(MoveKind::Renamed),
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+                copy_and_remove(from, to).map(|()| MoveKind::CrossDevice)
+            }
+            Err(err) => Err(err),
+        }
+    };
+    on_moved();
+    outcome
+}
+
+/// Checks that `from` can still be moved to `to`, since the files may have changed
+/// after the renames were checked.
+fn verify_move(from: &Path, to: &Path, existing_targets: &HashSet<PathBuf>) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from).map_err(|err| {
+        let message = if err.kind() == io::ErrorKind::NotFound {
+            "no longer exists".to_string()
+        } else {
+            format!("changed since the check, it can't be read: {}", err)
+        };
+        io::Error::new(err.kind(), message)
+    })?;
+    if metadata.permissions().readonly() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "changed since the check, it's now read-only",
+        ));
+    }
+    // Only a case change renames a file onto itself
+    let same_file = || fs::canonicalize(from).ok() == fs::canonicalize(to).ok();
+    if to != from
+        && fs::symlink_metadata(to).is_ok()
+        && !existing_targets.contains(to)
+        && !same_file()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} appeared since the check, so it wasn't overwritten",
+                to.to_string_lossy()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Moves a file across filesystems by copying it next to `to` under a temporary
+/// name, keeping its permissions and times, removing `from`, then renaming the copy
+/// into place. If copying or removing fails the copy is removed again, so the file
+/// isn't left in both places and a file already at `to` is left alone.
+fn copy_and_remove(from: &Path, to: &Path) -> io::Result<()> {
+    let temp = temporary_path(to);
+    let copied = fs::copy(from, &temp).and_then(|_| {
+        let metadata = fs::metadata(from)?;
+        let times = fs::FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?);
+        fs::File::options()
+            .write(true)
+            .open(&temp)?
+            .set_times(times)
+    });
+    if let Err(err) = copied {
+        let _ = fs::remove_file(&temp);
+        return Err(io::Error::new(
+            err.kind(),
+            format!("copying to another filesystem failed: {}", err),
+        ));
+    }
+
+    if let Err(err) = fs::remove_file(from) {
+        let cleanup = match fs::remove_file(&temp) {
+            Ok(()) => String::new(),
+            Err(_) => format!(", and the copy at {} remains", temp.to_string_lossy()),
+        };
+        return Err(io::Error::new(
+            err.kind(),
+            format!(
+                "copied to another filesystem but the original couldn't be removed: {}{}",
+                err, cleanup
+            ),
+        ));
+    }
+    fs::rename(&temp, to).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!(
+                "copied to another filesystem but couldn't be given its new name, so it's at {}: {}",
+                temp.to_string_lossy(),
+                err
+            ),
+        )
+    })
+}
+
+/// The files that were moved by an apply or undo, and why any others weren't.
+#[derive(Default)]
+pub struct RenameResult {
+    /// `(from, to)` for every file that was moved
+    pub moved: Vec<(PathBuf, PathBuf)>,
+    pub failures: Vec<String>,
+    /// The file each failure was for, as it was named when it failed, and why
+    pub errors: Vec<(PathBuf, String)>,
+    /// How many changed files were left alone, for being read-only or skipped over a
+    /// warning
+    pub skipped: usize,
+    /// How many changed files already had their new name, from an earlier apply
+    pub in_place: usize,
+    /// How many files weren't moved because the operation was cancelled
+    pub cancelled: usize,
+    /// How many of the moved files had to be copied to another filesystem
+    pub cross_device: usize,
+    /// The backup manifest written before the moves, if there is one
+    pub manifest: Option<PathBuf>,
+}
+
+impl RenameResult {
+    fn collect(moves: Vec<(PathBuf, PathBuf)>, outcomes: Vec<io::Result<MoveKind>>) -> Self {
+        let mut result = RenameResult::default();
+        for (entry, outcome) in moves.into_iter().zip(outcomes) {
+            match outcome {
+                Ok(kind) => {
+                    if kind == MoveKind::CrossDevice {
+                        result.cross_device += 1;
+                    }
+                    result.moved.push(entry);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => result.cancelled += 1,
+                Err(err) => result.fail(entry.0, err.to_string()),
+            }
+        }
+        result
+    }
+
+    /// Records that `file` couldn't be moved because of `reason`.
+    fn fail(&mut self, file: PathBuf, reason: String) {
+        self.failures
+            .push(format!("{}: {}", file.to_string_lossy(), reason));
+        self.errors.push((file, reason));
+    }
+}
+
+/// The moves an apply makes, for every item that isn't read-only since those were
+/// already reported by `check_renames`. Items keeping their name, such as those
+/// left out of the selection, items already renamed by an earlier apply, and moves
+/// onto `skipped_targets` are left out.
+pub fn planned_moves(
+    items: &[RenameItem],
+    skipped_targets: &HashSet<PathBuf>,
+) -> Vec<(PathBuf, PathBuf)> {
+    planned_items(items, skipped_targets)
+        .map(|item| (item.file.clone(), item.destination()))
+        .collect()
+}
+
+/// The items `planned_moves` moves, in the same order, such as for listing them
+/// before applying.
+pub fn planned_items<'a>(
+    items: &'a [RenameItem],
+    skipped_targets: &'a HashSet<PathBuf>,
+) -> impl Iterator<Item = &'a RenameItem> {
+    items
+        .iter()
+        .filter(|item| item.is_changed() && !item.is_read_only() && !item.is_in_place())
+        .filter(|item| !skipped_targets.contains(&item.destination()))
+}
+
+/// The destinations of changed items that are already taken, for the moves to
+/// overwrite.
+pub fn existing_targets(items: &[RenameItem]) -> HashSet<PathBuf> {
+    items
+        .iter()
+        .filter(|item| item.is_changed())
+        .map(|item| item.destination())
+        .filter(|dest| fs::symlink_metadata(dest).is_ok())
+        .collect()
+}
+
+/// How many of the changed items `moves` leaves out, other than the ones already
+/// at their new name.
+pub fn skipped_count(items: &[RenameItem], moves: &[(PathBuf, PathBuf)]) -> usize {
+    let changed = items
+        .iter()
+        .filter(|item| item.is_changed() && !item.is_in_place())
+        .count();
+    changed - moves.len()
+}
+
+/// How many of the changed items already have their new name, so aren't moved.
+pub fn in_place_count(items: &[RenameItem]) -> usize {
+    items.iter().filter(|item| item.is_in_place()).count()
+}
+
+/// The directory `path` goes in, if it doesn't exist yet.
+fn missing_parent(path: &Path) -> Option<&Path> {
+    path.parent()
+        .filter(|parent| !parent.as_os_str().is_empty() && !parent.is_dir())
+}
+
+/// Creates the missing directories that `moves` go into. Moves whose directory
+/// couldn't be created are removed, and returned with why.
+pub fn create_target_directories(moves: &mut Vec<(PathBuf, PathBuf)>) -> Vec<(PathBuf, String)> {
+    let mut errors = Vec::new();
+    moves.retain(|(from, to)| match missing_parent(to) {
+        Some(parent) => match fs::create_dir_all(parent) {
+            Ok(()) => true,
+            Err(err) => {
+                errors.push((
+                    from.clone(),
+                    format!("couldn't create {}: {}", parent.to_string_lossy(), err),
+                ));
+                false
+            }
+        },
+        None => true,
+    });
+    errors
+}
+
+/// Renames the items from `planned_moves`, or copies them when `copy` is set.
+/// Items that were moved have `file` pointed at their new location, and those
+/// that failed have `error` set.
+pub fn perform_renames(
+    items: &mut [RenameItem],
+    skipped_targets: &HashSet<PathBuf>,
+    existing_targets: &HashSet<PathBuf>,
+    copy: bool,
+) -> RenameResult {
+    let mut moves = planned_moves(items, skipped_targets);
+    let skipped = skipped_count(items, &moves);
+    let errors = create_target_directories(&mut moves);
+    let mut result = run_moves(
+        moves,
+        errors,
+        existing_targets,
+        copy,
+        &AtomicBool::new(false),
+        &|| {},
+    );
+    result.skipped = skipped;
+    result.in_place = in_place_count(items);
+    if !copy {
+        relocate_items(items, &result.moved);
+    }
+    record_errors(items, &result);
+    result
+}
+
+/// Performs `moves`, adding the errors from preparing them to the result.
+pub fn run_moves(
+    moves: Vec<(PathBuf, PathBuf)>,
+    errors: Vec<(PathBuf, String)>,
+    existing_targets: &HashSet<PathBuf>,
+    copy: bool,
+    cancelled: &AtomicBool,
+    on_moved: &(dyn Fn() + Sync),
+) -> RenameResult {
+    let outcomes = perform_moves(&moves, existing_targets, copy, cancelled, on_moved);
+    let mut result = RenameResult::collect(moves, outcomes);
+    for (file, reason) in errors {
+        result.fail(file, reason);
+    }
+    result
+}
+
+/// Sets `error` on the items `result` failed to move, and clears it on those it
+/// moved.
+pub fn record_errors(items: &mut [RenameItem], result: &RenameResult) {
+    let errors: HashMap<&Path, &String> = result
+        .errors
+        .iter()
+        .map(|(file, reason)| (file.as_path(), reason))
+        .collect();
+    let moved: HashSet<&Path> = result
+        .moved
+        .iter()
+        .flat_map(|(from, to)| [from.as_path(), to.as_path()])
+        .collect();
+    for item in items {
+        if let Some(reason) = errors.get(item.file.as_path()) {
+            item.error = Some(reason.to_string());
+        } else if moved.contains(item.file.as_path()) {
+            item.error = None;
+        }
+    }
+}
+
+/// Points items at the new location of their file after it was moved.
+pub fn relocate_items(items: &mut [RenameItem], moved: &[(PathBuf, PathBuf)]) {
+    let moved: HashMap<&Path, &PathBuf> = moved
+        .iter()
+        .map(|(from, to)| (from.as_path(), to))
+        .collect();
+    for item in items {
+        if let Some(to) = moved.get(item.file.as_path()) {
+            item.file = to.to_path_buf();
+        }
+    }
+}
+
+/// Moves every file back to where it was before `moved`, in reverse order. A file
+/// is left alone if something else has since taken its old name.
+pub fn undo_moves(moved: &[(PathBuf, PathBuf)]) -> RenameResult {
+    let moving: HashSet<&Path> = moved.iter().map(|(_, to)| to.as_path()).collect();
+    let mut errors = Vec::new();
+
+    let moves: Vec<(PathBuf, PathBuf)> = moved
+        .iter()
+        .rev()
+        .filter(|(from, to)| {
+            let collides = from.exists() && !moving.contains(from.as_path());
+            if collides {
+                errors.push((
+                    to.clone(),
+                    format!("{} already exists", from.to_string_lossy()),
+                ));
+            }
+            !collides
+        })
+        .map(|(from, to)| (to.clone(), from.clone()))
+        .collect();
+
+    // Colliding files were left out above, so nothing is overwritten
+    run_moves(
+        moves,
+        errors,
+        &HashSet::new(),
+        false,
+        &AtomicBool::new(false),
+        &|| {},
+    )
+}
+
+/// One rename in a backup manifest.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// The renames an apply was about to make, written before any of them so they can
+/// be undone with `--restore` even after a crash.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// When the apply started, in RFC 3339
+    pub timestamp: String,
+    pub renames: Vec<ManifestEntry>,
+    /// When the renames were undone from this manifest, in RFC 3339, so they
+    /// aren't undone twice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restored: Option<String>,
+}
+
+/// Writes `moves` to a manifest at `path`, with the paths made absolute so it can
+/// be restored from anywhere.
+pub fn write_manifest(path: &Path, moves: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    let renames = moves
+        .iter()
+        .map(|(from, to)| {
+            Ok(ManifestEntry {
+                from: std::path::absolute(from)?,
+                to: std::path::absolute(to)?,
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    let manifest = Manifest {
+        timestamp: Local::now().to_rfc3339(),
+        renames,
+        restored: None,
+    };
+    save_manifest(path, &manifest)
+}
+
+fn save_manifest(path: &Path, manifest: &Manifest) -> io::Result<()> {
+    let contents = serde_json::to_string_pretty(manifest).map_err(io::Error::other)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents)
+}
+
+/// Records in the manifest at `path` that its renames have been undone.
+pub fn mark_restored(path: &Path) -> io::Result<()> {
+    let mut manifest = read_manifest(path)?;
+    manifest.restored = Some(Local::now().to_rfc3339());
+    save_manifest(path, &manifest)
+}
+
+pub fn read_manifest(path: &Path) -> io::Result<Manifest> {
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Renames files with find and replace patterns, without the interface.
+///
+/// Add the files, set the patterns, then `update` to work out the new names.
+/// `check` reports what would go wrong before `perform` makes the renames.
+///
+/// ```no_run
+/// use tui_rename::RenameEngine;
+///
+/// let mut engine = RenameEngine::new(r"^(\d+) - ", "$1. ")?;
+/// let failures = engine.add_paths(&["music".to_string()], true);
+/// engine.update();
+/// if engine.check().conflicting_names.is_empty() {
+///     let result = engine.perform();
+///     println!("Renamed {} files, {} failed", result.moved.len(), result.failures.len());
+/// }
+/// # Ok::<(), regex::Error>(())
+/// ```
+pub struct RenameEngine {
+    /// The patterns and settings the new names are worked out with. Call `update`
+    /// after changing them.
+    pub patterns: RenamePatterns,
+    /// Leave out files matched by `.gitignore` and `.ignore` files when recursing
+    pub respect_gitignore: bool,
+    /// Add hidden files when recursing
+    pub include_hidden: bool,
+    items: Vec<RenameItem>,
+}
+
+impl RenameEngine {
+    /// An engine with no files that replaces `find`, a regex, with `replace`.
+    pub fn new(find: &str, replace: &str) -> Result<Self, regex::Error> {
+        let mut engine = RenameEngine::with_patterns(RenamePatterns::default());
+        engine.set_find_pattern(find)?;
+        engine.patterns.replace_pat = replace.to_string();
+        Ok(engine)
+    }
+
+    /// An engine with no files that renames them with `patterns`.
+    pub fn with_patterns(patterns: RenamePatterns) -> Self {
+        RenameEngine {
+            patterns,
+            respect_gitignore: false,
+            include_hidden: false,
+            items: Vec::new(),
+        }
+    }
+
+    /// Compiles `find` with the flags set in `patterns` and makes it the find
+    /// pattern. The old one is kept if it doesn't compile.
+    pub fn set_find_pattern(&mut self, find: &str) -> Result<(), regex::Error> {
+        self.patterns.find_pat = self.patterns.compile_pattern(find)?;
+        self.patterns.find_pat_raw = find.to_string();
+        Ok(())
+    }
+
+    /// Adds the files at `paths`, and when `recursive` is set the files in any
+    /// directories among them, as `respect_gitignore` and `include_hidden` say.
+    /// Returns a message for each path that couldn't be added.
+    pub fn add_paths(&mut self, paths: &[String], recursive: bool) -> Vec<String> {
+        let mut failures = Vec::new();
+        load_paths(
+            paths,
+            recursive,
+            self.respect_gitignore,
+            self.include_hidden,
+            &mut self.items,
+            &mut failures,
+        );
+        failures
+    }
+
+    /// The files, in the order they were added, which is the order `${n}` counts in.
+    pub fn items(&self) -> &[RenameItem] {
+        &self.items
+    }
+
+    /// The files, to rename some by hand. Set `manually_edited` on those so
+    /// `update` leaves them alone.
+    pub fn items_mut(&mut self) -> &mut [RenameItem] {
+        &mut self.items
+    }
+
+    /// Works out every file's new name from the patterns. Returns how many files had
+    /// time tokens that couldn't be read, which expand to nothing.
+    pub fn update(&mut self) -> usize {
+        let order: Vec<usize> = (0..self.items.len()).collect();
+        apply_patterns(&mut self.items, &order, &self.patterns)
+    }
+
+    /// The problems with the new names from the last `update`.
+    pub fn check(&self) -> CheckResult {
+        check_renames(&self.items, &self.patterns)
+    }
+
+    /// Renames the files to their new names, or copies them when `copy_files` is
+    /// set. Existing files at a destination are overwritten, as `check` warns about
+    /// in `overwrite_targets`, but files that appear there while renaming aren't.
+    /// Renamed files are pointed at their new location.
+    pub fn perform(&mut self) -> RenameResult {
+        let existing = existing_targets(&self.items);
+        perform_renames(
+            &mut self.items,
+            &HashSet::new(),
+            &existing,
+            self.patterns.copy_files,
+        )
+    }
+
+    /// Moves the files in `result` back to where they were, such as after `perform`.
+    pub fn undo(&mut self, result: &RenameResult) -> RenameResult {
+        let undone = undo_moves(&result.moved);
+        relocate_items(&mut self.items, &undone.moved);
+        record_errors(&mut self.items, &undone);
+        undone
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty scratch directory unique to this test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tui_rename_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn item_with_rename(dir: &std::path::Path, original: &str, renamed: &str) -> RenameItem {
+        let path = dir.join(original);
+        fs::write(&path, original).unwrap();
+        let mut item = RenameItem::new(path).unwrap();
+        item.renamed = renamed.to_string();
+        item
+    }
+
+    /// The order the items were loaded in, as if the table wasn't sorted.
+    fn loaded_order(items: &[RenameItem]) -> Vec<usize> {
+        (0..items.len()).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walking_directories_survives_symlink_loops() {
+        let dir = test_dir("walk");
+        fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        fs::write(dir.join("top.txt"), "").unwrap();
+        fs::write(dir.join("sub/deeper/low.txt"), "").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing"), dir.join("broken")).unwrap();
+
+        let mut items = Vec::new();
+        let mut failed_items = Vec::new();
+        walk_directory(&dir, false, true, &mut items, &mut failed_items);
+
+        let displayed: Vec<_> = items.iter().map(|item| item.display.as_str()).collect();
+        assert_eq!(displayed, ["top.txt", "sub/deeper/low.txt"]);
+        assert_eq!(items[1].original, "low.txt");
+        assert_eq!(
+            failed_items,
+            [format!(
+                "{} — broken symlink",
+                dir.join("broken").to_string_lossy()
+            )]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_only_items_are_skipped() {
+        let dir = test_dir("read_only_skipped");
+        let writable = item_with_rename(&dir, "writable.txt", "writable_new.txt");
+        let locked = item_with_rename(&dir, "locked.txt", "locked_new.txt");
+
+        let mut perms = fs::metadata(&locked.file).unwrap().permissions();
+        perms.set_readonly(true);
+        fs::set_permissions(&locked.file, perms).unwrap();
+
+        let mut items = vec![writable, locked];
+        let result = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+
+        assert_eq!(result.moved.len(), 1);
+        assert!(result.failures.is_empty());
+        assert!(dir.join("writable_new.txt").exists());
+        assert!(dir.join("locked.txt").exists());
+        assert!(!dir.join("locked_new.txt").exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Asserts each `(name, content)` pair, where test files hold their original name.
+    fn assert_contents(dir: &Path, expected: &[(&str, &str)]) {
+        for (name, content) in expected {
+            assert_eq!(&fs::read_to_string(dir.join(name)).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn literal_mode_escapes_patterns() {
+        let mut patterns = RenamePatterns {
+            find_pat_raw: "a.b".to_string(),
+            replace_pat: "$1".to_string(),
+            literal: true,
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = patterns.compile().unwrap();
+
+        let mut literal = RenameItem::new(PathBuf::from("a.b.txt")).unwrap();
+        let mut other = RenameItem::new(PathBuf::from("aXb.txt")).unwrap();
+        literal.set_pattern(&patterns, &patterns.replacements(1, &literal.file));
+        other.set_pattern(&patterns, &patterns.replacements(1, &other.file));
+
+        assert_eq!(literal.renamed, "$1.txt");
+        assert_eq!(other.renamed, "aXb.txt");
+    }
+
+    /// Renames `name` with the given find and replace patterns.
+    fn renamed_with(
+        patterns: &mut RenamePatterns,
+        find: &str,
+        replace: &str,
+        name: &str,
+    ) -> String {
+        patterns.find_pat_raw = find.to_string();
+        patterns.replace_pat = replace.to_string();
+        patterns.find_pat = patterns.compile().unwrap();
+
+        let mut item = RenameItem::new(PathBuf::from(name)).unwrap();
+        item.set_pattern(patterns, &patterns.replacements(1, &item.file));
+        item.renamed
+    }
+
+    /// Renames every name in `names` together, in the order given.
+    fn all_renamed_with(
+        patterns: &mut RenamePatterns,
+        find: &str,
+        replace: &str,
+        names: &[&str],
+    ) -> Vec<String> {
+        patterns.find_pat_raw = find.to_string();
+        patterns.replace_pat = replace.to_string();
+        patterns.find_pat = patterns.compile().unwrap();
+
+        let mut items: Vec<_> = names
+            .iter()
+            .map(|name| RenameItem::new(PathBuf::from(name)).unwrap())
+            .collect();
+        let order = loaded_order(&items);
+        apply_patterns(&mut items, &order, patterns);
+        items.into_iter().map(|item| item.renamed).collect()
+    }
+
+    #[test]
+    fn time_tokens_format_the_file_times() {
+        let dir = test_dir("time_tokens");
+        let item = item_with_rename(&dir, "a.txt", "a.txt");
+        let modified = fs::metadata(&item.file).unwrap().modified().unwrap();
+        let date = DateTime::<Local>::from(modified)
+            .format("%Y-%m-%d")
+            .to_string();
+
+        let mut patterns = RenamePatterns {
+            replace_pat: "${mtime:%Y-%m-%d}_$0".to_string(),
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = Regex::new(".+").unwrap();
+        let mut items = vec![item, RenameItem::new(dir.join("missing.txt")).unwrap()];
+
+        let order = loaded_order(&items);
+        assert_eq!(apply_patterns(&mut items, &order, &patterns), 1);
+        assert_eq!(items[0].renamed, format!("{}_a.txt", date));
+        assert_eq!(items[1].renamed, "_missing.txt");
+
+        patterns.replace_pat = "${atime}${ctime:%Y}${mtime:%Q}".to_string();
+        assert_eq!(
+            patterns.replacements(1, &items[0].file)[0].len(),
+            "2000-01-012000".len()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counter_tokens_number_items_in_order() {
+        let mut patterns = RenamePatterns::default();
+        let names = ["b.png", "a.png", "c.png"];
+
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "image_${n}", &names),
+            ["image_1.png", "image_2.png", "image_3.png"]
+        );
+        assert_eq!(
+            all_renamed_with(&mut patterns, "^", "${n:3}_", &names),
+            ["001_b.png", "002_a.png", "003_c.png"]
+        );
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "$${n}_${n}", &names[..1]),
+            ["${n}_1.png"]
+        );
+
+        patterns.literal = true;
+        assert_eq!(
+            all_renamed_with(&mut patterns, "b", "$1_${n:2}", &names[..1]),
+            ["$1_01.png"]
+        );
+    }
+
+    #[test]
+    fn escaped_tokens_are_kept_as_written() {
+        let mut patterns = RenamePatterns::default();
+        let names = ["photo.jpg"];
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "$${stem}-$${n:3}-${stem}", &names),
+            ["${stem}-${n:3}-photo.jpg"]
+        );
+        // `$$` is read first, so a third `$` starts a token again
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "$$${n}_$$$$1", &names),
+            ["$1_$$1.jpg"]
+        );
+
+        // In literal mode a lone `$` is plain text, but `$${` still escapes
+        patterns.literal = true;
+        assert_eq!(
+            all_renamed_with(&mut patterns, "photo", "$$${n}-$$-${n}", &names),
+            ["$${n}-$$-1.jpg"]
+        );
+    }
+
+    #[test]
+    fn stem_only_keeps_the_final_extension() {
+        let mut patterns = RenamePatterns::default();
+
+        assert_eq!(
+            renamed_with(&mut patterns, "(?i)jpg|photo", "x", "photo.JPG"),
+            "x.JPG"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, r"\.", "_", "archive.tar.gz"),
+            "archive_tar.gz"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "bash", "zsh", ".bashrc"),
+            ".zshrc"
+        );
+        assert_eq!(renamed_with(&mut patterns, "^", "_", ".bashrc"), "_.bashrc");
+
+        patterns.stem_only = false;
+        assert_eq!(
+            renamed_with(&mut patterns, r"\.", "_", "archive.tar.gz"),
+            "archive_tar_gz"
+        );
+    }
+
+    #[test]
+    fn counter_can_restart_per_directory() {
+        let mut patterns = RenamePatterns {
+            counter_per_directory: true,
+            ..RenamePatterns::default()
+        };
+        let names = [
+            "one/a.jpg",
+            "two/b.jpg",
+            "one/c.jpg",
+            "two/d.jpg",
+            "two/e.jpg",
+        ];
+
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "${n}", &names),
+            ["1.jpg", "1.jpg", "2.jpg", "2.jpg", "3.jpg"]
+        );
+
+        patterns.counter_per_directory = false;
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "${n}", &names),
+            ["1.jpg", "2.jpg", "3.jpg", "4.jpg", "5.jpg"]
+        );
+    }
+
+    #[test]
+    fn counter_can_restart_per_extension() {
+        let mut patterns = RenamePatterns {
+            counter_per_extension: true,
+            ..RenamePatterns::default()
+        };
+        let names = ["a.jpg", "b.png", "c.JPG", "d.jpg", "e.png", "README"];
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "${n}", &names),
+            ["1.jpg", "1.png", "2.JPG", "3.jpg", "2.png", "1"]
+        );
+
+        // Directories and extensions can both restart it
+        patterns.counter_per_directory = true;
+        assert_eq!(
+            all_renamed_with(
+                &mut patterns,
+                ".+",
+                "${n}",
+                &["one/a.jpg", "two/b.jpg", "one/c.png", "one/d.jpg"]
+            ),
+            ["1.jpg", "1.jpg", "1.png", "2.jpg"]
+        );
+    }
+
+    #[test]
+    fn only_picked_items_are_renamed() {
+        let mut patterns = RenamePatterns {
+            selection_only: true,
+            replace_pat: "${n}".to_string(),
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = Regex::new(".+").unwrap();
+        let dir = test_dir("picked");
+        let mut items: Vec<RenameItem> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| item_with_rename(&dir, name, name))
+            .collect();
+        items[1].picked = true;
+        items[2].picked = true;
+
+        let order = loaded_order(&items);
+        apply_patterns(&mut items, &order, &patterns);
+        let renamed: Vec<&str> = items.iter().map(|item| item.renamed.as_str()).collect();
+        assert_eq!(renamed, ["a.txt", "1.txt", "2.txt"]);
+        assert_eq!(planned_moves(&items, &HashSet::new()).len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manual_renames_are_kept() {
+        let mut patterns = RenamePatterns {
+            find_pat_raw: "a".to_string(),
+            replace_pat: "b".to_string(),
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = patterns.compile().unwrap();
+        let mut items = vec![
+            RenameItem::new(PathBuf::from("a1.txt")).unwrap(),
+            RenameItem::new(PathBuf::from("a2.txt")).unwrap(),
+        ];
+        items[1].renamed = "custom.txt".to_string();
+        items[1].manually_edited = true;
+
+        let order = loaded_order(&items);
+        apply_patterns(&mut items, &order, &patterns);
+        assert_eq!(items[0].renamed, "b1.txt");
+        assert_eq!(items[1].renamed, "custom.txt");
+
+        items[1].manually_edited = false;
+        let order = loaded_order(&items);
+        apply_patterns(&mut items, &order, &patterns);
+        assert_eq!(items[1].renamed, "b2.txt");
+    }
+
+    #[test]
+    fn chained_rules_feed_each_other() {
+        let mut patterns = RenamePatterns::default();
+        patterns
+            .rules
+            .push((Regex::new("_").unwrap(), " ".to_string()));
+        patterns
+            .rules
+            .push((Regex::new(r"(\w+) (\w+)").unwrap(), "$2 $1".to_string()));
+
+        assert_eq!(
+            renamed_with(&mut patterns, "-", "_", "first-second.txt"),
+            "second first.txt"
+        );
+        assert_eq!(
+            all_renamed_with(&mut patterns, "^", "${n}_", &["a.txt", "b.txt"]),
+            ["a 1.txt", "b 2.txt"]
+        );
+    }
+
+    #[test]
+    fn whitespace_is_trimmed_and_collapsed() {
+        let mut patterns = RenamePatterns {
+            trim_whitespace: true,
+            collapse_spaces: true,
+            ..RenamePatterns::default()
+        };
+
+        assert_eq!(
+            renamed_with(&mut patterns, "_", " ", "_a__b\t_.txt"),
+            "a b.txt"
+        );
+        assert_eq!(renamed_with(&mut patterns, "x", "", " x .txt"), ".txt");
+
+        patterns.stem_only = false;
+        assert_eq!(renamed_with(&mut patterns, "x$", " ", "  a.txt x"), "a.txt");
+        assert_eq!(renamed_with(&mut patterns, "a", " ", "a"), "");
+    }
+
+    #[test]
+    fn empty_names_are_flagged() {
+        let mut patterns = RenamePatterns {
+            stem_only: false,
+            ..RenamePatterns::default()
+        };
+        let renamed = all_renamed_with(&mut patterns, ".*", "", &["a.txt", "b.txt"]);
+        assert_eq!(renamed, ["", ""]);
+
+        let mut items = vec![
+            RenameItem::new(PathBuf::from("a.txt")).unwrap(),
+            RenameItem::new(PathBuf::from("b.txt")).unwrap(),
+            RenameItem::new(PathBuf::from("c.txt")).unwrap(),
+        ];
+        items[0].renamed = "".to_string();
+        items[1].renamed = "  ".to_string();
+
+        let check_result = check_renames(&items, &RenamePatterns::default());
+        assert_eq!(check_result.empty_names, ["a.txt", "b.txt"]);
+        assert!(check_result.invalid_names.is_empty());
+    }
+
+    #[test]
+    fn case_escapes_transform_the_replacement() {
+        let mut patterns = RenamePatterns::default();
+
+        assert_eq!(
+            renamed_with(
+                &mut patterns,
+                r"(\w+)_(\w+)",
+                r"\U$1\E_$2",
+                "hello_World.txt"
+            ),
+            "HELLO_World.txt"
+        );
+        assert_eq!(
+            renamed_with(
+                &mut patterns,
+                r"(\w+) (\w+)",
+                r"\u\L$1\E \L$2",
+                "mIxEd CaSe.txt"
+            ),
+            "Mixed case.txt"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "o", r"\U0", "foo_bar.txt"),
+            "f00_bar.txt"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "a(.)", r"\U$1\", "abac.txt"),
+            r"B\C\.txt"
+        );
+
+        patterns.literal = true;
+        assert_eq!(
+            renamed_with(&mut patterns, "bar", r"\Ubaz", "foo_bar.txt"),
+            r"foo_\Ubaz.txt"
+        );
+    }
+
+    #[test]
+    fn existing_files_are_overwrite_targets() {
+        let dir = test_dir("overwrite_targets");
+        fs::write(dir.join("existing.txt"), "").unwrap();
+        let items = vec![
+            item_with_rename(&dir, "foo.txt", "existing.txt"),
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "a.txt"),
+        ];
+
+        let check_result = check_renames(&items, &RenamePatterns::default());
+
+        assert_eq!(
+            check_result.overwrite_targets,
+            vec![dir.join("existing.txt").to_string_lossy().to_string()]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn renames_onto_skipped_targets_are_left_out() {
+        let dir = test_dir("skipped_targets");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", "existing.txt"),
+            item_with_rename(&dir, "b.txt", "c.txt"),
+        ];
+        let skipped: HashSet<PathBuf> = [dir.join("existing.txt")].into_iter().collect();
+
+        assert_eq!(
+            planned_moves(&items, &skipped),
+            [(dir.join("b.txt"), dir.join("c.txt"))]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn invalid_names_are_detected() {
+        let mut items = vec![
+            RenameItem::new(PathBuf::from("a.txt")).unwrap(),
+            RenameItem::new(PathBuf::from("b.txt")).unwrap(),
+        ];
+        items[0].renamed = "what?.txt".to_string();
+        items[1].renamed = "nul\0.txt".to_string();
+
+        let check_result = check_renames(&items, &RenamePatterns::default());
+
+        if cfg!(windows) {
+            assert_eq!(check_result.invalid_names, ["what?.txt", "nul\0.txt"]);
+            assert!(!is_valid_name("trailing."));
+            assert!(!is_valid_name("trailing "));
+        } else {
+            assert_eq!(check_result.invalid_names, ["nul\0.txt"]);
+            assert!(is_valid_name("trailing."));
+        }
+    }
+
+    #[test]
+    fn separators_move_files_into_new_directories() {
+        let dir = test_dir("separators");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "sub/dir/a.txt"),
+            item_with_rename(&dir, "b.txt", "c.txt"),
+        ];
+
+        let check_result = check_renames(&items, &RenamePatterns::default());
+        assert_eq!(check_result.moved_names, ["sub/dir/a.txt"]);
+        assert!(check_result.invalid_names.is_empty());
+
+        let result = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+        assert!(result.failures.is_empty());
+        assert_contents(&dir, &[("sub/dir/a.txt", "a.txt"), ("c.txt", "b.txt")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn swapped_names_are_renamed_through_a_temporary() {
+        let dir = test_dir("two_cycle");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "a.txt"),
+        ];
+
+        let result = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+
+        assert_eq!(result.moved.len(), 2);
+        assert!(result.failures.is_empty());
+        assert_contents(&dir, &[("a.txt", "b.txt"), ("b.txt", "a.txt")]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        assert_eq!(items[0].file, dir.join("b.txt"));
+        assert_eq!(items[1].file, dir.join("a.txt"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn three_cycle_and_chain_are_renamed_in_order() {
+        let dir = test_dir("three_cycle");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "c.txt"),
+            item_with_rename(&dir, "c.txt", "a.txt"),
+            item_with_rename(&dir, "x.txt", "y.txt"),
+            item_with_rename(&dir, "y.txt", "z.txt"),
+        ];
+
+        let result = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+
+        assert_eq!(result.moved.len(), 5);
+        assert!(result.failures.is_empty());
+        assert_contents(
+            &dir,
+            &[
+                ("b.txt", "a.txt"),
+                ("c.txt", "b.txt"),
+                ("a.txt", "c.txt"),
+                ("y.txt", "x.txt"),
+                ("z.txt", "y.txt"),
+            ],
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 5);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cycles_fed_by_another_move_are_broken() {
+        let dir = test_dir("fed_cycle");
+        let mut items = vec![
+            item_with_rename(&dir, "x.txt", "a.txt"),
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "a.txt"),
+        ];
+
+        let result = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+
+        // The swap is made, and the file waiting on it finds its target taken
+        assert_eq!(result.moved.len(), 2);
+        assert_eq!(result.failures.len(), 1);
+        assert_contents(
+            &dir,
+            &[("x.txt", "x.txt"), ("a.txt", "b.txt"), ("b.txt", "a.txt")],
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn large_sets_are_renamed_correctly() {
+        let dir = test_dir("large_set");
+        let mut items: Vec<RenameItem> = (0..2000)
+            .map(|i| {
+                let original = format!("{}.txt", i);
+                // Independent renames, with a chain of shifted names and swapped pairs mixed in
+                let renamed = match i {
+                    i if i < 1000 => format!("renamed_{}.txt", i),
+                    i if i < 1499 => format!("{}.txt", i + 1),
+                    1499 => "chain_end.txt".to_string(),
+                    i if i % 2 == 0 => format!("{}.txt", i + 1),
+                    i => format!("{}.txt", i - 1),
+                };
+                item_with_rename(&dir, &original, &renamed)
+            })
+            .collect();
+
+        let result = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+        assert!(result.failures.is_empty());
+        assert_eq!(result.moved.len(), 2000);
+
+        for item in &items {
+            let expected = item.original.clone();
+            assert_eq!(fs::read_to_string(&item.file).unwrap(), expected);
+            assert_eq!(item.file, dir.join(&item.renamed));
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2000);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cancelling_stops_the_remaining_renames() {
+        let dir = test_dir("cancel");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "a.txt"),
+            item_with_rename(&dir, "c.txt", "d.txt"),
+            item_with_rename(&dir, "d.txt", "e.txt"),
+            item_with_rename(&dir, "e.txt", "f.txt"),
+        ];
+        let moves = planned_moves(&items, &HashSet::new());
+
+        // Cancel as soon as the first file has moved
+        let cancelled = AtomicBool::new(false);
+        let result = run_moves(
+            moves,
+            Vec::new(),
+            &HashSet::new(),
+            false,
+            &cancelled,
+            &|| cancelled.store(true, Ordering::Relaxed),
+        );
+
+        assert!(result.failures.is_empty());
+        assert!(result.cancelled > 0);
+        assert_eq!(result.moved.len() + result.cancelled, 5);
+        for (from, to) in &result.moved {
+            let name = from.file_name().unwrap().to_str().unwrap();
+            assert_eq!(fs::read_to_string(to).unwrap(), name);
+        }
+        assert!(fs::read_dir(&dir).unwrap().all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with('.')));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undo_restores_original_names() {
+        let dir = test_dir("undo");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "a.txt"),
+            item_with_rename(&dir, "c.txt", "d.txt"),
+        ];
+        let applied = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+
+        let undone = undo_moves(&applied.moved);
+        relocate_items(&mut items, &undone.moved);
+
+        assert_eq!(undone.moved.len(), 3);
+        assert!(undone.failures.is_empty());
+        assert_contents(
+            &dir,
+            &[("a.txt", "a.txt"), ("b.txt", "b.txt"), ("c.txt", "c.txt")],
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        assert_eq!(items[2].file, dir.join("c.txt"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn undo_does_not_overwrite_new_files() {
+        let dir = test_dir("undo_collision");
+        let mut items = vec![item_with_rename(&dir, "a.txt", "b.txt")];
+        let applied = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+        fs::write(dir.join("a.txt"), "new").unwrap();
+
+        let undone = undo_moves(&applied.moved);
+
+        assert!(undone.moved.is_empty());
+        assert_eq!(undone.failures.len(), 1);
+        assert_contents(&dir, &[("a.txt", "new"), ("b.txt", "a.txt")]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn paths_without_a_file_name_fail_to_load() {
+        let dir = test_dir("no_file_name");
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        let filenames = vec![
+            "/".to_string(),
+            "../".to_string(),
+            dir.join("a.txt").to_string_lossy().to_string(),
+            dir.join("missing.txt").to_string_lossy().to_string(),
+        ];
+
+        let mut items = Vec::new();
+        let mut failed_items = Vec::new();
+        load_paths(
+            &filenames,
+            false,
+            false,
+            false,
+            &mut items,
+            &mut failed_items,
+        );
+
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].original, "a.txt");
+        assert_eq!(
+            failed_items,
+            vec![
+                "/ — has no file name".to_string(),
+                "../ — has no file name".to_string(),
+                format!(
+                    "{} — doesn't exist",
+                    dir.join("missing.txt").to_string_lossy()
+                ),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copying_keeps_contents_and_times() {
+        let dir = test_dir("copy_and_remove");
+        let from = dir.join("a.txt");
+        fs::write(&from, "a.txt").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&from)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        copy_and_remove(&from, &dir.join("b.txt")).unwrap();
+
+        assert_contents(&dir, &[("b.txt", "a.txt")]);
+        assert!(!from.exists());
+        let copied = fs::metadata(dir.join("b.txt")).unwrap();
+        assert_eq!(copied.modified().unwrap(), modified);
+
+        // A file being overwritten is kept when the copy can't be made
+        assert!(copy_and_remove(&dir.join("missing.txt"), &dir.join("b.txt")).is_err());
+        assert_contents(&dir, &[("b.txt", "a.txt")]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn full_paths_can_be_matched() {
+        let mut patterns = RenamePatterns {
+            find_pat_raw: "2023".to_string(),
+            replace_pat: "2024".to_string(),
+            full_path: true,
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = patterns.compile().unwrap();
+
+        let mut item = RenameItem::new(PathBuf::from("2023/img_2023.jpg")).unwrap();
+        apply_patterns(std::slice::from_mut(&mut item), &[0], &patterns);
+        assert_eq!(item.renamed, "2024/img_2024.jpg");
+        assert_eq!(item.destination(), PathBuf::from("2024/img_2024.jpg"));
+        assert!(item.is_changed());
+        assert!(item.is_moved());
+
+        patterns.find_pat_raw = "img".to_string();
+        patterns.find_pat = patterns.compile().unwrap();
+        apply_patterns(std::slice::from_mut(&mut item), &[0], &patterns);
+        assert_eq!(item.destination(), PathBuf::from("2023/2024_2023.jpg"));
+        assert!(!item.is_moved());
+    }
+
+    #[test]
+    fn names_differing_in_unicode_form_conflict_when_normalized() {
+        let dir = test_dir("normalize_unicode");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", "caf\u{e9}.txt"),
+            item_with_rename(&dir, "b.txt", "cafe\u{301}.txt"),
+        ];
+
+        let mut patterns = RenamePatterns::default();
+        assert!(check_renames(&items, &patterns)
+            .conflicting_names
+            .is_empty());
+        patterns.normalize_unicode = true;
+        assert_eq!(check_renames(&items, &patterns).conflicting_names.len(), 1);
+
+        let mut item = RenameItem::new(dir.join("cafe\u{301}.txt")).unwrap();
+        item.set_pattern(&patterns, &[String::new()]);
+        assert_eq!(item.renamed, "caf\u{e9}.txt");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_shown_relative_to_the_base() {
+        let base = Path::new("/home/user");
+        let display = |file: &str| {
+            let mut item = RenameItem::new(PathBuf::from(file)).unwrap();
+            item.display_relative_to(base);
+            item.display
+        };
+
+        assert_eq!(display("photos/a.jpg"), "photos/a.jpg");
+        assert_eq!(display("/home/user/photos/b.jpg"), "photos/b.jpg");
+        assert_eq!(display("/tmp/c.jpg"), "c.jpg");
+        assert_eq!(display("../d.jpg"), "d.jpg");
+    }
+
+    #[test]
+    fn extension_case_is_changed_on_its_own() {
+        let mut patterns = RenamePatterns {
+            lowercase_extension: true,
+            ..RenamePatterns::default()
+        };
+        assert_eq!(
+            all_renamed_with(
+                &mut patterns,
+                "",
+                "",
+                &["IMG.JPG", "Archive.TAR.GZ", "README"]
+            ),
+            ["IMG.jpg", "Archive.TAR.gz", "README"]
+        );
+
+        patterns.lowercase_extension = false;
+        patterns.uppercase_extension = true;
+        patterns.stem_only = false;
+        assert_eq!(
+            renamed_with(&mut patterns, "photo", "pic", "photo.jpg"),
+            "pic.JPG"
+        );
+    }
+
+    #[test]
+    fn long_names_are_cut_to_the_limit() {
+        let mut patterns = RenamePatterns {
+            max_name_length: Some(10),
+            stem_only: false,
+            ..RenamePatterns::default()
+        };
+        let names = [
+            "holiday photo.jpg",
+            "holiday photo",
+            "short.jpg",
+            "a.verylongextension",
+        ];
+        assert_eq!(
+            all_renamed_with(&mut patterns, "", "", &names),
+            ["holida.jpg", "holiday ph", "short.jpg", "a.verylong"]
+        );
+
+        // The ellipsis takes up one of the characters, and nothing is cut mid-letter
+        patterns.truncate_with_ellipsis = true;
+        assert_eq!(
+            all_renamed_with(&mut patterns, "", "", &names[..2]),
+            ["holid….jpg", "holiday p…"]
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "", "", "日本語のファイル名です.txt"),
+            "日本語のフ….txt"
+        );
+
+        let mut item = RenameItem::new(PathBuf::from("holiday photo.jpg")).unwrap();
+        item.set_pattern(&patterns, &patterns.replacements(1, &item.file));
+        assert!(item.truncated);
+        item.rename_to("short.jpg".to_string());
+        assert!(!item.truncated);
+    }
+
+    #[test]
+    fn whole_names_change_case_after_the_patterns() {
+        let mut patterns = RenamePatterns {
+            lowercase_name: true,
+            stem_only: false,
+            ..RenamePatterns::default()
+        };
+        assert_eq!(
+            all_renamed_with(&mut patterns, "", "", &["Holiday PHOTO.JPG", "README"]),
+            ["holiday photo.jpg", "readme"]
+        );
+        // Keeping the extension keeps its case, unless it's changed on its own
+        patterns.stem_only = true;
+        assert_eq!(
+            renamed_with(&mut patterns, "", "", "IMG 01.JPG"),
+            "img 01.JPG"
+        );
+        patterns.uppercase_extension = true;
+        assert_eq!(
+            renamed_with(&mut patterns, "", "", "IMG 01.jpg"),
+            "img 01.JPG"
+        );
+
+        // Folding first means every letter it spells out is cased
+        patterns = RenamePatterns {
+            uppercase_name: true,
+            ascii_fold: true,
+            stem_only: false,
+            ..RenamePatterns::default()
+        };
+        assert_eq!(renamed_with(&mut patterns, "", "", "Жук.txt"), "ZHUK.TXT");
+        patterns.ascii_fold = false;
+        assert_eq!(
+            renamed_with(&mut patterns, " a", " b", "straße a.txt"),
+            "STRASSE B.TXT"
+        );
+    }
+
+    #[test]
+    fn name_case_follows_unicode_rather_than_a_language() {
+        let mut patterns = RenamePatterns {
+            lowercase_name: true,
+            stem_only: false,
+            ..RenamePatterns::default()
+        };
+        // Turkish would lowercase `I` to dotless `ı`, and `İ` to a plain `i`
+        assert_eq!(
+            renamed_with(&mut patterns, "", "", "İSTANBUL IZMIR.txt"),
+            "i\u{307}stanbul izmir.txt"
+        );
+        // Σ is only a final ς at the end of a word, and the extension's dot doesn't
+        // end one
+        assert_eq!(
+            renamed_with(&mut patterns, "", "", "ΣΟΦΟΣ ΝΟΥΣ"),
+            "σοφος νους"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "", "", "ΣΟΦΟΣ.txt"),
+            "σοφοσ.txt"
+        );
+
+        patterns.lowercase_name = false;
+        patterns.uppercase_name = true;
+        assert_eq!(renamed_with(&mut patterns, "", "", "ıi.txt"), "II.TXT");
+    }
+
+    #[test]
+    fn copying_keeps_the_originals() {
+        let dir = test_dir("copy_mode");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "c.txt"),
+            item_with_rename(&dir, "b.txt", "a.txt"),
+        ];
+
+        // The originals stay put, so the check warns about overwriting them
+        let existing = existing_targets(&items);
+        let result = perform_renames(&mut items, &HashSet::new(), &existing, true);
+
+        assert_eq!(result.moved.len(), 2);
+        assert!(result.failures.is_empty());
+        assert_contents(
+            &dir,
+            &[("a.txt", "b.txt"), ("b.txt", "b.txt"), ("c.txt", "a.txt")],
+        );
+        assert_eq!(items[0].file, dir.join("a.txt"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Swapping still works, through a parked copy
+        let dir = test_dir("copy_mode_swap");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "a.txt"),
+        ];
+        let existing = existing_targets(&items);
+        perform_renames(&mut items, &HashSet::new(), &existing, true);
+        assert_contents(&dir, &[("a.txt", "b.txt"), ("b.txt", "a.txt")]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn conflicts_are_told_apart_from_collisions_with_unchanged_files() {
+        let dir = test_dir("conflict_kinds");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", "x.txt"),
+            item_with_rename(&dir, "b.txt", "x.txt"),
+            item_with_rename(&dir, "c.txt", "d.txt"),
+            item_with_rename(&dir, "d.txt", "d.txt"),
+        ];
+        let check_result = check_renames(&items, &RenamePatterns::default());
+        let shown = |path: &str| dir.join(path).to_string_lossy().to_string();
+        assert_eq!(check_result.conflicting_names, [shown("x.txt")]);
+        assert_eq!(check_result.unchanged_collisions, [shown("d.txt")]);
+
+        // Files with the same name in different directories don't clash
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let items = vec![
+            item_with_rename(&dir, "e.txt", "f.txt"),
+            item_with_rename(&dir.join("sub"), "f.txt", "f.txt"),
+        ];
+        let check_result = check_renames(&items, &RenamePatterns::default());
+        assert!(check_result.conflicting_names.is_empty());
+        assert!(check_result.unchanged_collisions.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn conflicting_names_are_given_suffixes() {
+        let dir = test_dir("deduplicate");
+        let mut items = vec![
+            item_with_rename(&dir, "a.jpg", "photo.jpg"),
+            item_with_rename(&dir, "b.jpg", "photo.jpg"),
+            item_with_rename(&dir, "c.jpg", "photo.jpg"),
+            item_with_rename(&dir, "d", "notes"),
+            item_with_rename(&dir, "e", "notes"),
+            item_with_rename(&dir, "f.txt", "other.txt"),
+        ];
+        // A suffix that's already taken is skipped
+        fs::write(dir.join("notes (1)"), "").unwrap();
+
+        let order = loaded_order(&items);
+        assert_eq!(
+            deduplicate_names(&mut items, &order, &RenamePatterns::default()),
+            3
+        );
+        let renamed: Vec<&str> = items.iter().map(|item| item.renamed.as_str()).collect();
+        assert_eq!(
+            renamed,
+            [
+                "photo.jpg",
+                "photo (1).jpg",
+                "photo (2).jpg",
+                "notes",
+                "notes (2)",
+                "other.txt"
+            ]
+        );
+        assert!(items[1].manually_edited && !items[0].manually_edited);
+        assert!(check_renames(&items, &RenamePatterns::default())
+            .conflicting_names
+            .is_empty());
+
+        // A name a file is keeping goes to none of them
+        let mut items = vec![
+            item_with_rename(&dir, "g.txt", "h.txt"),
+            item_with_rename(&dir, "h.txt", "h.txt"),
+            item_with_rename(&dir, "i.txt", "h.txt"),
+        ];
+        let order = loaded_order(&items);
+        deduplicate_names(&mut items, &order, &RenamePatterns::default());
+        assert_eq!(items[0].renamed, "h (1).txt");
+        assert_eq!(items[2].renamed, "h (2).txt");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_differing_in_case_can_conflict() {
+        let dir = test_dir("case_conflicts");
+        let items = vec![
+            item_with_rename(&dir, "a.jpg", "photo.jpg"),
+            item_with_rename(&dir, "b.jpg", "Photo.JPG"),
+            item_with_rename(&dir, "c.jpg", "other.jpg"),
+        ];
+        let mut patterns = RenamePatterns {
+            case_insensitive_conflicts: false,
+            ..RenamePatterns::default()
+        };
+        assert!(check_renames(&items, &patterns)
+            .conflicting_names
+            .is_empty());
+
+        // Both spellings are reported
+        patterns.case_insensitive_conflicts = true;
+        let shown = |path: &str| dir.join(path).to_string_lossy().to_string();
+        assert_eq!(
+            check_renames(&items, &patterns).conflicting_names,
+            [shown("Photo.JPG"), shown("photo.jpg")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(any(target_os = "macos", windows))]
+    #[test]
+    fn names_differing_in_case_conflict_by_default() {
+        let dir = test_dir("case_conflicts_default");
+        let items = vec![
+            item_with_rename(&dir, "a.jpg", "photo.jpg"),
+            item_with_rename(&dir, "b.jpg", "Photo.JPG"),
+        ];
+        let check_result = check_renames(&items, &RenamePatterns::default());
+        assert_eq!(check_result.conflicting_names.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_only_files_stay_put_for_collisions() {
+        let dir = test_dir("read_only_collision");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "c.txt"),
+            item_with_rename(&dir, "d.txt", "c.txt"),
+        ];
+        let check_result = check_renames(&items, &RenamePatterns::default());
+        assert_eq!(check_result.conflicting_names.len(), 1);
+        assert!(check_result.unchanged_collisions.is_empty());
+
+        // b.txt can't move out of a.txt's way, and doesn't take c.txt from d.txt
+        let file = dir.join("b.txt");
+        let mut perms = fs::metadata(&file).unwrap().permissions();
+        perms.set_readonly(true);
+        fs::set_permissions(&file, perms).unwrap();
+        let check_result = check_renames(&items, &RenamePatterns::default());
+        let shown = |path: &str| dir.join(path).to_string_lossy().to_string();
+        assert_eq!(check_result.unchanged_collisions, [shown("b.txt")]);
+        assert!(check_result.conflicting_names.is_empty());
+        assert_eq!(check_result.permission_problems, [shown("b.txt")]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn long_destinations_are_flagged() {
+        let dir = test_dir("too_long");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", &format!("{}.txt", "x".repeat(300))),
+            item_with_rename(&dir, "b.txt", "c.txt"),
+        ];
+        let patterns = RenamePatterns {
+            max_path_length: Some(260),
+            ..RenamePatterns::default()
+        };
+
+        let check_result = check_renames(&items, &patterns);
+        assert_eq!(
+            check_result.too_long,
+            [items[0].destination().to_string_lossy()]
+        );
+        let unlimited = RenamePatterns {
+            max_path_length: None,
+            ..RenamePatterns::default()
+        };
+        assert!(check_renames(&items, &unlimited).too_long.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replacing_is_limited_to_the_character_range() {
+        let mut patterns = RenamePatterns {
+            range_start: Some(3),
+            range_length: Some(4),
+            ..RenamePatterns::default()
+        };
+        assert_eq!(
+            renamed_with(&mut patterns, "a", "o", "aaaaaaaa.txt"),
+            "aaooooaa.txt"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "^.", "X", "日本語のファイル.txt"),
+            "日本Xのファイル.txt"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, ".+", "[$0]", "caféçàö.txt"),
+            "ca[féçà]ö.txt"
+        );
+
+        // Ranges past the end of the name are cut short
+        patterns.range_start = Some(2);
+        patterns.range_length = Some(100);
+        assert_eq!(renamed_with(&mut patterns, "é", "e", "éé.txt"), "ée.txt");
+        patterns.range_start = Some(10);
+        assert_eq!(renamed_with(&mut patterns, "$", "!", "éé.txt"), "éé!.txt");
+        patterns.range_start = None;
+        patterns.range_length = Some(1);
+        assert_eq!(renamed_with(&mut patterns, "é", "e", "éé.txt"), "eé.txt");
+        assert_eq!(char_range("ab", Some(usize::MAX), Some(usize::MAX)), 2..2);
+    }
+
+    #[test]
+    fn numbers_are_padded_to_the_width() {
+        let mut patterns = RenamePatterns {
+            pad_numbers: Some(2),
+            ..RenamePatterns::default()
+        };
+        let names = ["ch1.txt", "ch2.txt", "ch10.txt", "ch100.txt", "intro.txt"];
+        assert_eq!(
+            all_renamed_with(&mut patterns, "", "", &names),
+            ["ch01.txt", "ch02.txt", "ch10.txt", "ch100.txt", "intro.txt"]
+        );
+
+        patterns.pad_numbers = Some(3);
+        assert_eq!(
+            renamed_with(&mut patterns, "", "", "s1e12 part 3.mp4"),
+            "s001e012 part 003.mp4"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "^", "${n}_", "7.mp4"),
+            "001_007.mp4"
+        );
+    }
+
+    #[test]
+    fn only_the_chosen_occurrence_is_replaced() {
+        let mut patterns = RenamePatterns {
+            occurrence: Some(1),
+            ..RenamePatterns::default()
+        };
+        assert_eq!(
+            renamed_with(&mut patterns, "a", "b", "banana.txt"),
+            "bbnana.txt"
+        );
+
+        patterns.occurrence = Some(2);
+        assert_eq!(
+            renamed_with(&mut patterns, "a", "o", "banana.txt"),
+            "banona.txt"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "(a)(n)", r"\U$2$1", "banana.txt"),
+            "banNAa.txt"
+        );
+        assert_eq!(renamed_with(&mut patterns, "x", "y", "x.txt"), "x.txt");
+
+        patterns.occurrence = None;
+        assert_eq!(
+            renamed_with(&mut patterns, "a", "o", "banana.txt"),
+            "bonono.txt"
+        );
+    }
+
+    #[test]
+    fn friendly_tokens_name_groups_in_braces() {
+        let mut patterns = RenamePatterns {
+            friendly_tokens: true,
+            ..RenamePatterns::default()
+        };
+        assert_eq!(
+            renamed_with(
+                &mut patterns,
+                r"(?P<year>\d{4})-(\d+)",
+                "{2} of {year} {{draft}} $5 {n:02}",
+                "2023-07.txt"
+            ),
+            "07 of 2023 {draft} $5 01.txt"
+        );
+
+        patterns.friendly_tokens = false;
+        assert_eq!(
+            renamed_with(
+                &mut patterns,
+                r"(?P<year>\d{4})",
+                "${year}_{year}",
+                "2023.txt"
+            ),
+            "2023_{year}.txt"
+        );
+    }
+
+    #[test]
+    fn names_are_folded_to_ascii() {
+        assert_eq!(ascii_fold("café crème brûlée.txt"), "cafe creme brulee.txt");
+        assert_eq!(ascii_fold("Ångström Straße Łódź"), "Angstrom Strasse Lodz");
+        assert_eq!(ascii_fold("Москва.jpg"), "Moskva.jpg");
+        assert_eq!(ascii_fold("東京 2024.png"), "Dong Jing 2024.png");
+        assert_eq!(ascii_fold("½ done"), "1-2 done");
+
+        let mut patterns = RenamePatterns {
+            ascii_fold: true,
+            ..RenamePatterns::default()
+        };
+        assert_eq!(
+            renamed_with(&mut patterns, "^", "né_", "über.txt"),
+            "ne_uber.txt"
+        );
+    }
+
+    #[test]
+    fn references_to_missing_groups_are_found() {
+        let mut patterns = RenamePatterns {
+            find_pat_raw: r"(?P<year>\d{4})-(\d+)".to_string(),
+            replace_pat: "$1-${2}-$3.${year}.$month.$$4.${n:02}.${mtime:%Y}".to_string(),
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = patterns.compile().unwrap();
+        assert_eq!(patterns.unresolved_references(), ["${3}", "${month}"]);
+
+        patterns.replace_pat = "$1$2".to_string();
+        assert!(patterns.unresolved_references().is_empty());
+        // The regex reads this as a group named `1_x`
+        patterns.replace_pat = "$1_x".to_string();
+        assert_eq!(patterns.unresolved_references(), ["${1_x}"]);
+
+        patterns.literal = true;
+        patterns.replace_pat = "$9".to_string();
+        assert!(patterns.unresolved_references().is_empty());
+    }
+
+    #[test]
+    fn ignored_files_are_left_out_when_recursing() {
+        let dir = test_dir("gitignore");
+        let project = dir.join("project");
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::create_dir_all(project.join("build")).unwrap();
+        fs::create_dir_all(project.join("sub")).unwrap();
+        fs::write(dir.join(".gitignore"), "*.log\n!keep.log\nbuild/\n").unwrap();
+        fs::write(project.join("sub/.ignore"), "c.txt\n").unwrap();
+        // Only `.gitignore` files count above the directory walked
+        fs::write(dir.join(".ignore"), "a.txt\n").unwrap();
+        for name in [
+            "a.txt",
+            "b.log",
+            "keep.log",
+            "build/out.txt",
+            "sub/c.txt",
+            "sub/d.txt",
+        ] {
+            fs::write(project.join(name), "").unwrap();
+        }
+
+        let filenames = [
+            project.to_string_lossy().to_string(),
+            project.join("b.log").to_string_lossy().to_string(),
+        ];
+        let mut items = Vec::new();
+        let mut failed_items = Vec::new();
+        load_paths(&filenames, true, true, true, &mut items, &mut failed_items);
+        let displayed: Vec<_> = items.iter().map(|item| item.display.as_str()).collect();
+        assert_eq!(
+            displayed,
+            ["a.txt", "keep.log", "sub/.ignore", "sub/d.txt", "b.log"]
+        );
+        assert!(failed_items.is_empty());
+
+        let mut items = Vec::new();
+        load_paths(
+            &filenames[..1],
+            true,
+            false,
+            true,
+            &mut items,
+            &mut failed_items,
+        );
+        assert_eq!(items.len(), 7);
+
+        // Walking into an ignored directory still leaves out what's in it
+        let mut items = Vec::new();
+        let build = [project.join("build").to_string_lossy().to_string()];
+        load_paths(&build, true, true, true, &mut items, &mut failed_items);
+        assert!(items.is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn env_tokens_expand_to_the_variable() {
+        std::env::set_var("TUI_RENAME_TEST_HOST", "box");
+        std::env::remove_var("TUI_RENAME_TEST_UNSET");
+        let mut patterns = RenamePatterns::default();
+        assert_eq!(
+            renamed_with(&mut patterns, "$", "_${env:TUI_RENAME_TEST_HOST}", "a.txt"),
+            "a_box.txt"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, "$", "_${env:TUI_RENAME_TEST_UNSET}", "a.txt"),
+            "a_.txt"
+        );
+        assert!(patterns.unresolved_references().is_empty());
+        assert_eq!(patterns.unset_variables(), ["TUI_RENAME_TEST_UNSET"]);
+        patterns.replace_pat = "${env:TUI_RENAME_TEST_HOST}".to_string();
+        assert!(patterns.unset_variables().is_empty());
+    }
+
+    #[test]
+    fn stem_and_extension_tokens_use_the_original_name() {
+        let mut patterns = RenamePatterns::default();
+        assert_eq!(
+            renamed_with(&mut patterns, ".+", "${ext}_${stem}", "report.final.pdf"),
+            "pdf_report.final.pdf"
+        );
+
+        patterns.stem_only = false;
+        assert_eq!(
+            renamed_with(&mut patterns, "final", "${ext}", "report.final.pdf"),
+            "report.pdf.pdf"
+        );
+        assert_eq!(
+            renamed_with(&mut patterns, ".+", "${stem}-$0.${ext}", "README"),
+            "README-README."
+        );
+        assert!(patterns.unresolved_references().is_empty());
+    }
+
+    #[test]
+    fn counter_starts_and_steps_as_set() {
+        let mut patterns = RenamePatterns {
+            counter_start: 100,
+            counter_step: 5,
+            ..RenamePatterns::default()
+        };
+        let names = ["a.png", "b.png", "c.png"];
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "${n}", &names),
+            ["100.png", "105.png", "110.png"]
+        );
+
+        patterns.counter_start = 0;
+        patterns.counter_step = -1;
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "${n:2}", &names),
+            ["00.png", "-1.png", "-2.png"]
+        );
+
+        patterns.counter_start = i64::MAX - 1;
+        patterns.counter_step = i64::MAX;
+        assert_eq!(
+            all_renamed_with(&mut patterns, ".+", "${n}", &names[..2]),
+            [format!("{}.png", i64::MAX - 1), format!("{}.png", i64::MAX)]
+        );
+    }
+
+    #[test]
+    fn missing_directories_are_flagged_unless_created() {
+        let dir = test_dir("missing_directories");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", "new/a.txt"),
+            item_with_rename(&dir, "b.txt", "new/b.txt"),
+            item_with_rename(&dir, "c.txt", "c2.txt"),
+        ];
+        let mut patterns = RenamePatterns {
+            create_directories: false,
+            ..RenamePatterns::default()
+        };
+        assert_eq!(
+            check_renames(&items, &patterns).missing_directories,
+            [dir.join("new").to_string_lossy().to_string()]
+        );
+
+        patterns.create_directories = true;
+        assert!(check_renames(&items, &patterns)
+            .missing_directories
+            .is_empty());
+
+        // A file where the directory should be can't be replaced by one
+        fs::write(dir.join("blocked"), "").unwrap();
+        let mut moves = vec![(dir.join("c.txt"), dir.join("blocked/c.txt"))];
+        let failures = create_target_directories(&mut moves);
+        assert!(moves.is_empty());
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].1.contains("couldn't create"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn empty_find_patterns_leave_names_alone() {
+        let mut patterns = RenamePatterns::default();
+        assert_eq!(
+            renamed_with(&mut patterns, "", "X", "photo.jpg"),
+            "photo.jpg"
+        );
+
+        patterns.rules = vec![(Regex::new("o").unwrap(), "0".to_string())];
+        assert_eq!(
+            renamed_with(&mut patterns, "", "X", "photo.jpg"),
+            "ph0t0.jpg"
+        );
+
+        patterns.literal = true;
+        patterns.rules.clear();
+        assert_eq!(
+            renamed_with(&mut patterns, "", "X", "photo.jpg"),
+            "photo.jpg"
+        );
+    }
+
+    #[test]
+    fn rename_result_counts_each_outcome() {
+        let dir = test_dir("result_counts");
+        fs::write(dir.join("blocker.txt"), "blocker.txt").unwrap();
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "a_new.txt"),
+            item_with_rename(&dir, "locked.txt", "locked_new.txt"),
+            item_with_rename(&dir, "warned.txt", "warned_new.txt"),
+            item_with_rename(&dir, "c.txt", "blocker.txt/c.txt"),
+            item_with_rename(&dir, "same.txt", "same.txt"),
+        ];
+        let mut perms = fs::metadata(&items[1].file).unwrap().permissions();
+        perms.set_readonly(true);
+        fs::set_permissions(&items[1].file, perms).unwrap();
+        let skipped_targets = HashSet::from([dir.join("warned_new.txt")]);
+
+        let result = perform_renames(&mut items, &skipped_targets, &HashSet::new(), false);
+
+        assert_eq!(result.moved.len(), 1);
+        assert_eq!(result.skipped, 2);
+        assert_eq!(result.failures.len(), 1);
+        assert_contents(&dir, &[("a_new.txt", "a.txt"), ("c.txt", "c.txt")]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn files_appearing_after_the_check_are_not_overwritten() {
+        let dir = test_dir("appeared_after_check");
+        fs::write(dir.join("old.txt"), "old.txt").unwrap();
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "old.txt"),
+            item_with_rename(&dir, "b.txt", "new.txt"),
+            item_with_rename(&dir, "c.txt", "c_new.txt"),
+            item_with_rename(&dir, "gone.txt", "gone_new.txt"),
+        ];
+        let existing = existing_targets(&items);
+
+        fs::write(dir.join("new.txt"), "new.txt").unwrap();
+        fs::remove_file(dir.join("gone.txt")).unwrap();
+        let result = perform_renames(&mut items, &HashSet::new(), &existing, false);
+
+        assert_eq!(result.moved.len(), 2);
+        assert_eq!(result.skipped, 0);
+        assert_eq!(result.failures.len(), 2);
+        assert!(result.failures[0].contains("new.txt appeared since the check"));
+        assert_eq!(
+            result.failures[1],
+            format!(
+                "{}: no longer exists",
+                dir.join("gone.txt").to_string_lossy()
+            )
+        );
+        assert_contents(
+            &dir,
+            &[
+                ("old.txt", "a.txt"),
+                ("new.txt", "new.txt"),
+                ("b.txt", "b.txt"),
+                ("c_new.txt", "c.txt"),
+            ],
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn hidden_files_are_left_out_when_recursing() {
+        let dir = test_dir("hidden_files");
+        fs::create_dir_all(dir.join(".cache")).unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in [
+            "a.txt",
+            ".DS_Store",
+            ".cache/b.txt",
+            "sub/c.txt",
+            "marked.txt",
+        ] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+        #[cfg(windows)]
+        std::process::Command::new("attrib")
+            .arg("+h")
+            .arg(dir.join("marked.txt"))
+            .status()
+            .unwrap();
+
+        let filenames = [
+            dir.to_string_lossy().to_string(),
+            dir.join(".DS_Store").to_string_lossy().to_string(),
+        ];
+        let mut items = Vec::new();
+        let mut failed_items = Vec::new();
+        load_paths(
+            &filenames,
+            true,
+            false,
+            false,
+            &mut items,
+            &mut failed_items,
+        );
+        let displayed: Vec<_> = items.iter().map(|item| item.display.as_str()).collect();
+        let hidden_attribute_shown = if cfg!(windows) {
+            vec![]
+        } else {
+            vec!["marked.txt"]
+        };
+        let expected: Vec<&str> = ["a.txt"]
+            .into_iter()
+            .chain(hidden_attribute_shown)
+            .chain(["sub/c.txt", ".DS_Store"])
+            .collect();
+        assert_eq!(displayed, expected);
+
+        let mut items = Vec::new();
+        load_paths(
+            &filenames[..1],
+            true,
+            false,
+            true,
+            &mut items,
+            &mut failed_items,
+        );
+        assert_eq!(items.len(), 5);
+        assert!(failed_items.is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_items_keep_their_error_until_renamed_again() {
+        let dir = test_dir("item_errors");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "taken.txt"),
+            item_with_rename(&dir, "b.txt", "b_new.txt"),
+        ];
+        items[1].error = Some("failed before".to_string());
+        fs::write(dir.join("taken.txt"), "taken.txt").unwrap();
+
+        let result = perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].0, dir.join("a.txt"));
+        assert!(items[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("appeared since the check"));
+        assert_eq!(items[1].error, None);
+
+        // The same name keeps the error, and a new one clears it
+        items[0].rename_to("taken.txt".to_string());
+        assert!(items[0].error.is_some());
+        items[0].rename_to("free.txt".to_string());
+        assert_eq!(items[0].error, None);
+
+        items[0].renamed = "taken.txt".to_string();
+        perform_renames(&mut items, &HashSet::new(), &HashSet::new(), false);
+        assert!(items[0].error.is_some());
+        items[0].reset();
+        assert_eq!(items[0].error, None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn engine_walks_directories_as_set() {
+        let dir = test_dir("engine_walk");
+        for name in ["a.txt", "b.txt", ".hidden"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        fs::write(dir.join(".ignore"), "b.txt\n").unwrap();
+        let paths = [dir.to_string_lossy().to_string()];
+        let names = |engine: &RenameEngine| -> Vec<String> {
+            engine
+                .items()
+                .iter()
+                .map(|item| item.display.clone())
+                .collect()
+        };
+
+        // Like the command line, neither is on unless asked for
+        let mut engine = RenameEngine::new("a", "b").unwrap();
+        engine.add_paths(&paths, true);
+        assert_eq!(names(&engine), ["a.txt", "b.txt"]);
+
+        let mut engine = RenameEngine::new("a", "b").unwrap();
+        engine.respect_gitignore = true;
+        engine.include_hidden = true;
+        engine.add_paths(&paths, true);
+        assert_eq!(names(&engine), [".hidden", ".ignore", "a.txt"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn engine_renames_and_undoes_a_directory() {
+        let dir = test_dir("engine");
+        fs::write(dir.join("1 - intro.md"), "intro").unwrap();
+        fs::write(dir.join("2 - outro.md"), "outro").unwrap();
+
+        let mut engine = RenameEngine::new(r"^(\d+) - ", "$1. ").unwrap();
+        let failures = engine.add_paths(&[dir.to_string_lossy().to_string()], true);
+        assert!(failures.is_empty());
+        assert_eq!(engine.update(), 0);
+        let mut renamed: Vec<_> = engine.items().iter().map(|item| &item.renamed).collect();
+        renamed.sort();
+        assert_eq!(renamed, ["1. intro.md", "2. outro.md"]);
+        assert!(engine.check().conflicting_names.is_empty());
+
+        let result = engine.perform();
+        assert_eq!(result.moved.len(), 2);
+        assert!(result.failures.is_empty());
+        assert_contents(&dir, &[("1. intro.md", "intro"), ("2. outro.md", "outro")]);
+        assert!(engine.items().iter().all(|item| item.file.exists()));
+
+        let undone = engine.undo(&result);
+        assert_eq!(undone.moved.len(), 2);
+        assert_contents(
+            &dir,
+            &[("1 - intro.md", "intro"), ("2 - outro.md", "outro")],
+        );
+        assert!(engine.items().iter().all(|item| item.file.exists()));
+        assert!(RenameEngine::new("(", "").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn conflicts_are_settled_by_the_policy() {
+        for policy in ConflictPolicy::ALL {
+            let dir = test_dir(&format!("policy_{:?}", policy));
+            fs::write(dir.join("keep.txt"), "keep").unwrap();
+            let mut items = vec![
+                item_with_rename(&dir, "a.txt", "x.txt"),
+                item_with_rename(&dir, "b.txt", "x.txt"),
+                item_with_rename(&dir, "c.txt", "keep.txt"),
+                item_with_rename(&dir, "d.txt", "e.txt"),
+                item_with_rename(&dir, "e.txt", "e.txt"),
+                item_with_rename(&dir, "f.txt", "g.txt"),
+            ];
+            let patterns = RenamePatterns {
+                conflict_policy: policy,
+                ..RenamePatterns::default()
+            };
+            let mut check = check_renames(&items, &patterns);
+            assert!(check.has_warnings());
+
+            let order = loaded_order(&items);
+            let skipped = settle_conflicts(&mut items, &order, &patterns, &mut check);
+            assert_eq!(check.has_warnings(), policy == ConflictPolicy::Ask);
+            if policy == ConflictPolicy::Ask {
+                assert!(skipped.is_empty());
+                fs::remove_dir_all(&dir).unwrap();
+                continue;
+            }
+
+            let existing = existing_targets(&items);
+            let result = perform_renames(&mut items, &skipped, &existing, false);
+            assert_contents(&dir, &[("g.txt", "f.txt")]);
+            match policy {
+                ConflictPolicy::Skip => {
+                    assert_eq!(skipped.len(), 3);
+                    assert_eq!(result.moved.len(), 1);
+                    assert_contents(
+                        &dir,
+                        &[
+                            ("a.txt", "a.txt"),
+                            ("b.txt", "b.txt"),
+                            ("keep.txt", "keep"),
+                            ("e.txt", "e.txt"),
+                        ],
+                    );
+                }
+                ConflictPolicy::Overwrite => {
+                    // The second file doesn't replace the first
+                    assert_eq!(result.failures.len(), 1);
+                    assert_contents(
+                        &dir,
+                        &[
+                            ("x.txt", "a.txt"),
+                            ("b.txt", "b.txt"),
+                            ("keep.txt", "c.txt"),
+                            ("e.txt", "d.txt"),
+                        ],
+                    );
+                }
+                ConflictPolicy::Suffix => {
+                    assert!(skipped.is_empty() && result.failures.is_empty());
+                    assert_contents(
+                        &dir,
+                        &[
+                            ("x.txt", "a.txt"),
+                            ("x (1).txt", "b.txt"),
+                            ("keep.txt", "keep"),
+                            ("keep (1).txt", "c.txt"),
+                            ("e.txt", "e.txt"),
+                            ("e (1).txt", "d.txt"),
+                        ],
+                    );
+                }
+                ConflictPolicy::Ask => unreachable!(),
+            }
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        // An existing target is overwritten once, not by every file renamed to it
+        let dir = test_dir("policy_overwrite_existing");
+        fs::write(dir.join("x.txt"), "x").unwrap();
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "x.txt"),
+            item_with_rename(&dir, "b.txt", "x.txt"),
+        ];
+        let patterns = RenamePatterns {
+            conflict_policy: ConflictPolicy::Overwrite,
+            ..RenamePatterns::default()
+        };
+        let mut check = check_renames(&items, &patterns);
+        let order = loaded_order(&items);
+        let skipped = settle_conflicts(&mut items, &order, &patterns, &mut check);
+        let existing = existing_targets(&items);
+        let result = perform_renames(&mut items, &skipped, &existing, false);
+        assert_eq!((result.moved.len(), result.failures.len()), (1, 1));
+        assert_contents(&dir, &[("x.txt", "a.txt"), ("b.txt", "b.txt")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reapplying_leaves_renamed_files_alone() {
+        let dir = test_dir("reapply");
+        fs::write(dir.join("1 - intro.md"), "intro").unwrap();
+        fs::write(dir.join("2 - outro.md"), "outro").unwrap();
+
+        let mut engine = RenameEngine::new(r"^(\d+) - ", "$1. ").unwrap();
+        engine.add_paths(&[dir.to_string_lossy().to_string()], true);
+        engine.update();
+        let first = engine.perform();
+        assert_eq!((first.moved.len(), first.in_place), (2, 0));
+
+        // The items still show their original names, so the same patterns give
+        // the names the files already have
+        engine.update();
+        assert!(engine.items().iter().all(|item| item.is_in_place()));
+        assert!(!engine.check().has_warnings());
+        let second = engine.perform();
+        assert_eq!(second.moved.len(), 0);
+        assert_eq!((second.in_place, second.skipped), (2, 0));
+        assert!(second.failures.is_empty());
+        assert_contents(&dir, &[("1. intro.md", "intro"), ("2. outro.md", "outro")]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
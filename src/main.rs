@@ -1,57 +1,83 @@
+use chrono::{DateTime, Local};
 use cursive::align::Align;
 use cursive::direction::Orientation;
-use cursive::theme::{BaseColor, Color, ColorStyle, Effect, Style};
+use cursive::event::{Event, Key};
+use cursive::theme::{BaseColor, Color, ColorStyle, Effect, Palette, PaletteColor, Style, Theme};
 use cursive::traits::{Boxable, Nameable};
-use cursive::views::{Dialog, EditView, LinearLayout, ScrollView, TextView, ViewRef};
+use cursive::utils::markup::StyledString;
+use cursive::views::{
+    Button, Checkbox, Dialog, DummyView, EditView, LinearLayout, ListView, NamedView, OnEventView,
+    ScrollView, SelectView, TextView, ViewRef,
+};
 use cursive::Cursive;
-use cursive_table_view::{TableView, TableViewItem};
-use regex::Regex;
-use std::collections::BTreeSet;
-use std::path::PathBuf;
+use cursive_table_view::{TableColumn, TableView, TableViewItem};
+use directories::ProjectDirs;
+use regex::{Regex, RegexBuilder};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tui_rename::{
+    apply_patterns, check_renames, create_target_directories, deduplicate_names, existing_targets,
+    failed_item, in_place_count, is_hidden, load_paths, mark_restored, perform_renames,
+    planned_items, planned_moves, read_manifest, record_errors, relocate_items, run_moves,
+    settle_conflicts, skipped_count, split_extension, undo_moves, write_manifest, CheckResult,
+    ConflictPolicy, RenameItem, RenamePatterns, RenameResult,
+};
+
+/// How long typing has to pause before the table is updated with the new patterns
+const UPDATE_DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// Applies with at least this many renames run in the background with a progress dialog
+const PROGRESS_THRESHOLD: usize = 200;
+
+/// Whether the table compares names naturally. `TableViewItem::cmp` can't see the
+/// settings, so they're mirrored here.
+static NATURAL_SORT: AtomicBool = AtomicBool::new(false);
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 enum RenameColumn {
+    /// Marks the items picked for "Apply to selection only"
+    Picked,
     Original,
     Renamed,
-}
-
-// TODO: Show more of the path if it needs it to be unique
-#[derive(Clone, Debug, PartialEq)]
-struct RenameItem {
-    original: String,
-    renamed: String,
-    file: PathBuf,
+    /// Marks the items the patterns change, which sort first
+    Changed,
+    /// The directory the file is in, shown when the settings ask for it
+    Directory,
+    /// The file's size, shown with Modified when the settings ask for them
+    Size,
+    Modified,
+    /// Why the file couldn't be renamed, shown while any files have failed
+    Status,
 }
 
 type RenameView = TableView<RenameItem, RenameColumn>;
 
-impl RenameItem {
-    fn new(path: PathBuf) -> Self {
-        let original = path.file_name().unwrap().to_string_lossy();
-        RenameItem {
-            original: original.to_string(),
-            renamed: original.to_string(),
-            file: path,
-        }
-    }
-
-    fn set_pattern(&mut self, find_pat: &Regex, replace_pat: &str) {
-        self.renamed = find_pat
-            .replace_all(&self.original, replace_pat)
-            .to_string();
-    }
-
-    fn rename(&self) {
-        let mut owned = self.file.to_owned();
-        owned.push(&self.renamed)
-    }
-}
-
 impl TableViewItem<RenameColumn> for RenameItem {
     fn to_column(&self, column: RenameColumn) -> String {
         match column {
-            RenameColumn::Original => self.original.clone(),
+            RenameColumn::Picked => if self.picked { "[x]" } else { "[ ]" }.to_string(),
+            RenameColumn::Original => self.display.clone(),
             RenameColumn::Renamed => self.renamed.clone(),
+            RenameColumn::Changed => if self.is_changed() { "*" } else { "" }.to_string(),
+            RenameColumn::Directory => self.directory().to_string_lossy().to_string(),
+            RenameColumn::Size => self.size.map_or("?".to_string(), format_size),
+            RenameColumn::Modified => self.modified.map_or("?".to_string(), |time| {
+                DateTime::<Local>::from(time)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            }),
+            RenameColumn::Status => self.error.clone().unwrap_or_default(),
         }
     }
 
@@ -60,308 +86,5304 @@ impl TableViewItem<RenameColumn> for RenameItem {
         Self: Sized,
     {
         match column {
-            RenameColumn::Original => self.original.cmp(&other.original),
-            RenameColumn::Renamed => self.renamed.cmp(&other.renamed),
+            RenameColumn::Picked => other.picked.cmp(&self.picked),
+            RenameColumn::Original => compare_names(&self.display, &other.display),
+            RenameColumn::Renamed => compare_names(&self.renamed, &other.renamed),
+            RenameColumn::Changed => other.is_changed().cmp(&self.is_changed()),
+            RenameColumn::Directory => self
+                .directory()
+                .cmp(other.directory())
+                .then_with(|| compare_names(&self.display, &other.display)),
+            RenameColumn::Size => self.size.cmp(&other.size),
+            RenameColumn::Modified => self.modified.cmp(&other.modified),
+            // Failed items sort first
+            RenameColumn::Status => other.error.is_some().cmp(&self.error.is_some()),
         }
     }
 }
 
-struct RenamePatterns {
-    // Not always synced with find_pat
-    find_pat_raw: String,
-    find_pat: Regex,
-    replace_pat: String,
+/// A size in bytes for the Size column, in binary units past 1024 bytes.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
 }
 
-fn main() {
-    cursive::logger::init();
-
-    // Creates the cursive root - required for every application.
-    let mut siv = cursive::default();
+fn compare_names(a: &str, b: &str) -> std::cmp::Ordering {
+    if NATURAL_SORT.load(Ordering::Relaxed) {
+        natural_cmp(a, b)
+    } else {
+        a.cmp(b)
+    }
+}
 
-    let mut table = RenameView::new()
-        .column(RenameColumn::Original, "Original", |c| c.width_percent(48))
-        .column(RenameColumn::Renamed, "Renamed", |c| c.width_percent(48));
+/// Compares runs of digits by their value and everything else as text, so `file2`
+/// comes before `file10`. Names that only differ in leading zeros fall back to
+/// comparing as text.
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let is_digit = |c: char| c.is_ascii_digit();
+    let (mut x, mut y) = (a, b);
+    loop {
+        let ordering = match (x.chars().next(), y.chars().next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(c), Some(d)) if is_digit(c) && is_digit(d) => {
+                let x_end = x.find(|c| !is_digit(c)).unwrap_or(x.len());
+                let y_end = y.find(|c| !is_digit(c)).unwrap_or(y.len());
+                let x_number = x[..x_end].trim_start_matches('0');
+                let y_number = y[..y_end].trim_start_matches('0');
+                x = &x[x_end..];
+                y = &y[y_end..];
+                x_number
+                    .len()
+                    .cmp(&y_number.len())
+                    .then_with(|| x_number.cmp(y_number))
+            }
+            (Some(c), Some(d)) => {
+                x = &x[c.len_utf8()..];
+                y = &y[d.len_utf8()..];
+                c.cmp(&d)
+            }
+        };
+        if ordering != std::cmp::Ordering::Equal {
+            return ordering;
+        }
+    }
+}
 
-    let mut items = Vec::new();
-    let mut failed_items = Vec::new();
+/// The state of the interface, around the patterns it edits.
+struct AppState {
+    patterns: RenamePatterns,
+    /// Show the Directory column, to tell apart files with the same name
+    show_directory: bool,
+    /// Show the Size and Modified columns
+    show_details: bool,
+    /// How much of the table's width the Original column takes, in percent, rather
+    /// than sharing what's left with the Renamed column. Read when the table is built.
+    original_column_width: Option<usize>,
+    /// How much of the table's width the Renamed column takes, in percent
+    renamed_column_width: Option<usize>,
+    /// Write a manifest of the renames before making them, for `--restore`
+    backup_manifest: bool,
+    /// Colour the changes in the selected file's new name, rather than marking them
+    color_diff: bool,
+    /// The colours the interface is drawn in
+    theme: ColorTheme,
+    /// Sort numbers in names by their value, rather than digit by digit
+    natural_sort: bool,
+    /// Ask about each rename in turn when applying, like `mv -i`
+    confirm_each: bool,
+    /// Add hidden files and directories when adding the files in a directory
+    include_hidden: bool,
+    /// Where to write the manifest, instead of a new file in the config directory
+    manifest_path: Option<PathBuf>,
+    /// Whether the table has the Directory column at the moment
+    showing_directory: bool,
+    /// Whether the table has the Size and Modified columns at the moment
+    showing_details: bool,
+    /// Whether the table has the Status column at the moment
+    showing_status: bool,
+    /// Whether rows have been moved by hand since the table was last sorted, so
+    /// `${n}` follows the rows rather than the sort order
+    manual_order: bool,
+    /// The files moved by each apply, most recent last
+    history: Vec<Vec<(PathBuf, PathBuf)>>,
+    /// Only log the renames an apply would make
+    dry_run: bool,
+    /// Ask before quitting with renames that haven't been applied
+    confirm_quit: bool,
+    /// Targets of the current apply that couldn't be cleared, so aren't renamed onto
+    skipped_targets: HashSet<PathBuf>,
+    /// Destinations that already existed when the renames were checked, so the
+    /// user was warned about overwriting them
+    existing_targets: HashSet<PathBuf>,
+    /// The saved presets, as they are on disk
+    presets: Presets,
+    /// How many items had time tokens that couldn't be read in the last update
+    unreadable_times: usize,
+    /// Only show the items whose Original contains this, or matches it as a regex
+    /// after a `/`
+    filter: String,
+    /// The items the filter hides, by their position among all the items. They're
+    /// still renamed, and are put back in the table before applying.
+    hidden_items: Vec<(usize, RenameItem)>,
+    /// When the table is next due to be updated, if an edit hasn't been applied yet
+    pending_update: Option<Instant>,
+    /// The warning dialogs the current apply is waiting on
+    warnings: WarningState,
+    /// The find or replace box a keybinding was last pressed in
+    focused_box: Option<&'static str>,
+    /// The directory the file browser was last in, to open it there again
+    browse_dir: Option<PathBuf>,
+    /// Set while `rename_in_background` is moving files, so nothing else moves or
+    /// quits under it
+    applying: bool,
+    /// The chained rules' find and replace patterns as they were typed, which
+    /// `rules` is compiled from with the current flags
+    rules_raw: Vec<(String, String)>,
+}
 
-    for filename in std::env::args().skip(1) {
-        let path = PathBuf::from(filename);
-        let string = path.to_string_lossy().to_string();
-        if path.is_file() {
-            items.push(RenameItem::new(path));
-        } else if !path.exists() {
-            failed_items.push(string);
-        } else {
-            log::debug!("Ignoring directory: {}", string);
+impl Default for AppState {
+    fn default() -> Self {
+        AppState {
+            patterns: RenamePatterns::default(),
+            show_directory: false,
+            show_details: false,
+            original_column_width: None,
+            renamed_column_width: None,
+            backup_manifest: false,
+            color_diff: true,
+            theme: ColorTheme::default(),
+            natural_sort: false,
+            confirm_each: false,
+            include_hidden: false,
+            manifest_path: None,
+            showing_directory: false,
+            showing_details: false,
+            showing_status: false,
+            manual_order: false,
+            history: Vec::new(),
+            dry_run: false,
+            confirm_quit: true,
+            skipped_targets: HashSet::new(),
+            existing_targets: HashSet::new(),
+            presets: Presets::new(),
+            unreadable_times: 0,
+            filter: String::new(),
+            hidden_items: Vec::new(),
+            rules_raw: Vec::new(),
+            browse_dir: None,
+            pending_update: None,
+            warnings: WarningState::default(),
+            applying: false,
+            focused_box: None,
         }
     }
+}
 
-    if items.is_empty() {
-        // EARLY RETURN
-        siv.add_layer(
-            Dialog::text("No files provided!")
-                .title("Error")
-                .button("Close", |s| s.quit()),
-        );
-        siv.run();
-        return;
+impl Deref for AppState {
+    type Target = RenamePatterns;
+
+    fn deref(&self) -> &RenamePatterns {
+        &self.patterns
     }
+}
 
-    table.set_items_stable(items);
+impl DerefMut for AppState {
+    fn deref_mut(&mut self) -> &mut RenamePatterns {
+        &mut self.patterns
+    }
+}
 
-    siv.set_user_data(RenamePatterns {
-        find_pat_raw: "".to_string(),
-        find_pat: Regex::new("").expect("Blank regex returns an error"),
-        replace_pat: "".to_string(),
-    });
+/// The patterns and settings kept between runs.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+    find_pattern: String,
+    replace_pattern: String,
+    case_insensitive: bool,
+    multi_line: bool,
+    dot_matches_new_line: bool,
+    literal: bool,
+    stem_only: bool,
+    counter_per_directory: bool,
+    counter_per_extension: bool,
+    counter_start: i64,
+    counter_step: i64,
+    trim_whitespace: bool,
+    collapse_spaces: bool,
+    show_directory: bool,
+    show_details: bool,
+    original_column_width: Option<usize>,
+    renamed_column_width: Option<usize>,
+    selection_only: bool,
+    full_path: bool,
+    create_directories: bool,
+    normalize_unicode: bool,
+    case_insensitive_conflicts: bool,
+    conflict_policy: ConflictPolicy,
+    lowercase_extension: bool,
+    uppercase_extension: bool,
+    lowercase_name: bool,
+    uppercase_name: bool,
+    copy_files: bool,
+    backup_manifest: bool,
+    // 0 turns the check off. `None` would be left out of the file, and read back
+    // as the platform's default.
+    max_path_length: usize,
+    max_name_length: Option<usize>,
+    truncate_with_ellipsis: bool,
+    color_diff: bool,
+    theme: ColorTheme,
+    occurrence: Option<usize>,
+    range_start: Option<usize>,
+    range_length: Option<usize>,
+    pad_numbers: Option<usize>,
+    friendly_tokens: bool,
+    natural_sort: bool,
+    ascii_fold: bool,
+    confirm_each: bool,
+    include_hidden: bool,
+    rules: Vec<(String, String)>,
+}
 
-    let mut error_style = Style::default();
-    // let error_red = BaseColor::Red;
+impl Default for Config {
+    fn default() -> Self {
+        Config::from(&AppState::default())
+    }
+}
 
-    error_style.color = ColorStyle::new(Color::Dark(BaseColor::Red), Color::Light(BaseColor::Blue));
-    error_style.effects.insert(Effect::Underline);
-    error_style.effects.insert(Effect::Bold);
+impl From<&AppState> for Config {
+    fn from(patterns: &AppState) -> Self {
+        Config {
+            find_pattern: patterns.find_pat_raw.clone(),
+            replace_pattern: patterns.replace_pat.clone(),
+            case_insensitive: patterns.case_insensitive,
+            multi_line: patterns.multi_line,
+            dot_matches_new_line: patterns.dot_matches_new_line,
+            literal: patterns.literal,
+            stem_only: patterns.stem_only,
+            counter_per_directory: patterns.counter_per_directory,
+            counter_per_extension: patterns.counter_per_extension,
+            counter_start: patterns.counter_start,
+            counter_step: patterns.counter_step,
+            trim_whitespace: patterns.trim_whitespace,
+            collapse_spaces: patterns.collapse_spaces,
+            show_directory: patterns.show_directory,
+            show_details: patterns.show_details,
+            original_column_width: patterns.original_column_width,
+            renamed_column_width: patterns.renamed_column_width,
+            selection_only: patterns.selection_only,
+            full_path: patterns.full_path,
+            create_directories: patterns.create_directories,
+            normalize_unicode: patterns.normalize_unicode,
+            case_insensitive_conflicts: patterns.case_insensitive_conflicts,
+            conflict_policy: patterns.conflict_policy,
+            lowercase_extension: patterns.lowercase_extension,
+            uppercase_extension: patterns.uppercase_extension,
+            lowercase_name: patterns.lowercase_name,
+            uppercase_name: patterns.uppercase_name,
+            copy_files: patterns.copy_files,
+            backup_manifest: patterns.backup_manifest,
+            max_path_length: patterns.max_path_length.unwrap_or(0),
+            max_name_length: patterns.max_name_length,
+            truncate_with_ellipsis: patterns.truncate_with_ellipsis,
+            color_diff: patterns.color_diff,
+            theme: patterns.theme,
+            occurrence: patterns.occurrence,
+            range_start: patterns.range_start,
+            range_length: patterns.range_length,
+            pad_numbers: patterns.pad_numbers,
+            friendly_tokens: patterns.friendly_tokens,
+            natural_sort: patterns.natural_sort,
+            ascii_fold: patterns.ascii_fold,
+            confirm_each: patterns.confirm_each,
+            include_hidden: patterns.include_hidden,
+            rules: patterns.rules_raw.clone(),
+        }
+    }
+}
 
-    let main_layout = LinearLayout::new(Orientation::Vertical)
-        .child(TextView::new("Find pattern:"))
-        .child(
-            EditView::new()
-                .on_edit(on_edit_find_pattern)
-                .on_submit(on_submit_find_pattern)
-                .with_name("find_pattern"),
-        )
-        .child(TextView::new("Replace pattern:"))
-        .child(
-            EditView::new()
-                .on_edit(on_edit_replace_pattern)
-                .with_name("replace_pattern"),
-        )
-        .child(
-            Dialog::around(table.with_name("file_table").min_size((50, 20)))
-                .title("Files")
-                .button("Cancel", |s| s.quit())
-                .button("Settings", show_settings_window)
-                .button("Apply", apply_renames),
-        )
-        .child(
-            TextView::new("")
-                .align(Align::bot_center())
-                .style(error_style)
-                .with_name("error_message"),
-        )
-        .full_screen();
+impl AppState {
+    /// Takes the patterns and settings from `config`. The find pattern still has
+    /// to be compiled.
+    fn apply_config(&mut self, config: &Config) {
+        self.find_pat_raw = config.find_pattern.clone();
+        self.replace_pat = config.replace_pattern.clone();
+        self.case_insensitive = config.case_insensitive;
+        self.multi_line = config.multi_line;
+        self.dot_matches_new_line = config.dot_matches_new_line;
+        self.literal = config.literal;
+        self.stem_only = config.stem_only;
+        self.counter_per_directory = config.counter_per_directory;
+        self.counter_per_extension = config.counter_per_extension;
+        self.counter_start = config.counter_start;
+        self.counter_step = config.counter_step;
+        self.trim_whitespace = config.trim_whitespace;
+        self.collapse_spaces = config.collapse_spaces;
+        self.show_directory = config.show_directory;
+        self.show_details = config.show_details;
+        self.original_column_width = config.original_column_width;
+        self.renamed_column_width = config.renamed_column_width;
+        self.selection_only = config.selection_only;
+        self.full_path = config.full_path;
+        self.create_directories = config.create_directories;
+        self.normalize_unicode = config.normalize_unicode;
+        self.case_insensitive_conflicts = config.case_insensitive_conflicts;
+        self.conflict_policy = config.conflict_policy;
+        self.lowercase_extension = config.lowercase_extension;
+        self.uppercase_extension = config.uppercase_extension;
+        self.lowercase_name = config.lowercase_name;
+        self.uppercase_name = config.uppercase_name;
+        self.copy_files = config.copy_files;
+        self.backup_manifest = config.backup_manifest;
+        self.max_path_length = Some(config.max_path_length).filter(|&n| n > 0);
+        self.max_name_length = config.max_name_length;
+        self.truncate_with_ellipsis = config.truncate_with_ellipsis;
+        self.color_diff = config.color_diff;
+        self.theme = config.theme;
+        self.occurrence = config.occurrence;
+        self.range_start = config.range_start;
+        self.range_length = config.range_length;
+        self.pad_numbers = config.pad_numbers;
+        self.friendly_tokens = config.friendly_tokens;
+        self.natural_sort = config.natural_sort;
+        self.ascii_fold = config.ascii_fold;
+        self.confirm_each = config.confirm_each;
+        self.include_hidden = config.include_hidden;
+        self.rules_raw = config.rules.clone();
+        NATURAL_SORT.store(config.natural_sort, Ordering::Relaxed);
+    }
 
-    siv.add_layer(main_layout);
+    /// Compiles the chained rules with the currently selected flags.
+    fn compile_rules(&self) -> Result<Vec<(Regex, String)>, regex::Error> {
+        self.rules_raw
+            .iter()
+            .map(|(find, replace)| Ok((self.compile_pattern(find)?, replace.clone())))
+            .collect()
+    }
+}
 
-    if !failed_items.is_empty() {
-        siv.add_layer(
-            Dialog::around(ScrollView::new(
-                LinearLayout::new(Orientation::Vertical).with_name("failed_items"),
-            ))
-            .title("Failed to access items: ")
-            .button("Close", |s| {
-                s.pop_layer();
-            }),
-        );
+/// The colour themes to pick from, by the name used for `--theme` and in the config.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ColorTheme {
+    /// Cursive's own theme: dark text on light views, over a blue background
+    #[default]
+    Light,
+    /// Light text on black
+    Dark,
+    /// Only the eight basic colours, over the terminal's own background
+    Basic,
+}
+
+impl ColorTheme {
+    const ALL: [ColorTheme; 3] = [ColorTheme::Light, ColorTheme::Dark, ColorTheme::Basic];
+
+    fn name(self) -> &'static str {
+        match self {
+            ColorTheme::Light => "light",
+            ColorTheme::Dark => "dark",
+            ColorTheme::Basic => "basic",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        ColorTheme::ALL
+            .into_iter()
+            .find(|theme| theme.name() == name)
+    }
+
+    /// The cursive theme, with the colour error messages are shown in as the
+    /// custom `error` colour.
+    fn theme(self) -> Theme {
+        use BaseColor::*;
+        use Color::{Dark, Light, TerminalDefault};
+        use PaletteColor::*;
 
-        siv.call_on_name("failed_items", |list: &mut LinearLayout| {
-            for item in failed_items {
-                list.add_child(TextView::new(item));
+        let mut theme = Theme::default();
+        let palette = &mut theme.palette;
+        match self {
+            ColorTheme::Light => palette.set_color("error", Dark(Red)),
+            ColorTheme::Dark => {
+                palette[Background] = Dark(Black);
+                palette[Shadow] = Dark(Black);
+                palette[View] = Dark(Black);
+                palette[Primary] = Light(White);
+                palette[Secondary] = Light(Cyan);
+                palette[Tertiary] = Dark(White);
+                palette[TitlePrimary] = Light(Yellow);
+                palette[TitleSecondary] = Light(Cyan);
+                palette[Highlight] = Dark(Blue);
+                palette[HighlightInactive] = Dark(White);
+                palette[HighlightText] = Light(White);
+                palette.set_color("error", Light(Red));
             }
-        });
+            ColorTheme::Basic => {
+                theme.shadow = false;
+                palette[Background] = TerminalDefault;
+                palette[Shadow] = TerminalDefault;
+                palette[View] = TerminalDefault;
+                palette[Primary] = TerminalDefault;
+                palette[Secondary] = Dark(Blue);
+                palette[Tertiary] = Dark(White);
+                palette[TitlePrimary] = Dark(Yellow);
+                palette[TitleSecondary] = Dark(Cyan);
+                palette[Highlight] = Dark(Blue);
+                palette[HighlightInactive] = Dark(White);
+                palette[HighlightText] = Dark(White);
+                palette.set_color("error", Dark(Red));
+            }
+        }
+        theme
     }
+}
 
-    siv.add_global_callback('q', |s| s.quit());
-    siv.add_global_callback('`', Cursive::toggle_debug_console);
-    // Starts the event loop.
-    siv.run();
+/// How error messages stand out under the theme with `palette`.
+fn error_style(palette: &Palette) -> Style {
+    let error = palette
+        .custom("error")
+        .copied()
+        .unwrap_or(Color::Dark(BaseColor::Red));
+    let mut style = Style::from(ColorStyle::new(error, PaletteColor::View));
+    style.effects.insert(Effect::Underline);
+    style.effects.insert(Effect::Bold);
+    style
 }
 
-fn show_settings_window(s: &mut Cursive) -> () {
-    s.add_layer(
-        Dialog::text("Settings not implemented")
-            .dismiss_button("Close")
-            .title("Settings"),
-    )
+/// Draws the interface in the colours of the chosen theme.
+fn apply_theme(s: &mut Cursive) {
+    let patterns: &AppState = s.user_data().unwrap();
+    let theme = patterns.theme.theme();
+    let style = error_style(&theme.palette);
+    s.set_theme(theme);
+    s.call_on_name("error_message", |v: &mut TextView| v.set_style(style));
 }
 
-fn on_edit_find_pattern(s: &mut Cursive, new_val: &str, _cursor: usize) {
-    let mut patterns: &mut RenamePatterns = s.user_data().unwrap();
-    patterns.find_pat_raw = new_val.to_string();
+/// Where the config file `name` is kept, in the platform's config directory.
+fn config_path(name: &str) -> Option<PathBuf> {
+    ProjectDirs::from("", "", "tui_rename").map(|dirs| dirs.config_dir().join(name))
+}
 
-    match Regex::new(new_val) {
-        Ok(re) => {
-            patterns.find_pat = re;
-            hide_error_message(s);
-            update_renames(s);
-        }
-        // Simply do not change
+/// Reads the config file `name`, falling back to `fallback` if it's missing or
+/// broken.
+fn load_config_file<T: DeserializeOwned>(name: &str, fallback: fn() -> T) -> T {
+    let path = match config_path(name) {
+        Some(path) => path,
+        None => return fallback(),
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
         Err(err) => {
-            let err = err.to_string();
-            let short_err = err.lines().last().unwrap();
-            set_error_message(s, short_err);
-            log::warn!("{}", short_err);
+            if err.kind() != io::ErrorKind::NotFound {
+                log::warn!("Could not read {}: {}", path.to_string_lossy(), err);
+            }
+            return fallback();
         }
+    };
+    toml::from_str(&contents).unwrap_or_else(|err| {
+        log::warn!(
+            "Ignoring invalid config {}: {}",
+            path.to_string_lossy(),
+            err
+        );
+        fallback()
+    })
+}
+
+fn save_config_file<T: Serialize>(name: &str, value: &T) -> io::Result<PathBuf> {
+    let path =
+        config_path(name).ok_or_else(|| io::Error::other("could not find a config directory"))?;
+    let contents = toml::to_string(value).map_err(io::Error::other)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
     }
+    fs::write(&path, contents)?;
+    Ok(path)
 }
 
-/// Errors if there is a problem in the regex.
-fn on_submit_find_pattern(s: &mut Cursive, new_val: &str) {
-    let mut patterns: &mut RenamePatterns = s.user_data().unwrap();
-    patterns.find_pat_raw = new_val.to_string();
+/// Reads the saved config, falling back to the defaults if it's missing or broken.
+fn load_config() -> Config {
+    load_config_file("config.toml", Config::default)
+}
 
-    match Regex::new(new_val) {
-        Ok(re) => {
-            patterns.find_pat = re;
-            update_renames(s);
-        }
-        Err(err) => {
-            s.add_layer(
-                Dialog::text(format!("{}", err))
-                    .title("Pattern Error")
-                    .button("Close", |s| {
-                        s.pop_layer();
-                    }),
-            );
-        }
+fn save_config(config: &Config) -> io::Result<PathBuf> {
+    save_config_file("config.toml", config)
+}
+
+/// Named sets of patterns and settings, which can be applied from the settings.
+type Presets = BTreeMap<String, Config>;
+
+/// Matches a leading counter such as `001_` or `12 - `, for the "Remove numbering"
+/// quick action.
+const REMOVE_NUMBERING_PATTERN: &str = r"^\d+[_\-. ]*";
+
+/// Matches each run of whitespace, for the "Spaces to underscores" quick action.
+const SPACES_PATTERN: &str = r"\s+";
+
+/// Matches each run of underscores, for the "Underscores to spaces" quick action.
+const UNDERSCORES_PATTERN: &str = "_+";
+
+/// The presets there are before any have been saved.
+fn default_presets() -> Presets {
+    let mut presets = Presets::new();
+    presets.insert(
+        "camera photos".to_string(),
+        Config {
+            find_pattern: r"^(IMG|DSC|DSCN|PXL)_?\d+".to_string(),
+            replace_pattern: "photo_${n:4}".to_string(),
+            ..Config::default()
+        },
+    );
+    presets.insert(
+        "lowercase everything".to_string(),
+        Config {
+            find_pattern: ".+".to_string(),
+            replace_pattern: r"\L$0".to_string(),
+            stem_only: false,
+            ..Config::default()
+        },
+    );
+    presets.insert(
+        "remove numbering".to_string(),
+        Config {
+            find_pattern: REMOVE_NUMBERING_PATTERN.to_string(),
+            ..Config::default()
+        },
+    );
+    presets.insert(
+        "spaces to underscores".to_string(),
+        Config {
+            find_pattern: SPACES_PATTERN.to_string(),
+            replace_pattern: "_".to_string(),
+            ..Config::default()
+        },
+    );
+    presets.insert(
+        "underscores to spaces".to_string(),
+        Config {
+            find_pattern: UNDERSCORES_PATTERN.to_string(),
+            replace_pattern: " ".to_string(),
+            ..Config::default()
+        },
+    );
+    presets
+}
+
+fn load_presets() -> Presets {
+    load_config_file("presets.toml", default_presets)
+}
+
+fn save_presets(presets: &Presets) -> io::Result<PathBuf> {
+    save_config_file("presets.toml", presets)
+}
+
+/// The settings `preset` switches `config` to. Presets are for patterns; the
+/// theme stays as it is.
+fn with_preset(config: &Config, preset: &Config) -> Config {
+    Config {
+        theme: config.theme,
+        ..preset.clone()
     }
 }
 
-fn on_edit_replace_pattern(s: &mut Cursive, new_val: &str, _cursor: usize) {
-    let mut patterns: &mut RenamePatterns = s.user_data().unwrap();
-    patterns.replace_pat = new_val.to_string();
-    update_renames(s);
+/// Options given on the command line.
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    /// Add the files inside directories, rather than ignoring them
+    recursive: bool,
+    /// A rename plan exported earlier, to take the new names from
+    import: Option<PathBuf>,
+    /// Show what would be renamed without touching any files
+    dry_run: bool,
+    /// Quit straight away, even with renames left to apply
+    no_confirm_quit: bool,
+    /// Copy the files to their new names instead of renaming them
+    copy: bool,
+    /// Where to write the backup manifest, which turns on writing it
+    manifest: Option<PathBuf>,
+    /// A backup manifest to rename everything back from, without opening the TUI
+    restore: Option<PathBuf>,
+    /// Undo the renames in the newest backup manifest, without opening the TUI
+    undo_last: bool,
+    /// The colour theme to use instead of the saved one
+    theme: Option<ColorTheme>,
+    /// Leave out files matched by `.gitignore` and `.ignore` files when recursing
+    respect_gitignore: bool,
+    /// Add hidden files when recursing, whatever the settings say
+    hidden: bool,
+    /// A file to write the log to, as well as the debug console
+    log_file: Option<PathBuf>,
+    /// The find pattern to start with instead of the saved one
+    find: Option<String>,
+    /// The replace pattern to start with instead of the saved one
+    replace: Option<String>,
+    /// A saved preset to start with instead of the saved patterns and settings
+    preset: Option<String>,
+    /// Print the planned renames as JSON instead of opening the TUI
+    json: bool,
+    /// Make the renames straight away instead of opening the TUI
+    apply: bool,
+    /// Apply even with renames the checks warn about
+    force: bool,
+    /// What to do about conflicting names instead of the saved policy
+    conflict_policy: Option<ConflictPolicy>,
+    paths: Vec<String>,
 }
 
-fn update_renames(s: &mut Cursive) {
-    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
-    let items = table.borrow_items_mut();
-    let patterns: &RenamePatterns = s.user_data().unwrap();
+impl Args {
+    /// Errors with a message for the user if an option is missing its value.
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut options_done = false;
 
-    for item in items {
-        item.set_pattern(&patterns.find_pat, &patterns.replace_pat);
+        while let Some(arg) = args.next() {
+            if options_done {
+                parsed.paths.push(arg);
+                continue;
+            }
+            match arg.as_str() {
+                "--" => options_done = true,
+                "-r" | "--recursive" => parsed.recursive = true,
+                "--dry-run" => parsed.dry_run = true,
+                "--no-confirm-quit" => parsed.no_confirm_quit = true,
+                "--copy" => parsed.copy = true,
+                "--respect-gitignore" => parsed.respect_gitignore = true,
+                "--hidden" => parsed.hidden = true,
+                "--json" => parsed.json = true,
+                "--apply" => parsed.apply = true,
+                "--force" => parsed.force = true,
+                "--undo-last" => parsed.undo_last = true,
+                "--find" => parsed.find = Some(option_value(&arg, &mut args)?),
+                "--replace" => parsed.replace = Some(option_value(&arg, &mut args)?),
+                "--preset" => parsed.preset = Some(option_value(&arg, &mut args)?),
+                "--manifest" => {
+                    parsed.manifest = Some(PathBuf::from(option_value(&arg, &mut args)?))
+                }
+                "--restore" => parsed.restore = Some(PathBuf::from(option_value(&arg, &mut args)?)),
+                "--import" => parsed.import = Some(PathBuf::from(option_value(&arg, &mut args)?)),
+                "--log-file" => {
+                    parsed.log_file = Some(PathBuf::from(option_value(&arg, &mut args)?))
+                }
+                "--theme" => {
+                    let name = option_value(&arg, &mut args)?;
+                    parsed.theme = Some(ColorTheme::from_name(&name).ok_or_else(|| {
+                        format!("unknown theme {} (expected light, dark or basic)", name)
+                    })?);
+                }
+                "--on-conflict" => {
+                    let name = option_value(&arg, &mut args)?;
+                    parsed.conflict_policy =
+                        Some(ConflictPolicy::from_name(&name).ok_or_else(|| {
+                            format!(
+                                "unknown conflict policy {} (expected ask, skip, overwrite or suffix)",
+                                name
+                            )
+                        })?);
+                }
+                _ => parsed.paths.push(arg),
+            }
+        }
+
+        Ok(parsed)
     }
 }
 
-struct CheckResult {
-    conflicting_names: Vec<String>,
-    permission_problems: Vec<String>,
+fn option_value(option: &str, args: &mut impl Iterator<Item = String>) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} requires a value", option))
 }
 
-fn check_renames(items: &[RenameItem]) -> CheckResult {
-    let mut unique_set = BTreeSet::<String>::new();
-    let mut conflicting_names = Vec::new();
+/// Loads the files named on the command line, or piped in after `-`, taking new
+/// names from the imported plan if there is one. Returns the items, the files that
+/// couldn't be loaded and the plan rows that matched no file.
+fn load_items(args: &Args, config: &Config) -> (Vec<RenameItem>, Vec<String>, Vec<String>) {
+    let mut items = Vec::new();
+    let mut failed_items = Vec::new();
+
+    let filenames = if args.paths == ["-"] {
+        read_paths(io::stdin().lock())
+    } else {
+        expand_response_files(&args.paths, &mut failed_items)
+    };
 
-    let renamed_items = items.iter().map(|it| it.renamed.clone());
-    for item in renamed_items {
-        if !unique_set.insert(item.clone()) {
-            // non unique
-            conflicting_names.push(item.clone());
+    load_paths(
+        &filenames,
+        args.recursive,
+        args.respect_gitignore,
+        args.hidden || config.include_hidden,
+        &mut items,
+        &mut failed_items,
+    );
+    // Shown paths are relative to where we were started, to tell apart files with
+    // the same name
+    if let Ok(base) = std::env::current_dir() {
+        for item in &mut items {
+            item.display_relative_to(&base);
         }
     }
 
-    let permission_problems = items
-        .iter()
-        .filter_map(|item| {
-            match item.file.metadata() {
-                Ok(meta) => meta.permissions().readonly(),
-                Err(_) => true,
-            }
-            .then(|| item.file.to_string_lossy().to_string())
-        })
-        .collect();
+    let mut unmatched_rows = Vec::new();
+    if let Some(import) = &args.import {
+        match read_plan(import) {
+            Ok(plan) => unmatched_rows = import_plan(&mut items, plan),
+            Err(err) => failed_items.push(failed_item(import, &err.to_string())),
+        }
+    }
+
+    (items, failed_items, unmatched_rows)
+}
+
+/// Reads one path per line, as piped in from tools like `find`. Surrounding
+/// whitespace is trimmed and empty lines are skipped.
+fn read_paths(reader: impl BufRead) -> Vec<String> {
+    reader
+        .lines()
+        .map_while(Result::ok)
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
 
-    CheckResult {
-        conflicting_names,
-        permission_problems,
+/// Replaces each `@listfile` argument with the paths listed in that file, one per
+/// line, for batches too big for the command line. Blank lines and lines starting
+/// with `#` are skipped. A file actually named with a leading `@` can be given as
+/// `./@name`.
+fn expand_response_files(paths: &[String], failed_items: &mut Vec<String>) -> Vec<String> {
+    let mut expanded = Vec::with_capacity(paths.len());
+    for path in paths {
+        let list = match path.strip_prefix('@') {
+            Some(list) if !list.is_empty() => Path::new(list),
+            _ => {
+                expanded.push(path.clone());
+                continue;
+            }
+        };
+        match fs::File::open(list) {
+            Ok(file) => expanded.extend(
+                read_paths(io::BufReader::new(file))
+                    .into_iter()
+                    .filter(|line| !line.starts_with('#')),
+            ),
+            Err(err) => failed_items.push(failed_item(list, &err.to_string())),
+        }
     }
+    expanded
 }
 
-fn apply_renames(s: &mut Cursive) {
-    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
-    let items = table.borrow_items();
-    let check_result = check_renames(items);
+/// Sends the records at or above `level` to the debug console, and to the log file
+/// if there is one. Other crates only get as far as `info`, so their debug
+/// output doesn't bury ours.
+struct AppLogger {
+    file: Option<Mutex<io::BufWriter<fs::File>>>,
+    level: log::LevelFilter,
+}
+
+impl log::Log for AppLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+            && (metadata.level() <= log::Level::Info || metadata.target().starts_with("tui_rename"))
+    }
 
-    let actual_length = items.len() - check_result.permission_problems.len();
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        cursive::logger::log(record);
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+            // There's nowhere left to report a failed log write
+            let _ = write_log_line(&mut *file, record);
+        }
+    }
 
-    let do_rename = move |s: &mut Cursive, items: &Vec<RenameItem>| {
-        for item in items {
-            item.rename();
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap_or_else(|err| err.into_inner()).flush();
         }
+    }
+}
+
+fn write_log_line(out: &mut impl Write, record: &log::Record<'_>) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} {:<5} {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        record.level(),
+        record.args()
+    )
+}
 
-        while s.pop_layer().is_some() {}
+/// The level for the debug console and log file from a `RUST_LOG`-style `spec`,
+/// such as `warn` or `tui_rename=debug`. Directives for other crates are ignored,
+/// and anything missing or unreadable falls back to `info`.
+fn log_level(spec: Option<&str>) -> log::LevelFilter {
+    spec.into_iter()
+        .flat_map(|spec| spec.split(','))
+        .filter_map(|directive| match directive.trim().split_once('=') {
+            Some(("tui_rename", level)) => Some(level),
+            Some(_) => None,
+            None => Some(directive.trim()),
+        })
+        .filter_map(|level| level.parse().ok())
+        .next_back()
+        .unwrap_or(log::LevelFilter::Info)
+}
 
-        s.add_layer(
-            Dialog::text(format!("Renamed {} files ", actual_length))
-                .button("Finish", |s| s.quit()),
-        );
+/// Installs the logger, writing to `log_file` as well if it's given. The file is
+/// flushed when a panic happens, so the lead-up to it isn't lost.
+fn init_logger(log_file: Option<&Path>) -> io::Result<()> {
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(io::BufWriter::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ))),
+        None => None,
     };
+    let level = log_level(std::env::var("RUST_LOG").ok().as_deref());
 
-    if check_result.conflicting_names.len() > 0 {
-        let names_message = format!(
-            "Files will be renamed to the same value:\n {}",
-            check_result.conflicting_names.join(",\n ")
+    cursive::logger::reserve_logs(1_000);
+    // Only fails if a logger is already set, which main never does twice
+    let _ = log::set_logger(Box::leak(Box::new(AppLogger { file, level })));
+    log::set_max_level(level);
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        log::error!("{}", info);
+        log::logger().flush();
+        default_hook(info);
+    }));
+    Ok(())
+}
+
+fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("tui_rename: {}", message);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(err) = init_logger(args.log_file.as_deref()) {
+        eprintln!(
+            "tui_rename: {}: {}",
+            args.log_file.unwrap_or_default().to_string_lossy(),
+            err
         );
+        std::process::exit(1);
+    }
 
-        let items_clone = items.to_owned();
+    if let Some(manifest) = &args.restore {
+        let status = restore_manifest(manifest);
+        log::logger().flush();
+        std::process::exit(status);
+    }
+    if args.undo_last {
+        let status = match config_path("backups").and_then(|dir| latest_manifest(&dir)) {
+            Some(manifest) => restore_manifest(&manifest),
+            None => {
+                eprintln!(
+                    "tui_rename: there are no backup manifests to undo. They're written when \
+                     \"Write backup manifest\" is turned on in the settings."
+                );
+                1
+            }
+        };
+        log::logger().flush();
+        std::process::exit(status);
+    }
 
-        let names_dialog = Dialog::text(names_message)
-            .title("Conflicting names Error")
-            .button("Cancel", |s| {
-                s.pop_layer();
-                s.call_on_name("perm_dialog", |v: &mut Dialog| {
-                    v.buttons_mut().nth(1).unwrap().disable()
-                });
-            })
-            .button("Continue", move |s| {
-                s.pop_layer();
-                // If the other warning has been dismissed, then do the operation
-                if s.find_name::<Dialog>("perm_dialog").is_none() {
-                    do_rename(s, &items_clone);
-                }
-            })
-            .with_name("names_dialog");
+    let mut config = load_config();
+    let presets = load_presets();
+    if let Some(name) = &args.preset {
+        match presets.get(name) {
+            Some(preset) => config = with_preset(&config, preset),
+            None => {
+                eprintln!("tui_rename: there's no preset called {}", name);
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some(find) = &args.find {
+        config.find_pattern = find.clone();
+    }
+    if let Some(replace) = &args.replace {
+        config.replace_pattern = replace.clone();
+    }
 
-        s.add_layer(names_dialog);
+    if args.json || args.apply {
+        let status = if args.json {
+            print_json_plan(&args, &config)
+        } else {
+            apply_without_ui(&args, &config)
+        };
+        log::logger().flush();
+        std::process::exit(status);
     }
 
-    if check_result.permission_problems.len() > 0 {
-        let perm_message = format!(
-            "Files cannot be renamed:\n {}",
-            check_result.permission_problems.join(",\n ")
-        );
+    // Creates the cursive root - required for every application.
+    let mut siv = cursive::default();
 
-        let items_clone = items.to_owned();
+    let mut table = RenameView::new()
+        .column(RenameColumn::Picked, "Sel", |c| c.width(8))
+        // Original and Renamed share the width the other columns don't take, unless
+        // they're given a width
+        .column(RenameColumn::Original, "Original", |c| {
+            column_width(c, config.original_column_width)
+        })
+        .column(RenameColumn::Renamed, "Renamed", |c| {
+            column_width(c, config.renamed_column_width)
+        })
+        .column(RenameColumn::Changed, "Changed", |c| c.width(12))
+        .default_column(RenameColumn::Original)
+        .on_submit(edit_renamed)
+        .on_select(|s, _row, _index| refresh_preview(s))
+        // The counter follows the sort order, so sorting renumbers
+        .on_sort(|s, _column, _order| {
+            s.with_user_data(|patterns: &mut AppState| patterns.manual_order = false);
+            update_renames(s)
+        });
 
-        let perm_dialog = Dialog::text(perm_message)
-            .title("Permissions Error")
-            .button("Cancel", |s| {
-                s.pop_layer();
-                s.call_on_name("names_dialog", |v: &mut Dialog| {
-                    v.buttons_mut().nth(1).unwrap().disable()
-                });
-            })
-            .button("Continue", move |s| {
-                s.pop_layer();
-                // If the other warning has been dismissed, then do the operation
-                if s.find_name::<Dialog>("names_dialog").is_none() {
-                    do_rename(s, &items_clone);
-                }
-            })
-            .with_name("perm_dialog");
+    let (items, failed_items, unmatched_rows) = load_items(&args, &config);
+    table.set_items_stable(items);
 
-        s.add_layer(perm_dialog);
+    let mut patterns = AppState {
+        dry_run: args.dry_run,
+        confirm_quit: !args.no_confirm_quit,
+        ..AppState::default()
+    };
+    patterns.apply_config(&config);
+    patterns.copy_files |= args.copy;
+    patterns.include_hidden |= args.hidden;
+    patterns.backup_manifest |= args.manifest.is_some();
+    patterns.manifest_path = args.manifest.clone();
+    patterns.theme = args.theme.unwrap_or(patterns.theme);
+    patterns.presets = presets;
+    if patterns.show_directory {
+        set_directory_column(&mut table, true);
+        patterns.showing_directory = true;
     }
-}
+    if patterns.show_details {
+        set_details_columns(&mut table, true, patterns.showing_directory);
+        patterns.showing_details = true;
+    }
+    siv.set_user_data(patterns);
 
-fn set_error_message(s: &mut Cursive, message: &str) {
-    s.call_on_name("error_message", |v: &mut TextView| {
-        v.set_content(message);
-    });
-}
+    let main_layout = LinearLayout::new(Orientation::Vertical)
+        .child(TextView::new("Find pattern:"))
+        .child(pattern_box_bindings(
+            OnEventView::new(
+                EditView::new()
+                    .content(config.find_pattern)
+                    .on_edit(on_edit_find_pattern)
+                    .on_submit(on_submit_find_pattern)
+                    .with_name("find_pattern"),
+            ),
+            "find_pattern",
+        ))
+        .child(TextView::new("Replace pattern:"))
+        .child(pattern_box_bindings(
+            OnEventView::new(
+                EditView::new()
+                    .content(config.replace_pattern)
+                    .on_edit(on_edit_replace_pattern)
+                    .on_submit(|s, _| update_renames(s))
+                    .with_name("replace_pattern"),
+            ),
+            "replace_pattern",
+        ))
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(TextView::new("Matches: "))
+                .child(TextView::new("").with_name("match_preview")),
+        )
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(TextView::new("Groups: "))
+                .child(TextView::new("").with_name("capture_groups")),
+        )
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(TextView::new("Changes: "))
+                .child(TextView::new("").with_name("rename_diff")),
+        )
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(TextView::new("Destination: "))
+                .child(TextView::new("").with_name("destination_preview")),
+        )
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(TextView::new("Filter: "))
+                .child(
+                    EditView::new()
+                        .on_edit(on_edit_filter)
+                        .with_name("filter")
+                        .full_width(),
+                ),
+        )
+        .child(
+            Dialog::around(table_bindings(OnEventView::new(
+                table.with_name("file_table").min_size((50, 20)),
+            )))
+            .title("Files")
+            .button("Cancel", request_quit)
+            .button("Add files", show_file_browser)
+            .button("Settings", show_settings_window)
+            .button("Undo", undo_last_apply)
+            .button("Export", show_export_window)
+            .button("Apply", apply_renames),
+        )
+        .child(TextView::new("").with_name("changed_count"))
+        .child(
+            TextView::new("")
+                .align(Align::bot_center())
+                .with_name("error_message"),
+        )
+        .child(TextView::new("").with_name("status_line"))
+        .full_screen();
 
-fn hide_error_message(s: &mut Cursive) {
-    set_error_message(s, "");
+    siv.add_layer(main_layout);
+    apply_theme(&mut siv);
+    if let Err(err) = refresh_find_pattern(&mut siv) {
+        set_error_message(&mut siv, &short_error(&err));
+        update_renames(&mut siv);
+    }
+    // Started without files, so they're picked from here
+    if siv
+        .call_on_name("file_table", |v: &mut RenameView| v.is_empty())
+        .unwrap()
+    {
+        show_file_browser(&mut siv);
+    }
+
+    if !unmatched_rows.is_empty() {
+        siv.add_layer(list_dialog(
+            "Imported files that weren't loaded: ",
+            unmatched_rows,
+        ));
+    }
+
+    if !failed_items.is_empty() {
+        siv.add_layer(list_dialog("Failed to access items: ", failed_items));
+    }
+
+    for binding in KEYBINDINGS {
+        for event in binding.events {
+            match binding.scope {
+                BindingScope::Global => siv.add_global_callback(event.clone(), binding.action),
+                BindingScope::MainScreen => {
+                    siv.add_global_callback(event.clone(), on_main_screen(binding.action))
+                }
+                BindingScope::Table | BindingScope::PatternBox => {}
+            }
+        }
+    }
+    // Starts the event loop.
+    siv.run();
+    log::logger().flush();
+}
+
+/// Wraps `callback` so it only runs when no dialog is open over the file table.
+fn on_main_screen(callback: fn(&mut Cursive)) -> impl Fn(&mut Cursive) {
+    move |s| {
+        if s.screen().len() == 1 {
+            callback(s);
+        }
+    }
+}
+
+/// Where a keybinding works.
+enum BindingScope {
+    /// Anywhere, unless the focused view uses the key itself
+    Global,
+    /// Only when no dialog is open over the file table
+    MainScreen,
+    /// Only when the file table is focused
+    Table,
+    /// Only when the find or replace box is focused
+    PatternBox,
+}
+
+struct Keybinding {
+    events: &'static [Event],
+    /// How the key is shown in the help
+    key: &'static str,
+    description: &'static str,
+    scope: BindingScope,
+    action: fn(&mut Cursive),
+}
+
+/// Every keybinding, which are registered and listed in the help from here. Keys
+/// typed into an edit view go to it instead, except for the function keys which
+/// mean nothing there.
+const KEYBINDINGS: &[Keybinding] = &[
+    Keybinding {
+        events: &[Event::Key(Key::F1), Event::Char('?')],
+        key: "F1, ?",
+        description: "Show this help",
+        scope: BindingScope::Global,
+        action: show_help,
+    },
+    Keybinding {
+        events: &[Event::Key(Key::F5)],
+        key: "F5",
+        description: "Apply the renames",
+        scope: BindingScope::MainScreen,
+        action: apply_renames,
+    },
+    Keybinding {
+        events: &[Event::Key(Key::F2)],
+        key: "F2",
+        description: "Open the settings",
+        scope: BindingScope::MainScreen,
+        action: show_settings_window,
+    },
+    Keybinding {
+        events: &[Event::CtrlChar('z')],
+        key: "Ctrl+Z",
+        description: "Undo the last apply",
+        scope: BindingScope::MainScreen,
+        action: undo_last_apply,
+    },
+    Keybinding {
+        events: &[Event::Char(' ')],
+        key: "Space",
+        description: "Pick or unpick the selected file",
+        scope: BindingScope::Table,
+        action: |s| pick_items(s, Pick::Toggle),
+    },
+    Keybinding {
+        events: &[Event::Char('a')],
+        key: "a",
+        description: "Pick every file",
+        scope: BindingScope::Table,
+        action: |s| pick_items(s, Pick::All),
+    },
+    Keybinding {
+        events: &[Event::Char('i')],
+        key: "i",
+        description: "Invert which files are picked",
+        scope: BindingScope::Table,
+        action: |s| pick_items(s, Pick::Invert),
+    },
+    Keybinding {
+        events: &[Event::Key(Key::Del)],
+        key: "Delete",
+        description: "Remove the selected file from the list",
+        scope: BindingScope::Table,
+        action: remove_selected_item,
+    },
+    Keybinding {
+        events: &[Event::Char('r')],
+        key: "r",
+        description: "Put back the selected file's original name",
+        scope: BindingScope::Table,
+        action: revert_selected_item,
+    },
+    Keybinding {
+        events: &[Event::Alt(Key::Up)],
+        key: "Alt+Up",
+        description: "Move the selected file up, numbering it earlier",
+        scope: BindingScope::Table,
+        action: |s| move_selected_item(s, -1),
+    },
+    Keybinding {
+        events: &[Event::Alt(Key::Down)],
+        key: "Alt+Down",
+        description: "Move the selected file down, numbering it later",
+        scope: BindingScope::Table,
+        action: |s| move_selected_item(s, 1),
+    },
+    Keybinding {
+        events: &[Event::CtrlChar('v')],
+        key: "Ctrl+V",
+        description: "Paste the clipboard into the pattern",
+        scope: BindingScope::PatternBox,
+        action: paste_clipboard,
+    },
+    Keybinding {
+        events: &[Event::Char('`')],
+        key: "`",
+        description: "Toggle the debug console",
+        scope: BindingScope::Global,
+        action: Cursive::toggle_debug_console,
+    },
+    Keybinding {
+        events: &[Event::Char('q')],
+        key: "q",
+        description: "Quit without renaming",
+        scope: BindingScope::Global,
+        action: request_quit,
+    },
+];
+
+/// Adds the keybindings that only work in the file table.
+fn table_bindings<V>(mut view: OnEventView<V>) -> OnEventView<V> {
+    for binding in KEYBINDINGS {
+        if let BindingScope::Table = binding.scope {
+            for event in binding.events {
+                view.set_on_event(event.clone(), binding.action);
+            }
+        }
+    }
+    view
+}
+
+/// Adds the keybindings that only work in the find or replace box called `name`.
+/// Their actions can tell which box it was from `focused_box`.
+fn pattern_box_bindings<V>(mut view: OnEventView<V>, name: &'static str) -> OnEventView<V> {
+    for binding in KEYBINDINGS {
+        if let BindingScope::PatternBox = binding.scope {
+            let action = binding.action;
+            for event in binding.events {
+                view.set_on_event(event.clone(), move |s| {
+                    s.with_user_data(|patterns: &mut AppState| patterns.focused_box = Some(name));
+                    action(s);
+                });
+            }
+        }
+    }
+    view
+}
+
+/// The lines of the help, one per keybinding.
+fn help_lines() -> Vec<String> {
+    let width = KEYBINDINGS.iter().map(|b| b.key.len()).max().unwrap_or(0);
+    KEYBINDINGS
+        .iter()
+        .map(|binding| {
+            let scope = match binding.scope {
+                BindingScope::Table => " (in the file table)",
+                BindingScope::PatternBox => " (in the find or replace box)",
+                _ => "",
+            };
+            format!("{:width$}  {}{}", binding.key, binding.description, scope)
+        })
+        .collect()
+}
+
+fn show_help(s: &mut Cursive) {
+    s.add_layer(
+        OnEventView::new(list_dialog("Keybindings", help_lines())).on_event(Key::Esc, |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// Opens a browser for adding files to the table, starting where it was last left.
+fn show_file_browser(s: &mut Cursive) {
+    let patterns: &AppState = s.user_data().unwrap();
+    let dir = patterns
+        .browse_dir
+        .clone()
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    let entries = SelectView::<PathBuf>::new().on_submit(|s, path: &PathBuf| {
+        if path.is_dir() {
+            browse_to(s, path);
+        } else {
+            add_files(s, vec![path.clone()]);
+        }
+    });
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::new(Orientation::Vertical)
+                .child(TextView::new("").with_name("browse_dir"))
+                .child(DummyView)
+                .child(ScrollView::new(entries.with_name("browse_entries")).min_size((50, 15)))
+                .child(DummyView)
+                .child(
+                    TextView::new("Choose a file to add it, or a directory to open it")
+                        .with_name("browse_status"),
+                ),
+        )
+        .title("Add Files")
+        .button("Add all here", |s| {
+            let patterns: &AppState = s.user_data().unwrap();
+            let dir = patterns.browse_dir.clone().unwrap_or_default();
+            let include_hidden = patterns.include_hidden;
+            let files = browse_entries(&dir)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(_, path)| path)
+                .filter(|path| path.is_file() && (include_hidden || !is_hidden(path)))
+                .collect();
+            add_files(s, files);
+        })
+        .button("Close", |s| {
+            s.pop_layer();
+        }),
+    );
+    browse_to(s, &dir);
+}
+
+/// Shows the contents of `dir` in the file browser.
+fn browse_to(s: &mut Cursive, dir: &Path) {
+    let entries = match browse_entries(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            let message = format!("Couldn't open {}: {}", dir.to_string_lossy(), err);
+            s.call_on_name("browse_status", |v: &mut TextView| v.set_content(message));
+            return;
+        }
+    };
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+
+    s.call_on_name("browse_entries", |v: &mut SelectView<PathBuf>| {
+        v.clear();
+        if let Some(parent) = dir.parent() {
+            v.add_item("../", parent.to_path_buf());
+        }
+        for (label, path) in entries {
+            v.add_item(label, path);
+        }
+    });
+    s.call_on_name("browse_dir", |v: &mut TextView| {
+        v.set_content(dir.to_string_lossy())
+    });
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.browse_dir = Some(dir);
+}
+
+/// The directories in `dir` followed by its files, each in name order. Directories
+/// are labelled with a trailing `/`.
+fn browse_entries(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut directories = Vec::new();
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = entry.path();
+        if path.is_dir() {
+            directories.push((format!("{}/", name), path));
+        } else {
+            files.push((name, path));
+        }
+    }
+    directories.sort_by(|(a, _), (b, _)| compare_names(a, b));
+    files.sort_by(|(a, _), (b, _)| compare_names(a, b));
+    directories.extend(files);
+    Ok(directories)
+}
+
+/// Adds `paths` to the table with the patterns applied, leaving out any that are
+/// already in it.
+fn add_files(s: &mut Cursive, paths: Vec<PathBuf>) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &AppState = s.user_data().unwrap();
+    let known = table
+        .borrow_items()
+        .iter()
+        .chain(patterns.hidden_items.iter().map(|(_, item)| item));
+    let (added, duplicates) = new_items(known, paths);
+
+    let count = added.len();
+    let base = std::env::current_dir().ok();
+    for mut item in added {
+        if let Some(base) = &base {
+            item.display_relative_to(base);
+        }
+        table.insert_item(item);
+    }
+    drop(table);
+    update_renames(s);
+
+    let mut message = format!("Added {} files", count);
+    if duplicates > 0 {
+        message.push_str(&format!(", {} were already in the list", duplicates));
+    }
+    s.call_on_name("browse_status", |v: &mut TextView| v.set_content(message));
+}
+
+/// Items for the files in `paths` that aren't one of the `known` items or added
+/// twice, along with how many were left out for that.
+fn new_items<'a>(
+    known: impl Iterator<Item = &'a RenameItem>,
+    paths: Vec<PathBuf>,
+) -> (Vec<RenameItem>, usize) {
+    let identity = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut seen: HashSet<PathBuf> = known.map(|item| identity(&item.file)).collect();
+    let mut duplicates = 0;
+    let mut items = Vec::new();
+    for path in paths {
+        if !seen.insert(identity(&path)) {
+            duplicates += 1;
+            continue;
+        }
+        items.extend(RenameItem::new(path));
+    }
+    (items, duplicates)
+}
+
+/// A scrollable dialog showing one line per entry.
+fn list_dialog(title: &str, lines: Vec<String>) -> Dialog {
+    let mut list = LinearLayout::new(Orientation::Vertical);
+    for line in lines {
+        list.add_child(TextView::new(line));
+    }
+
+    Dialog::around(ScrollView::new(list))
+        .title(title)
+        .button("Close", |s| {
+            s.pop_layer();
+        })
+}
+
+/// Quotes a CSV field if it contains anything that would break the row apart.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Splits CSV text into rows of fields, handling quoted fields.
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => row.push(std::mem::take(&mut field)),
+            '\n' if !in_quotes => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            '\r' if !in_quotes && chars.peek() == Some(&'\n') => {}
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    rows
+}
+
+const PLAN_HEADER: [&str; 2] = ["original", "renamed"];
+
+/// Writes each item's original path and new name as a two-column CSV.
+fn write_plan(path: &Path, items: &[RenameItem]) -> io::Result<()> {
+    let mut csv = format!("{}\n", PLAN_HEADER.join(","));
+    for item in items {
+        csv.push_str(&format!(
+            "{},{}\n",
+            csv_field(&item.file.to_string_lossy()),
+            csv_field(&item.renamed)
+        ));
+    }
+    fs::write(path, csv)
+}
+
+/// Reads `(original path, renamed)` pairs written by `write_plan`.
+fn read_plan(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let text = fs::read_to_string(path)?;
+    let mut plan = Vec::new();
+
+    for (line, row) in parse_csv(&text).into_iter().enumerate() {
+        if line == 0 && row == PLAN_HEADER {
+            continue;
+        }
+        match <[String; 2]>::try_from(row) {
+            Ok([original, renamed]) => plan.push((original, renamed)),
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("row {} doesn't have two columns", line + 1),
+                ))
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Takes the new names from an imported plan, keeping them as manual edits so
+/// the patterns don't replace them. Returns the original paths that weren't
+/// among the items.
+fn import_plan(items: &mut [RenameItem], plan: Vec<(String, String)>) -> Vec<String> {
+    let mut by_path: HashMap<String, &mut RenameItem> = items
+        .iter_mut()
+        .map(|item| (item.file.to_string_lossy().to_string(), item))
+        .collect();
+    let mut unmatched = Vec::new();
+
+    for (original, renamed) in plan {
+        match by_path.get_mut(&original) {
+            Some(item) => {
+                item.renamed = renamed;
+                item.manually_edited = true;
+            }
+            None => unmatched.push(original),
+        }
+    }
+
+    unmatched
+}
+
+fn show_export_window(s: &mut Cursive) {
+    s.add_layer(
+        Dialog::around(
+            EditView::new()
+                .content("rename_plan.csv")
+                .on_submit(export_plan)
+                .with_name("export_path")
+                .min_width(40),
+        )
+        .title("Export to CSV")
+        .dismiss_button("Cancel")
+        .button("Export", |s| {
+            let path = s
+                .call_on_name("export_path", |v: &mut EditView| v.get_content())
+                .unwrap();
+            export_plan(s, &path);
+        }),
+    );
+}
+
+fn export_plan(s: &mut Cursive, path: &str) {
+    s.pop_layer();
+    flush_pending_update(s);
+    clear_filter(s);
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let items = table.borrow_items();
+    let result = write_plan(Path::new(path), items);
+    let count = items.len();
+    drop(table);
+
+    let message = match result {
+        Ok(()) => format!("Exported {} files to {}", count, path),
+        Err(err) => format!("Could not write {}: {}", path, err),
+    };
+    s.add_layer(
+        Dialog::text(message)
+            .title("Export")
+            .dismiss_button("Close"),
+    );
+}
+
+fn show_settings_window(s: &mut Cursive) {
+    let patterns: &AppState = s.user_data().unwrap();
+
+    let mut preset_list = SelectView::<String>::new().popup();
+    for name in patterns.presets.keys() {
+        preset_list.add_item(name.clone(), name.clone());
+    }
+    let presets = LinearLayout::new(Orientation::Vertical)
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(TextView::new("Preset: "))
+                .child(preset_list.with_name("preset_list"))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Apply", apply_preset))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Delete", delete_preset)),
+        )
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(EditView::new().with_name("preset_name").min_width(20))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Save preset", save_preset)),
+        )
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(TextView::new("Quick actions: "))
+                .child(Button::new("Remove numbering", remove_numbering))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Spaces to _", spaces_to_underscores))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("_ to spaces", underscores_to_spaces))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Regex help", show_regex_help)),
+        )
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(DummyView.fixed_width("Quick actions: ".len()))
+                .child(Button::new("Lowercase all", |s| change_name_case(s, true)))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Uppercase all", |s| change_name_case(s, false))),
+        );
+
+    let mut theme_list = SelectView::new().popup();
+    for theme in ColorTheme::ALL {
+        theme_list.add_item(theme.name(), theme);
+    }
+    theme_list.set_selection(
+        ColorTheme::ALL
+            .iter()
+            .position(|&t| t == patterns.theme)
+            .unwrap(),
+    );
+
+    let mut policy_list = SelectView::new().popup();
+    for policy in ConflictPolicy::ALL {
+        policy_list.add_item(policy.label(), policy);
+    }
+    policy_list.set_selection(
+        ConflictPolicy::ALL
+            .iter()
+            .position(|&p| p == patterns.conflict_policy)
+            .unwrap(),
+    );
+
+    let settings = ListView::new()
+        .child(
+            "Case insensitive",
+            Checkbox::new()
+                .with_checked(patterns.case_insensitive)
+                .with_name("case_insensitive"),
+        )
+        .child(
+            "Multiline mode",
+            Checkbox::new()
+                .with_checked(patterns.multi_line)
+                .with_name("multi_line"),
+        )
+        .child(
+            "Dot matches newline",
+            Checkbox::new()
+                .with_checked(patterns.dot_matches_new_line)
+                .with_name("dot_matches_new_line"),
+        )
+        .child(
+            "Literal (no regex)",
+            Checkbox::new()
+                .with_checked(patterns.literal)
+                .with_name("literal"),
+        )
+        .child(
+            "Friendly tokens ({name})",
+            Checkbox::new()
+                .with_checked(patterns.friendly_tokens)
+                .with_name("friendly_tokens"),
+        )
+        .child(
+            "Replace first only",
+            Checkbox::new()
+                .with_checked(patterns.occurrence == Some(1))
+                .with_name("replace_first"),
+        )
+        .child(
+            "Replace occurrence N",
+            EditView::new()
+                .content(match patterns.occurrence {
+                    Some(n) if n > 1 => n.to_string(),
+                    _ => String::new(),
+                })
+                .with_name("occurrence")
+                .fixed_width(6),
+        )
+        .child(
+            "Range start",
+            EditView::new()
+                .content(
+                    patterns
+                        .range_start
+                        .map(|n| n.to_string())
+                        .unwrap_or_default(),
+                )
+                .with_name("range_start")
+                .fixed_width(6),
+        )
+        .child(
+            "Range length",
+            EditView::new()
+                .content(
+                    patterns
+                        .range_length
+                        .map(|n| n.to_string())
+                        .unwrap_or_default(),
+                )
+                .with_name("range_length")
+                .fixed_width(6),
+        )
+        .child(
+            "Pad numbers to width",
+            EditView::new()
+                .content(
+                    patterns
+                        .pad_numbers
+                        .map(|n| n.to_string())
+                        .unwrap_or_default(),
+                )
+                .with_name("pad_numbers")
+                .fixed_width(6),
+        )
+        .child(
+            "Max name length",
+            EditView::new()
+                .content(
+                    patterns
+                        .max_name_length
+                        .map(|n| n.to_string())
+                        .unwrap_or_default(),
+                )
+                .with_name("max_name_length")
+                .fixed_width(6),
+        )
+        .child(
+            "Mark cut names with …",
+            Checkbox::new()
+                .with_checked(patterns.truncate_with_ellipsis)
+                .with_name("truncate_with_ellipsis"),
+        )
+        .child(
+            "Apply to stem only",
+            Checkbox::new()
+                .with_checked(patterns.stem_only)
+                .with_name("stem_only"),
+        )
+        .child(
+            "Counter per directory",
+            Checkbox::new()
+                .with_checked(patterns.counter_per_directory)
+                .with_name("counter_per_directory"),
+        )
+        .child(
+            "Counter per extension",
+            Checkbox::new()
+                .with_checked(patterns.counter_per_extension)
+                .with_name("counter_per_extension"),
+        )
+        .child(
+            "Counter start",
+            EditView::new()
+                .content(patterns.counter_start.to_string())
+                .with_name("counter_start")
+                .fixed_width(8),
+        )
+        .child(
+            "Counter step",
+            EditView::new()
+                .content(patterns.counter_step.to_string())
+                .with_name("counter_step")
+                .fixed_width(8),
+        )
+        .child(
+            "Trim whitespace",
+            Checkbox::new()
+                .with_checked(patterns.trim_whitespace)
+                .with_name("trim_whitespace"),
+        )
+        .child(
+            "Collapse spaces",
+            Checkbox::new()
+                .with_checked(patterns.collapse_spaces)
+                .with_name("collapse_spaces"),
+        )
+        .child(
+            "Show directory",
+            Checkbox::new()
+                .with_checked(patterns.show_directory)
+                .with_name("show_directory"),
+        )
+        .child(
+            "Show size and modified",
+            Checkbox::new()
+                .with_checked(patterns.show_details)
+                .with_name("show_details"),
+        )
+        .child(
+            "Original width % (restart)",
+            EditView::new()
+                .content(
+                    patterns
+                        .original_column_width
+                        .map(|n| n.to_string())
+                        .unwrap_or_default(),
+                )
+                .with_name("original_column_width")
+                .fixed_width(6),
+        )
+        .child(
+            "Renamed width % (restart)",
+            EditView::new()
+                .content(
+                    patterns
+                        .renamed_column_width
+                        .map(|n| n.to_string())
+                        .unwrap_or_default(),
+                )
+                .with_name("renamed_column_width")
+                .fixed_width(6),
+        )
+        .child(
+            "Include hidden files",
+            Checkbox::new()
+                .with_checked(patterns.include_hidden)
+                .with_name("include_hidden"),
+        )
+        .child(
+            "Natural sort",
+            Checkbox::new()
+                .with_checked(patterns.natural_sort)
+                .with_name("natural_sort"),
+        )
+        .child(
+            "Apply to selection only",
+            Checkbox::new()
+                .with_checked(patterns.selection_only)
+                .with_name("selection_only"),
+        )
+        .child(
+            "Match full path",
+            Checkbox::new()
+                .with_checked(patterns.full_path)
+                .with_name("full_path"),
+        )
+        .child(
+            "Create directories",
+            Checkbox::new()
+                .with_checked(patterns.create_directories)
+                .with_name("create_directories"),
+        )
+        .child(
+            "Normalize Unicode (NFC)",
+            Checkbox::new()
+                .with_checked(patterns.normalize_unicode)
+                .with_name("normalize_unicode"),
+        )
+        .child(
+            "Case-insensitive conflicts",
+            Checkbox::new()
+                .with_checked(patterns.case_insensitive_conflicts)
+                .with_name("case_insensitive_conflicts"),
+        )
+        .child(
+            "When names conflict",
+            policy_list.with_name("conflict_policy"),
+        )
+        .child(
+            "ASCII-fold",
+            Checkbox::new()
+                .with_checked(patterns.ascii_fold)
+                .with_name("ascii_fold"),
+        )
+        .child(
+            "Lowercase extension",
+            Checkbox::new()
+                .with_checked(patterns.lowercase_extension)
+                .on_change(|s, checked| uncheck_if(s, checked, "uppercase_extension"))
+                .with_name("lowercase_extension"),
+        )
+        .child(
+            "Uppercase extension",
+            Checkbox::new()
+                .with_checked(patterns.uppercase_extension)
+                .on_change(|s, checked| uncheck_if(s, checked, "lowercase_extension"))
+                .with_name("uppercase_extension"),
+        )
+        .child(
+            "Lowercase whole name",
+            Checkbox::new()
+                .with_checked(patterns.lowercase_name)
+                .on_change(|s, checked| uncheck_if(s, checked, "uppercase_name"))
+                .with_name("lowercase_name"),
+        )
+        .child(
+            "Uppercase whole name",
+            Checkbox::new()
+                .with_checked(patterns.uppercase_name)
+                .on_change(|s, checked| uncheck_if(s, checked, "lowercase_name"))
+                .with_name("uppercase_name"),
+        )
+        .child(
+            "Copy instead of renaming",
+            Checkbox::new()
+                .with_checked(patterns.copy_files)
+                .with_name("copy_files"),
+        )
+        .child(
+            "Confirm each rename",
+            Checkbox::new()
+                .with_checked(patterns.confirm_each)
+                .with_name("confirm_each"),
+        )
+        .child(
+            "Write backup manifest",
+            Checkbox::new()
+                .with_checked(patterns.backup_manifest)
+                .with_name("backup_manifest"),
+        )
+        .child(
+            "Colour changes",
+            Checkbox::new()
+                .with_checked(patterns.color_diff)
+                .with_name("color_diff"),
+        )
+        .child("Theme", theme_list.with_name("theme"));
+
+    let mut rules_list = SelectView::new();
+    for (find, replace) in &patterns.rules_raw {
+        rules_list.add_item(rule_label(find, replace), (find.clone(), replace.clone()));
+    }
+
+    let rules = LinearLayout::new(Orientation::Vertical)
+        .child(TextView::new(
+            "Chained rules, applied after the find pattern:",
+        ))
+        .child(rules_list.with_name("rules_list").min_height(3))
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(EditView::new().with_name("rule_find").min_width(20))
+                .child(TextView::new(" -> "))
+                .child(EditView::new().with_name("rule_replace").min_width(20)),
+        )
+        .child(
+            LinearLayout::new(Orientation::Horizontal)
+                .child(Button::new("Add", add_rule))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Remove", |s| {
+                    s.call_on_name("rules_list", |v: &mut RulesList| {
+                        if let Some(id) = v.selected_id() {
+                            v.remove_item(id);
+                        }
+                    });
+                }))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Up", |s| move_rule(s, -1)))
+                .child(DummyView.fixed_width(1))
+                .child(Button::new("Down", |s| move_rule(s, 1))),
+        );
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::new(Orientation::Vertical)
+                .child(presets)
+                .child(DummyView)
+                .child(settings)
+                .child(DummyView)
+                .child(rules),
+        )
+        .title("Settings")
+        .dismiss_button("Cancel")
+        .button("Save defaults", |s| {
+            apply_settings(s);
+            save_defaults(s);
+        })
+        .button("Confirm", apply_settings),
+    )
+}
+
+fn selected_preset(s: &mut Cursive) -> Option<String> {
+    s.call_on_name("preset_list", |v: &mut SelectView<String>| v.selection())
+        .flatten()
+        .map(|name| name.to_string())
+}
+
+/// Replaces the patterns and settings with the selected preset, closing the
+/// settings window.
+fn apply_preset(s: &mut Cursive) {
+    let name = match selected_preset(s) {
+        Some(name) => name,
+        None => return,
+    };
+    s.pop_layer();
+
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let config = with_preset(&Config::from(&*patterns), &patterns.presets[&name]);
+    patterns.apply_config(&config);
+    refresh_optional_columns(s);
+    s.call_on_name("find_pattern", |v: &mut EditView| {
+        v.set_content(config.find_pattern)
+    });
+    s.call_on_name("replace_pattern", |v: &mut EditView| {
+        v.set_content(config.replace_pattern)
+    });
+
+    match refresh_find_pattern(s) {
+        Ok(()) => refresh_reference_warning(s),
+        Err(err) => {
+            set_error_message(s, &short_error(&err));
+            update_renames(s);
+        }
+    }
+}
+
+/// Sets the patterns to strip leading counters, keeping the other settings.
+fn remove_numbering(s: &mut Cursive) {
+    set_quick_patterns(s, REMOVE_NUMBERING_PATTERN, "");
+}
+
+/// Sets the patterns to turn whitespace into underscores.
+fn spaces_to_underscores(s: &mut Cursive) {
+    set_quick_patterns(s, SPACES_PATTERN, "_");
+}
+
+/// Sets the patterns to turn underscores into spaces.
+fn underscores_to_spaces(s: &mut Cursive) {
+    set_quick_patterns(s, UNDERSCORES_PATTERN, " ");
+}
+
+/// Clears the patterns so new names are the originals, lowercased or uppercased
+/// as a whole.
+fn change_name_case(s: &mut Cursive, lowercase: bool) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.lowercase_name = lowercase;
+    patterns.uppercase_name = !lowercase;
+    set_quick_patterns(s, "", "");
+}
+
+/// Closes the settings and puts `find`, a regex, and `replace` in the pattern boxes
+/// for a quick action, keeping the other settings.
+fn set_quick_patterns(s: &mut Cursive, find: &str, replace: &str) {
+    s.pop_layer();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.find_pat_raw = find.to_string();
+    patterns.replace_pat = replace.to_string();
+    // The pattern is a regex
+    patterns.literal = false;
+    s.call_on_name("find_pattern", |v: &mut EditView| v.set_content(find));
+    s.call_on_name("replace_pattern", |v: &mut EditView| v.set_content(replace));
+
+    match refresh_find_pattern(s) {
+        Ok(()) => refresh_reference_warning(s),
+        Err(err) => set_error_message(s, &short_error(&err)),
+    }
+}
+
+/// Common regex pieces for the regex help, with what they match.
+const REGEX_SNIPPETS: &[(&str, &str)] = &[
+    (r"\d", "a digit"),
+    (r"\d+", "one or more digits"),
+    (r"\d{2,4}", "two to four digits"),
+    (r"\w+", "a word: letters, digits and underscores"),
+    (r"\s", "a space or tab"),
+    (".", "any character"),
+    (".*", "anything, including nothing"),
+    (r"\.", "a dot, rather than any character"),
+    ("[abc]", "one of a, b or c"),
+    ("[^abc]", "any character but a, b or c"),
+    ("cat|dog", "cat or dog"),
+    ("^", "the start of the name"),
+    ("$", "the end of the name"),
+    (r"(\d+)", "a group, put back with $1"),
+    (
+        r"(?P<year>\d{4})",
+        "a group named year, put back with ${year}",
+    ),
+    ("(?i)", "ignore case in the rest of the pattern"),
+];
+
+/// Lists `REGEX_SNIPPETS`, adding the chosen ones to the end of the find pattern.
+fn show_regex_help(s: &mut Cursive) {
+    let mut snippets = SelectView::new();
+    for (snippet, description) in REGEX_SNIPPETS {
+        snippets.add_item(format!("{:<18} {}", snippet, description), *snippet);
+    }
+    snippets.set_on_submit(|s, snippet: &&str| {
+        let mut pattern = s
+            .call_on_name("find_pattern", |v: &mut EditView| v.get_content())
+            .unwrap()
+            .to_string();
+        pattern.push_str(snippet);
+        s.call_on_name("find_pattern", |v: &mut EditView| v.set_content(&pattern));
+        on_edit_find_pattern(s, &pattern, pattern.len());
+        refresh_regex_help(s);
+    });
+
+    let mut note = String::from("Choose a piece to add it to the find pattern.");
+    if is_checked(s, "literal") {
+        note.push_str("\nLiteral mode is on, so these only work once it's turned off.");
+    }
+
+    s.add_layer(
+        Dialog::around(
+            LinearLayout::new(Orientation::Vertical)
+                .child(TextView::new(note))
+                .child(DummyView)
+                .child(ScrollView::new(snippets))
+                .child(DummyView)
+                .child(TextView::new("").with_name("regex_help_pattern")),
+        )
+        .title("Regex Help")
+        .dismiss_button("Close"),
+    );
+    refresh_regex_help(s);
+}
+
+/// Shows the find pattern in the regex help, and what's wrong with it if it
+/// doesn't compile.
+fn refresh_regex_help(s: &mut Cursive) {
+    let patterns: &AppState = s.user_data().unwrap();
+    let mut text = format!("Find pattern: {}", patterns.find_pat_raw);
+    if let Err(err) = patterns.compile() {
+        text.push_str(&format!("\n{}", short_error(&err)));
+    }
+    s.call_on_name("regex_help_pattern", |v: &mut TextView| v.set_content(text));
+}
+
+fn delete_preset(s: &mut Cursive) {
+    let name = match selected_preset(s) {
+        Some(name) => name,
+        None => return,
+    };
+    s.call_on_name("preset_list", |v: &mut SelectView<String>| {
+        if let Some(id) = v.selected_id() {
+            v.remove_item(id);
+        }
+    });
+
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.presets.remove(&name);
+    if let Err(err) = save_presets(&patterns.presets) {
+        s.add_layer(
+            Dialog::text(format!("Could not save presets: {}", err)).dismiss_button("Close"),
+        );
+    }
+}
+
+/// Saves the patterns and the settings in the window as a preset, under the name
+/// typed in.
+fn save_preset(s: &mut Cursive) {
+    let name = s
+        .call_on_name("preset_name", |v: &mut EditView| v.get_content())
+        .unwrap()
+        .trim()
+        .to_string();
+    if name.is_empty() {
+        return;
+    }
+    let config = dialog_config(s);
+
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let is_new = patterns.presets.insert(name.clone(), config).is_none();
+    let result = save_presets(&patterns.presets);
+
+    s.call_on_name("preset_list", |v: &mut SelectView<String>| {
+        if is_new {
+            v.add_item(name.clone(), name.clone());
+        }
+        let position = v.iter().position(|(_, preset)| *preset == name);
+        if let Some(id) = position {
+            v.set_selection(id);
+        }
+    });
+    s.call_on_name("preset_name", |v: &mut EditView| v.set_content(""));
+
+    if let Err(err) = result {
+        s.add_layer(
+            Dialog::text(format!("Could not save presets: {}", err)).dismiss_button("Close"),
+        );
+    }
+}
+
+/// Saves the current patterns and settings to be loaded on the next run.
+fn save_defaults(s: &mut Cursive) {
+    let patterns: &AppState = s.user_data().unwrap();
+    let message = match save_config(&Config::from(patterns)) {
+        Ok(path) => format!("Saved defaults to {}", path.to_string_lossy()),
+        Err(err) => format!("Could not save defaults: {}", err),
+    };
+    s.add_layer(Dialog::text(message).dismiss_button("Close"));
+}
+
+type RulesList = SelectView<(String, String)>;
+
+fn rule_label(find: &str, replace: &str) -> String {
+    format!("{} -> {}", find, replace)
+}
+
+/// Adds the rule typed into the settings window to the end of the list.
+fn add_rule(s: &mut Cursive) {
+    let find = s
+        .call_on_name("rule_find", |v: &mut EditView| v.get_content())
+        .unwrap();
+    let replace = s
+        .call_on_name("rule_replace", |v: &mut EditView| v.get_content())
+        .unwrap();
+
+    let patterns: &AppState = s.user_data().unwrap();
+    match patterns.compile_pattern(&find) {
+        Ok(_) => {
+            s.call_on_name("rules_list", |v: &mut RulesList| {
+                v.add_item(
+                    rule_label(&find, &replace),
+                    (find.to_string(), replace.to_string()),
+                );
+            });
+            for name in ["rule_find", "rule_replace"] {
+                s.call_on_name(name, |v: &mut EditView| v.set_content(""));
+            }
+        }
+        Err(err) => s.add_layer(
+            Dialog::text(format!("{}", err))
+                .title("Pattern Error")
+                .dismiss_button("Close"),
+        ),
+    }
+}
+
+/// Moves the selected rule `offset` places up or down the list.
+fn move_rule(s: &mut Cursive, offset: isize) {
+    s.call_on_name("rules_list", |v: &mut RulesList| {
+        let id = match v.selected_id() {
+            Some(id) => id,
+            None => return,
+        };
+        let target = id as isize + offset;
+        if target < 0 || target as usize >= v.len() {
+            return;
+        }
+
+        let (label, rule) = v
+            .get_item(id)
+            .map(|(label, rule)| (label.to_string(), rule.clone()))
+            .unwrap();
+        v.remove_item(id);
+        v.insert_item(target as usize, label, rule);
+        v.set_selection(target as usize);
+    });
+}
+
+fn is_checked(s: &mut Cursive, name: &str) -> bool {
+    s.call_on_name(name, |v: &mut Checkbox| v.is_checked())
+        .unwrap()
+}
+
+/// The number typed into the edit view `name`, if it is one.
+fn edit_number<T: std::str::FromStr>(s: &mut Cursive, name: &str) -> Option<T> {
+    s.call_on_name(name, |v: &mut EditView| v.get_content())
+        .and_then(|text| text.trim().parse().ok())
+}
+
+/// Unchecks the checkbox `other` when one it excludes was just checked.
+fn uncheck_if(s: &mut Cursive, checked: bool, other: &str) {
+    if checked {
+        s.call_on_name(other, |v: &mut Checkbox| v.uncheck());
+    }
+}
+
+/// The current patterns, with the settings as they're checked in the settings window.
+fn dialog_config(s: &mut Cursive) -> Config {
+    let patterns: &AppState = s.user_data().unwrap();
+    let mut config = Config::from(patterns);
+    config.case_insensitive = is_checked(s, "case_insensitive");
+    config.multi_line = is_checked(s, "multi_line");
+    config.dot_matches_new_line = is_checked(s, "dot_matches_new_line");
+    config.literal = is_checked(s, "literal");
+    config.stem_only = is_checked(s, "stem_only");
+    config.counter_per_directory = is_checked(s, "counter_per_directory");
+    config.counter_per_extension = is_checked(s, "counter_per_extension");
+    // Numbers that can't be read keep the value from before
+    config.counter_start = edit_number(s, "counter_start").unwrap_or(config.counter_start);
+    config.counter_step = edit_number(s, "counter_step").unwrap_or(config.counter_step);
+    config.trim_whitespace = is_checked(s, "trim_whitespace");
+    config.collapse_spaces = is_checked(s, "collapse_spaces");
+    config.show_directory = is_checked(s, "show_directory");
+    config.show_details = is_checked(s, "show_details");
+    // A blank width shares the space with the other column
+    config.original_column_width =
+        edit_number(s, "original_column_width").filter(|n| (1..=100).contains(n));
+    config.renamed_column_width =
+        edit_number(s, "renamed_column_width").filter(|n| (1..=100).contains(n));
+    config.selection_only = is_checked(s, "selection_only");
+    config.full_path = is_checked(s, "full_path");
+    config.create_directories = is_checked(s, "create_directories");
+    config.normalize_unicode = is_checked(s, "normalize_unicode");
+    config.case_insensitive_conflicts = is_checked(s, "case_insensitive_conflicts");
+    config.lowercase_extension = is_checked(s, "lowercase_extension");
+    config.uppercase_extension = is_checked(s, "uppercase_extension");
+    config.lowercase_name = is_checked(s, "lowercase_name");
+    config.uppercase_name = is_checked(s, "uppercase_name");
+    config.copy_files = is_checked(s, "copy_files");
+    config.confirm_each = is_checked(s, "confirm_each");
+    config.backup_manifest = is_checked(s, "backup_manifest");
+    config.color_diff = is_checked(s, "color_diff");
+    config.friendly_tokens = is_checked(s, "friendly_tokens");
+    config.natural_sort = is_checked(s, "natural_sort");
+    config.ascii_fold = is_checked(s, "ascii_fold");
+    config.include_hidden = is_checked(s, "include_hidden");
+    // A blank or unreadable occurrence replaces every match
+    config.occurrence = if is_checked(s, "replace_first") {
+        Some(1)
+    } else {
+        edit_number(s, "occurrence").filter(|&n| n > 0)
+    };
+    // A blank range covers the whole name
+    config.range_start = edit_number(s, "range_start").filter(|&n| n > 0);
+    config.range_length = edit_number(s, "range_length").filter(|&n| n > 0);
+    config.pad_numbers = edit_number(s, "pad_numbers").filter(|&n| n > 1);
+    // A blank length leaves names as long as they come
+    config.max_name_length = edit_number(s, "max_name_length").filter(|&n| n > 0);
+    config.truncate_with_ellipsis = is_checked(s, "truncate_with_ellipsis");
+    config.conflict_policy = s
+        .call_on_name("conflict_policy", |v: &mut SelectView<ConflictPolicy>| {
+            v.selection()
+        })
+        .flatten()
+        .map_or(config.conflict_policy, |policy| *policy);
+    config.theme = s
+        .call_on_name("theme", |v: &mut SelectView<ColorTheme>| v.selection())
+        .flatten()
+        .map_or(config.theme, |theme| *theme);
+    config.rules = s
+        .call_on_name("rules_list", |v: &mut RulesList| {
+            v.iter().map(|(_, rule)| rule.clone()).collect()
+        })
+        .unwrap_or(config.rules);
+    config
+}
+
+fn apply_settings(s: &mut Cursive) {
+    let config = dialog_config(s);
+    s.pop_layer();
+
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.apply_config(&config);
+    refresh_optional_columns(s);
+    apply_theme(s);
+    // The sort order may have changed with natural sort
+    s.call_on_name("file_table", |table: &mut RenameView| table.sort());
+
+    match refresh_find_pattern(s) {
+        Ok(()) => refresh_reference_warning(s),
+        Err(err) => set_error_message(s, &short_error(&err)),
+    }
+}
+
+/// Gives `column` `percent` of the table's width, if there's a width set.
+fn column_width(
+    column: TableColumn<RenameColumn>,
+    percent: Option<usize>,
+) -> TableColumn<RenameColumn> {
+    match percent {
+        Some(percent) => column.width_percent(percent),
+        None => column,
+    }
+}
+
+/// Adds or removes the Directory column, after the Original column.
+fn set_directory_column(table: &mut RenameView, show: bool) {
+    if show {
+        table.insert_column(2, RenameColumn::Directory, "Directory", |c| {
+            c.width_percent(25)
+        });
+    } else {
+        table.remove_column(2);
+    }
+}
+
+/// Adds or removes the Size and Modified columns, before the Changed column.
+fn set_details_columns(table: &mut RenameView, show: bool, showing_directory: bool) {
+    let position = if showing_directory { 4 } else { 3 };
+    if show {
+        table.insert_column(position, RenameColumn::Size, "Size", |c| c.width(10));
+        table.insert_column(position + 1, RenameColumn::Modified, "Modified", |c| {
+            c.width(17)
+        });
+    } else {
+        table.remove_column(position + 1);
+        table.remove_column(position);
+    }
+}
+
+/// Shows or hides the Directory, Size and Modified columns to match the settings.
+fn refresh_optional_columns(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    if patterns.show_directory != patterns.showing_directory {
+        patterns.showing_directory = patterns.show_directory;
+        set_directory_column(&mut table, patterns.show_directory);
+    }
+    if patterns.show_details != patterns.showing_details {
+        patterns.showing_details = patterns.show_details;
+        set_details_columns(
+            &mut table,
+            patterns.show_details,
+            patterns.showing_directory,
+        );
+    }
+}
+
+/// Adds the Status column at the end while any item has an error, and removes it
+/// once none do.
+fn refresh_status_column(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let failed = table.borrow_items().iter().any(|item| item.error.is_some())
+        || patterns
+            .hidden_items
+            .iter()
+            .any(|(_, item)| item.error.is_some());
+    if failed == patterns.showing_status {
+        return;
+    }
+    patterns.showing_status = failed;
+    if failed {
+        table.add_column(RenameColumn::Status, "Status", |c| c.width_percent(25));
+    } else {
+        // Status is after every other column
+        let position =
+            4 + usize::from(patterns.showing_directory) + 2 * usize::from(patterns.showing_details);
+        table.remove_column(position);
+    }
+}
+
+/// Recompiles the find pattern and the chained rules with the current flags and
+/// updates the renames. If any of them is invalid the previous regexes are kept.
+fn refresh_find_pattern(s: &mut Cursive) -> Result<(), regex::Error> {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let find_pat = patterns.compile()?;
+    patterns.rules = patterns.compile_rules()?;
+    patterns.find_pat = find_pat;
+    update_renames(s);
+    Ok(())
+}
+
+/// The last line of a regex error, which describes the problem without the
+/// multi-line pattern excerpt.
+fn short_error(err: &regex::Error) -> String {
+    let err = err.to_string();
+    err.lines().last().unwrap().to_string()
+}
+
+fn on_edit_find_pattern(s: &mut Cursive, new_val: &str, _cursor: usize) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.find_pat_raw = new_val.to_string();
+
+    match patterns.compile() {
+        Ok(find_pat) => {
+            patterns.find_pat = find_pat;
+            schedule_update(s);
+            refresh_reference_warning(s);
+        }
+        // Simply do not change
+        Err(err) => {
+            let short_err = short_error(&err);
+            set_error_message(s, &short_err);
+            log::warn!("{}", short_err);
+        }
+    }
+}
+
+/// Commands that print the system clipboard, tried in turn until one works.
+const CLIPBOARD_COMMANDS: &[&[&str]] = &[
+    &["wl-paste", "--no-newline"],
+    &["xclip", "-selection", "clipboard", "-out"],
+    &["xsel", "--clipboard", "--output"],
+    &["pbpaste"],
+    &["powershell", "-NoProfile", "-Command", "Get-Clipboard"],
+    &["termux-clipboard-get"],
+];
+
+/// How long each clipboard command gets before it's given up on. They run while
+/// the screen waits, so a stuck one mustn't freeze it for long.
+const CLIPBOARD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The text on the system clipboard, if any of `CLIPBOARD_COMMANDS` can read it.
+fn read_clipboard() -> Option<String> {
+    CLIPBOARD_COMMANDS
+        .iter()
+        .find_map(|command| command_output(command, CLIPBOARD_TIMEOUT))
+}
+
+/// What `command` prints, if it can be run and succeeds within `timeout`. It's
+/// killed once the time is up.
+fn command_output(command: &[&str], timeout: Duration) -> Option<String> {
+    let mut child = std::process::Command::new(command[0])
+        .args(&command[1..])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .ok()?;
+    // Read as it's printed, so a full pipe can't keep the command from finishing
+    let mut stdout = child.stdout.take()?;
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            _ => {
+                log::warn!("Gave up waiting for {}", command[0]);
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    };
+    let output = reader.join().ok()?.ok()?;
+    if status.success() {
+        String::from_utf8(output).ok()
+    } else {
+        None
+    }
+}
+
+/// What of the clipboard's `text` goes into a pattern box, which holds a single
+/// line: the first one, without its line ending.
+fn clipboard_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+/// Pastes the clipboard's first line into the focused pattern box at the cursor,
+/// which checks the pattern as typing does.
+fn paste_clipboard(s: &mut Cursive) {
+    let patterns: &AppState = s.user_data().unwrap();
+    let name = match patterns.focused_box {
+        Some(name) => name,
+        None => return,
+    };
+    let text = match read_clipboard() {
+        Some(text) => text,
+        None => {
+            set_error_message(
+                s,
+                "Couldn't read the clipboard. Install wl-clipboard, xclip or xsel, \
+                 or paste with the terminal instead.",
+            );
+            return;
+        }
+    };
+
+    let line = clipboard_line(&text);
+    // Each insert's callback sees the content so far, so the last one is enough
+    let on_edit = s
+        .call_on_name(name, |v: &mut EditView| {
+            line.chars().map(|c| v.insert(c)).last()
+        })
+        .flatten();
+    if let Some(on_edit) = on_edit {
+        on_edit(s);
+    }
+}
+
+/// Errors if there is a problem in the regex.
+fn on_submit_find_pattern(s: &mut Cursive, new_val: &str) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.find_pat_raw = new_val.to_string();
+
+    if let Err(err) = refresh_find_pattern(s) {
+        s.add_layer(
+            Dialog::text(format!("{}", err))
+                .title("Pattern Error")
+                .button("Close", |s| {
+                    s.pop_layer();
+                }),
+        );
+    }
+}
+
+fn on_edit_replace_pattern(s: &mut Cursive, new_val: &str, _cursor: usize) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.replace_pat = new_val.to_string();
+    schedule_update(s);
+    refresh_reference_warning(s);
+}
+
+/// Warns about group references the find pattern can't fill and variables that
+/// aren't set, or clears the message if there are none. An error in the find
+/// pattern is shown instead, since the groups can't be known until it's fixed.
+fn refresh_reference_warning(s: &mut Cursive) {
+    let patterns: &AppState = s.user_data().unwrap();
+    if let Err(err) = patterns.compile() {
+        set_error_message(s, &short_error(&err));
+        return;
+    }
+    let mut warnings = Vec::new();
+    let unresolved = patterns.unresolved_references();
+    if !unresolved.is_empty() {
+        warnings.push(format!(
+            "the find pattern has no group for {}",
+            unresolved.join(", ")
+        ));
+    }
+    let unset = patterns.unset_variables();
+    if !unset.is_empty() {
+        warnings.push(format!("no variable is set for {}", unset.join(", ")));
+    }
+    if warnings.is_empty() {
+        hide_error_message(s);
+    } else {
+        set_error_message(s, &format!("warning: {}", warnings.join("; ")));
+    }
+}
+
+/// Updates the table once typing pauses for `UPDATE_DEBOUNCE`, so renaming every
+/// item doesn't happen on each keystroke.
+fn schedule_update(s: &mut Cursive) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.pending_update = Some(Instant::now() + UPDATE_DEBOUNCE);
+
+    let cb_sink = s.cb_sink().clone();
+    thread::spawn(move || {
+        thread::sleep(UPDATE_DEBOUNCE);
+        // Only the callback for the last edit finds the update due
+        let _ = cb_sink.send(Box::new(|s| {
+            let patterns: &AppState = s.user_data().unwrap();
+            if matches!(patterns.pending_update, Some(due) if due <= Instant::now()) {
+                update_renames(s);
+            }
+        }));
+    });
+}
+
+/// Runs the update for an edit that's still waiting on `schedule_update`, so the
+/// table is current before it's used.
+fn flush_pending_update(s: &mut Cursive) {
+    let patterns: &AppState = s.user_data().unwrap();
+    if patterns.pending_update.is_some() {
+        update_renames(s);
+    }
+}
+
+fn update_renames(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.pending_update = None;
+
+    // The items are changed in place, so the selection stays on the same item and
+    // the table doesn't scroll
+    let selected = table.item();
+    let filtering = !patterns.filter.is_empty() || !patterns.hidden_items.is_empty();
+    let selected_file = selected
+        .filter(|_| filtering)
+        .and_then(|index| table.borrow_item(index))
+        .map(|item| item.file.clone());
+    // Hidden items are renamed and numbered as if they were shown
+    unhide_items(&mut table, &mut patterns.hidden_items);
+
+    let order = numbering_order(&mut table, patterns.manual_order);
+    patterns.unreadable_times = apply_patterns(table.borrow_items_mut(), &order, patterns);
+
+    hide_items(&mut table, &patterns.filter, &mut patterns.hidden_items);
+    let selected = match selected_file {
+        Some(file) => table
+            .borrow_items()
+            .iter()
+            .position(|item| item.file == file),
+        None => selected,
+    };
+    if let Some(selected) = selected {
+        table.set_selected_item(selected);
+    }
+    drop(table);
+    refresh_preview(s);
+    refresh_changed_count(s);
+    refresh_status_column(s);
+}
+
+/// Whether an item is shown with `filter`: its Original contains the text, ignoring
+/// case, or matches the regex after a leading `/`. An invalid regex shows everything.
+fn item_filter(filter: &str) -> Box<dyn Fn(&RenameItem) -> bool> {
+    if let Some(pattern) = filter.strip_prefix('/') {
+        return match RegexBuilder::new(pattern).case_insensitive(true).build() {
+            Ok(find_pat) => Box::new(move |item| find_pat.is_match(&item.display)),
+            Err(_) => Box::new(|_| true),
+        };
+    }
+    let filter = filter.to_lowercase();
+    Box::new(move |item| item.display.to_lowercase().contains(&filter))
+}
+
+/// Moves the items `filter` doesn't show out of the table and into `hidden`.
+fn hide_items(table: &mut RenameView, filter: &str, hidden: &mut Vec<(usize, RenameItem)>) {
+    if filter.is_empty() {
+        return;
+    }
+    let shows = item_filter(filter);
+    let mut shown = Vec::new();
+    for (position, item) in table.take_items().into_iter().enumerate() {
+        if shows(&item) {
+            shown.push(item);
+        } else {
+            hidden.push((position, item));
+        }
+    }
+    table.set_items(shown);
+}
+
+/// Puts the items hidden by the filter back in the table, where they were.
+fn unhide_items(table: &mut RenameView, hidden: &mut Vec<(usize, RenameItem)>) {
+    if hidden.is_empty() {
+        return;
+    }
+    let mut shown = table.take_items().into_iter();
+    let mut hidden = hidden.drain(..).peekable();
+    let mut items = Vec::with_capacity(shown.len() + hidden.len());
+    loop {
+        match hidden.next_if(|(position, _)| *position <= items.len()) {
+            Some((_, item)) => items.push(item),
+            None => match shown.next() {
+                Some(item) => items.push(item),
+                None => break,
+            },
+        }
+    }
+    // Only left if positions were lost track of, which shouldn't happen
+    items.extend(hidden.map(|(_, item)| item));
+    table.set_items(items);
+}
+
+/// Clears the filter and shows every item, for when everything is acted on.
+fn clear_filter(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    if patterns.filter.is_empty() && patterns.hidden_items.is_empty() {
+        return;
+    }
+    patterns.filter.clear();
+    unhide_items(&mut table, &mut patterns.hidden_items);
+    drop(table);
+    s.call_on_name("filter", |v: &mut EditView| v.set_content(""));
+    refresh_preview(s);
+    refresh_changed_count(s);
+}
+
+fn on_edit_filter(s: &mut Cursive, new_val: &str, _cursor: usize) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.filter = new_val.to_string();
+    schedule_update(s);
+}
+
+/// The table's items followed by the ones the filter hides, to count them all.
+fn all_items<'a>(
+    shown: &'a [RenameItem],
+    patterns: &'a AppState,
+) -> impl Iterator<Item = &'a RenameItem> + Clone {
+    let hidden = patterns.hidden_items.iter().map(|(_, item)| item);
+    shown.iter().chain(hidden)
+}
+
+/// How many items have a new name, from the patterns, the settings or by hand.
+fn changed_count<'a>(items: impl IntoIterator<Item = &'a RenameItem>) -> usize {
+    items.into_iter().filter(|item| item.is_changed()).count()
+}
+
+/// How many items would still be moved by an apply. Files already renamed by an
+/// earlier apply are at their destination, so aren't counted.
+fn pending_count<'a>(items: impl IntoIterator<Item = &'a RenameItem>) -> usize {
+    items
+        .into_iter()
+        .filter(|item| item.is_changed() && !item.is_in_place())
+        .count()
+}
+
+/// The status line under the table: how many files are loaded, how big they are
+/// and how many an apply would move. Sizes are the ones read as the files were
+/// loaded.
+fn status_line<'a>(items: impl Iterator<Item = &'a RenameItem> + Clone) -> String {
+    let total: u64 = items.clone().filter_map(|item| item.size).sum();
+    let unknown = items.clone().filter(|item| item.size.is_none()).count();
+    let mut size = format_size(total);
+    if unknown > 0 {
+        size.push_str(&format!(" ({} unreadable)", unknown));
+    }
+    format!(
+        "{} files, total size {}, {} will change",
+        items.clone().count(),
+        size,
+        pending_count(items)
+    )
+}
+
+/// Quits, first asking whether to discard the renames if there are any left to
+/// apply.
+fn request_quit(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &AppState = s.user_data().unwrap();
+    // Quitting would stop the renames partway, so they're cancelled from their dialog
+    if patterns.applying {
+        return;
+    }
+    let pending = pending_count(all_items(table.borrow_items(), patterns));
+    drop(table);
+
+    if !patterns.confirm_quit || pending == 0 {
+        s.quit();
+        return;
+    }
+    s.add_layer(
+        Dialog::text(format!("{} files would still be renamed.", pending))
+            .title("Discard pending renames?")
+            .button("Stay", |s| {
+                s.pop_layer();
+            })
+            .button("Quit anyway", |s| s.quit()),
+    );
+}
+
+/// Shows how many of the loaded files the patterns change.
+fn refresh_changed_count(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &AppState = s.user_data().unwrap();
+    let hidden = patterns.hidden_items.len();
+    let mut message = format!(
+        "{} of {} files changed",
+        changed_count(all_items(table.borrow_items(), patterns)),
+        table.len() + hidden
+    );
+    if hidden > 0 {
+        message.push_str(&format!(", {} hidden by the filter", hidden));
+    }
+    if patterns.unreadable_times > 0 {
+        message.push_str(&format!(
+            ", could not read the times of {} files",
+            patterns.unreadable_times
+        ));
+    }
+    let truncated = all_items(table.borrow_items(), patterns)
+        .filter(|item| item.truncated)
+        .count();
+    if truncated > 0 {
+        message.push_str(&format!(
+            ", {} names cut to {} characters",
+            truncated,
+            patterns.max_name_length.unwrap_or_default()
+        ));
+    }
+    let status = status_line(all_items(table.borrow_items(), patterns));
+    drop(table);
+    s.call_on_name("changed_count", |v: &mut TextView| v.set_content(message));
+    s.call_on_name("status_line", |v: &mut TextView| v.set_content(status));
+}
+
+/// Shows the selected item's original name with the matched parts highlighted.
+fn refresh_preview(s: &mut Cursive) {
+    let table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let selected = table
+        .item()
+        .and_then(|index| table.borrow_item(index))
+        .cloned();
+    drop(table);
+
+    let patterns: &AppState = s.user_data().unwrap();
+    let (preview, groups, diff, destination) = match selected {
+        Some(item) => {
+            let before = if item.whole_path {
+                item.file.to_string_lossy().to_string()
+            } else {
+                item.original.clone()
+            };
+            (
+                highlight_matches(&item.original, patterns),
+                describe_captures(&item.original, patterns),
+                render_diff(&char_diff(&before, &item.renamed), patterns.color_diff),
+                describe_destination(&item),
+            )
+        }
+        None => (
+            StyledString::new(),
+            String::new(),
+            StyledString::new(),
+            String::new(),
+        ),
+    };
+    s.call_on_name("match_preview", |v: &mut TextView| v.set_content(preview));
+    s.call_on_name("capture_groups", |v: &mut TextView| v.set_content(groups));
+    s.call_on_name("rename_diff", |v: &mut TextView| v.set_content(diff));
+    s.call_on_name("destination_preview", |v: &mut TextView| {
+        v.set_content(destination)
+    });
+}
+
+/// The absolute path `item` ends up at, noting when that's in another directory
+/// and when the directory doesn't exist yet.
+fn describe_destination(item: &RenameItem) -> String {
+    let destination = item.destination();
+    let mut description = std::path::absolute(&destination)
+        .unwrap_or_else(|_| destination.clone())
+        .to_string_lossy()
+        .to_string();
+    let parent = destination.parent().unwrap_or(Path::new(""));
+    if parent != item.directory() {
+        description.push_str(" (moved to another directory");
+        if !parent.as_os_str().is_empty() && !parent.is_dir() {
+            description.push_str(", which doesn't exist yet");
+        }
+        description.push(')');
+    }
+    description
+}
+
+/// A run of characters in `char_diff`.
+#[derive(Debug, PartialEq)]
+enum DiffPart {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// The most cells `char_diff` fills in for the differing middle of two names.
+/// Past it, as with long full paths, the middle is shown as removed and added
+/// whole.
+const DIFF_TABLE_LIMIT: usize = 250_000;
+
+/// The characters `renamed` keeps, removes and adds from `original`, from their
+/// longest common subsequence.
+fn char_diff(original: &str, renamed: &str) -> Vec<DiffPart> {
+    let a: Vec<char> = original.chars().collect();
+    let b: Vec<char> = renamed.chars().collect();
+    // The start and end they share are kept, whatever is between
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let middle = |chars: &[char]| chars[prefix..chars.len() - suffix].to_vec();
+    let (a_middle, b_middle) = (middle(&a), middle(&b));
+
+    let mut parts = Vec::new();
+    if prefix > 0 {
+        parts.push(DiffPart::Same(a[..prefix].iter().collect()));
+    }
+    if (a_middle.len() + 1).saturating_mul(b_middle.len() + 1) > DIFF_TABLE_LIMIT {
+        if !a_middle.is_empty() {
+            parts.push(DiffPart::Removed(a_middle.iter().collect()));
+        }
+        if !b_middle.is_empty() {
+            parts.push(DiffPart::Added(b_middle.iter().collect()));
+        }
+    } else {
+        parts.extend(subsequence_diff(&a_middle, &b_middle));
+    }
+    if suffix > 0 {
+        parts.push(DiffPart::Same(a[a.len() - suffix..].iter().collect()));
+    }
+    parts
+}
+
+/// `char_diff` for `a` and `b` in full, from a table of their longest common
+/// subsequences.
+fn subsequence_diff(a: &[char], b: &[char]) -> Vec<DiffPart> {
+    // common[i][j] is the length of the longest common subsequence of a[i..], b[j..]
+    let mut common = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            common[i][j] = if a[i] == b[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut parts: Vec<DiffPart> = Vec::new();
+    let mut push = |part: DiffPart| match (parts.last_mut(), part) {
+        (Some(DiffPart::Same(run)), DiffPart::Same(c)) => run.push_str(&c),
+        (Some(DiffPart::Removed(run)), DiffPart::Removed(c)) => run.push_str(&c),
+        (Some(DiffPart::Added(run)), DiffPart::Added(c)) => run.push_str(&c),
+        (_, part) => parts.push(part),
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            push(DiffPart::Same(a[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && common[i + 1][j] >= common[i][j + 1]) {
+            push(DiffPart::Removed(a[i].to_string()));
+            i += 1;
+        } else {
+            push(DiffPart::Added(b[j].to_string()));
+            j += 1;
+        }
+    }
+    parts
+}
+
+/// Shows removed characters in red and added ones in green, or as `[-old-]` and
+/// `{+new+}` without colours.
+fn render_diff(parts: &[DiffPart], color: bool) -> StyledString {
+    let mut styled = StyledString::new();
+    for part in parts {
+        match (part, color) {
+            (DiffPart::Same(text), _) => styled.append_plain(text),
+            (DiffPart::Removed(text), true) => styled.append_styled(
+                text,
+                Style::from(Color::Dark(BaseColor::Red)).combine(Effect::Strikethrough),
+            ),
+            (DiffPart::Added(text), true) => {
+                styled.append_styled(text, Color::Dark(BaseColor::Green))
+            }
+            (DiffPart::Removed(text), false) => styled.append_plain(format!("[-{}-]", text)),
+            (DiffPart::Added(text), false) => styled.append_plain(format!("{{+{}+}}", text)),
+        }
+    }
+    styled
+}
+
+/// Lists what each capture group of the find pattern binds to in the first match
+/// on `original`, as `index <name> "text"`. Groups that took no part in the match
+/// show `-`.
+fn describe_captures(original: &str, patterns: &RenamePatterns) -> String {
+    let searched = if patterns.stem_only {
+        split_extension(original).0
+    } else {
+        original
+    };
+    let captures = match patterns.find_pat.captures(searched) {
+        Some(captures) => captures,
+        None => return String::new(),
+    };
+
+    patterns
+        .find_pat
+        .capture_names()
+        .enumerate()
+        .skip(1)
+        .map(|(index, name)| {
+            let name = name.map(|name| format!(" <{}>", name)).unwrap_or_default();
+            match captures.get(index) {
+                Some(group) => format!("{}{} {:?}", index, name, group.as_str()),
+                None => format!("{}{} -", index, name),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+/// Renders `original` with the regions the find pattern matches in bold.
+fn highlight_matches(original: &str, patterns: &RenamePatterns) -> StyledString {
+    let searched = if patterns.stem_only {
+        split_extension(original).0
+    } else {
+        original
+    };
+
+    let mut styled = StyledString::new();
+    let mut last = 0;
+    for found in patterns.find_pat.find_iter(searched) {
+        if found.as_str().is_empty() {
+            continue;
+        }
+        if last < found.start() {
+            styled.append_plain(&original[last..found.start()]);
+        }
+        styled.append_styled(found.as_str(), Effect::Bold);
+        last = found.end();
+    }
+    if last < original.len() {
+        styled.append_plain(&original[last..]);
+    }
+
+    styled
+}
+
+/// The indices of `items` in the order the table shows them when sorted by
+/// `order`. Like the table, ties keep the order the items were loaded in.
+fn visual_order(
+    items: &[RenameItem],
+    order: Option<(RenameColumn, std::cmp::Ordering)>,
+) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..items.len()).collect();
+    if let Some((column, order)) = order {
+        indices.sort_by(|&a, &b| {
+            if order == std::cmp::Ordering::Less {
+                items[a].cmp(&items[b], column)
+            } else {
+                items[b].cmp(&items[a], column)
+            }
+        });
+    }
+    indices
+}
+
+/// The indices of the table's items in the order `${n}` numbers them, which is the
+/// rows' order once they've been moved by hand and the sort order otherwise.
+fn numbering_order(table: &mut RenameView, manual_order: bool) -> Vec<usize> {
+    if manual_order {
+        row_order(table)
+    } else {
+        let sort = table.order();
+        visual_order(table.borrow_items(), sort)
+    }
+}
+
+/// The indices of the table's items in the order its rows show them, which once
+/// rows are moved by hand isn't the sort order.
+fn row_order(table: &mut RenameView) -> Vec<usize> {
+    let focus = table.row();
+    let order = (0..table.len())
+        .filter_map(|row| {
+            table.set_selected_row(row);
+            table.item()
+        })
+        .collect();
+    if let Some(focus) = focus {
+        table.set_selected_row(focus);
+    }
+    order
+}
+
+/// Swaps the selected row with the one `offset` rows away, keeping the selection
+/// on the moved item. Returns whether there was a row to swap with.
+fn move_row(table: &mut RenameView, offset: isize) -> bool {
+    let (row, item) = match (table.row(), table.item()) {
+        (Some(row), Some(item)) => (row, item),
+        _ => return false,
+    };
+    let target = row as isize + offset;
+    if target < 0 || target as usize >= table.len() {
+        return false;
+    }
+
+    table.set_selected_row(target as usize);
+    let other = table.item().unwrap();
+    // The rows keep pointing at the same places, so swapping what's there swaps
+    // the rows and leaves the selection on the moved item
+    table.borrow_items_mut().swap(item, other);
+    true
+}
+
+/// Moves the selected item `offset` rows, so `${n}` numbers the files in the order
+/// they're shown rather than sorted. Sorting by a column again goes back to that
+/// order.
+fn move_selected_item(s: &mut Cursive, offset: isize) {
+    let patterns: &AppState = s.user_data().unwrap();
+    if !patterns.filter.is_empty() {
+        s.add_layer(Dialog::text("Clear the filter to move files").dismiss_button("Close"));
+        return;
+    }
+
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let moved = move_row(&mut table, offset);
+    drop(table);
+    if moved {
+        s.with_user_data(|patterns: &mut AppState| patterns.manual_order = true);
+        update_renames(s);
+    }
+}
+
+/// How `pick_items` changes which items are picked.
+enum Pick {
+    /// Flip the item under the cursor
+    Toggle,
+    All,
+    Invert,
+}
+
+fn pick_items(s: &mut Cursive, pick: Pick) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    match pick {
+        Pick::Toggle => {
+            if let Some(item) = table.item().and_then(|index| table.borrow_item_mut(index)) {
+                item.picked = !item.picked;
+            }
+        }
+        Pick::All => table
+            .borrow_items_mut()
+            .iter_mut()
+            .for_each(|item| item.picked = true),
+        Pick::Invert => table
+            .borrow_items_mut()
+            .iter_mut()
+            .for_each(|item| item.picked = !item.picked),
+    }
+    drop(table);
+    update_renames(s);
+}
+
+/// Drops the item under the cursor from the table, unless it's the last one.
+fn remove_selected_item(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    if table.len() == 1 && patterns.hidden_items.is_empty() {
+        drop(table);
+        set_error_message(
+            s,
+            "Can't remove the last file, there would be nothing to rename",
+        );
+        return;
+    }
+    if let Some(index) = table.item() {
+        remove_item_keeping_selection(&mut table, index);
+        // Hidden items after the removed one move up a place
+        let mut removed = index;
+        for (position, _) in &mut patterns.hidden_items {
+            if *position <= removed {
+                removed += 1;
+            } else {
+                *position -= 1;
+            }
+        }
+    }
+    drop(table);
+    update_renames(s);
+}
+
+/// Removes the item at `index`, keeping the same item selected. If that was the
+/// removed one, its neighbour is selected instead, never a row past the end.
+fn remove_item_keeping_selection(table: &mut RenameView, index: usize) {
+    let selected = table.item();
+    table.remove_item(index);
+    match selected {
+        Some(selected) if selected != index => table.set_selected_item(if selected > index {
+            selected - 1
+        } else {
+            selected
+        }),
+        _ => {
+            if let Some(row) = table.row() {
+                if row >= table.len() && !table.is_empty() {
+                    table.set_selected_row(table.len() - 1);
+                }
+            }
+        }
+    }
+}
+
+/// Lets the user type in the new name for the item at `index` by hand.
+fn edit_renamed(s: &mut Cursive, _row: usize, index: usize) {
+    flush_pending_update(s);
+    let table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let renamed = table.borrow_item(index).unwrap().renamed.clone();
+    drop(table);
+
+    s.add_layer(
+        Dialog::around(
+            EditView::new()
+                .content(renamed)
+                .on_submit(move |s, name| set_manual_rename(s, index, name))
+                .with_name("manual_rename")
+                .min_width(40),
+        )
+        .title("Rename file")
+        .button("Use pattern", move |s| {
+            s.pop_layer();
+            clear_manual_rename(s, index);
+        })
+        .dismiss_button("Cancel")
+        .button("Ok", move |s| {
+            let name = s
+                .call_on_name("manual_rename", |v: &mut EditView| v.get_content())
+                .unwrap();
+            set_manual_rename(s, index, &name);
+        }),
+    );
+}
+
+fn set_manual_rename(s: &mut Cursive, index: usize, name: &str) {
+    s.pop_layer();
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    if let Some(item) = table.borrow_item_mut(index) {
+        item.rename_to(name.to_string());
+        item.manually_edited = true;
+    }
+    drop(table);
+    refresh_preview(s);
+    refresh_changed_count(s);
+    refresh_status_column(s);
+}
+
+/// Hands the item at `index` back to the patterns.
+fn clear_manual_rename(s: &mut Cursive, index: usize) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    if let Some(item) = table.borrow_item_mut(index) {
+        item.manually_edited = false;
+    }
+    drop(table);
+    update_renames(s);
+}
+
+/// Resets the new name of the item under the cursor, including a manual edit. The
+/// patterns rename it again the next time they change.
+fn revert_selected_item(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let index = match table.item() {
+        Some(index) => index,
+        None => return,
+    };
+    if let Some(item) = table.borrow_item_mut(index) {
+        item.reset();
+        item.manually_edited = false;
+    }
+    drop(table);
+    refresh_preview(s);
+    refresh_changed_count(s);
+    refresh_status_column(s);
+}
+
+/// The warning dialogs an apply is waiting on. The renames only go ahead once
+/// every dialog shown has been continued, and not at all once one is cancelled.
+#[derive(Debug, Default)]
+struct WarningState {
+    /// Dialogs that haven't been answered yet
+    pending: HashSet<&'static str>,
+    cancelled: bool,
+}
+
+impl WarningState {
+    /// The dialogs `apply_renames` shows for the warnings in `check`.
+    fn for_check(check: &CheckResult) -> Self {
+        let warnings = [
+            ("names_dialog", &check.conflicting_names),
+            ("collisions_dialog", &check.unchanged_collisions),
+            ("perm_dialog", &check.permission_problems),
+            ("overwrite_dialog", &check.overwrite_targets),
+            ("moves_dialog", &check.moved_names),
+        ];
+        WarningState {
+            pending: warnings
+                .into_iter()
+                .filter(|(_, names)| !names.is_empty())
+                .map(|(name, _)| name)
+                .collect(),
+            cancelled: false,
+        }
+    }
+
+    /// Records the answer to the dialog `name`, returning whether that was the last
+    /// one so the renames can go ahead. Dialogs that were already answered, or
+    /// never shown, change nothing.
+    fn answer(&mut self, name: &str, continued: bool) -> bool {
+        if !self.pending.remove(name) {
+            return false;
+        }
+        self.cancelled |= !continued;
+        !self.cancelled && self.pending.is_empty()
+    }
+}
+
+fn warning_dialog(name: &'static str, title: &str, message: String) -> NamedView<Dialog> {
+    Dialog::text(message)
+        .title(title)
+        .button("Cancel", move |s| {
+            s.pop_layer();
+            answer_warning(s, name, false);
+        })
+        .button("Continue", move |s| {
+            s.pop_layer();
+            answer_warning(s, name, true);
+        })
+        .with_name(name)
+}
+
+/// Records the answer to a warning dialog, confirming the renames once every
+/// warning has been continued. After a cancel, the other warnings can only be
+/// cancelled too.
+fn answer_warning(s: &mut Cursive, name: &'static str, continued: bool) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let proceed = patterns.warnings.answer(name, continued);
+    if patterns.warnings.cancelled {
+        let others: Vec<&str> = patterns.warnings.pending.iter().copied().collect();
+        for other in others {
+            s.call_on_name(other, |v: &mut Dialog| {
+                v.buttons_mut().skip(1).for_each(|button| button.disable())
+            });
+        }
+    }
+    if proceed {
+        confirm_rename(s);
+    }
+}
+
+/// Lists every change the apply is about to make, renaming once confirmed.
+fn confirm_rename(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &AppState = s.user_data().unwrap();
+    if patterns.confirm_each {
+        let changes = planned_items(table.borrow_items(), &patterns.skipped_targets)
+            .map(|item| {
+                (
+                    item.destination(),
+                    format!("{} → {}", item.display, item.renamed),
+                )
+            })
+            .collect();
+        drop(table);
+        confirm_each_rename(s, Rc::new(changes), 0);
+        return;
+    }
+    let changes = planned_changes(table.borrow_items(), &patterns.skipped_targets);
+    drop(table);
+
+    let mut dialog = list_dialog("Confirm Renames", changes);
+    dialog.clear_buttons();
+    s.add_layer(
+        dialog
+            .button("Cancel", |s| {
+                s.pop_layer();
+            })
+            .button("Confirm", |s| {
+                s.pop_layer();
+                do_rename(s);
+            }),
+    );
+}
+
+/// Asks about the rename at `index` in `changes`, each a destination and its
+/// "old → new" line, then the ones after it. Declined renames have their
+/// destination skipped, and "Yes to all" stops asking.
+fn confirm_each_rename(s: &mut Cursive, changes: Rc<Vec<(PathBuf, String)>>, index: usize) {
+    let (destination, change) = match changes.get(index) {
+        Some(change) => change.clone(),
+        None => {
+            finish_confirming(s);
+            return;
+        }
+    };
+    let patterns: &AppState = s.user_data().unwrap();
+    let verb = if patterns.copy_files {
+        "Copy"
+    } else {
+        "Rename"
+    };
+
+    let next = changes.clone();
+    s.add_layer(
+        Dialog::text(format!("{} {}?", verb, change))
+            .title(format!("Confirm {} of {}", index + 1, changes.len()))
+            .button("Yes", move |s| {
+                s.pop_layer();
+                confirm_each_rename(s, next.clone(), index + 1);
+            })
+            .button("No", move |s| {
+                s.pop_layer();
+                let patterns: &mut AppState = s.user_data().unwrap();
+                patterns.skipped_targets.insert(destination.clone());
+                confirm_each_rename(s, changes.clone(), index + 1);
+            })
+            .button("Yes to all", |s| {
+                s.pop_layer();
+                finish_confirming(s);
+            })
+            .button("Cancel", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+/// Renames what was agreed to in `confirm_each_rename`, saying first which renames
+/// had to be left out along with the declined ones.
+fn finish_confirming(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let blocked = skip_blocked_renames(table.borrow_items(), &mut patterns.skipped_targets);
+    drop(table);
+
+    if blocked.is_empty() {
+        do_rename(s);
+        return;
+    }
+    let mut dialog = list_dialog("Also Skipped", blocked);
+    dialog.clear_buttons();
+    s.add_layer(
+        dialog
+            .button("Cancel", |s| {
+                s.pop_layer();
+            })
+            .button("Continue", |s| {
+                s.pop_layer();
+                do_rename(s);
+            }),
+    );
+}
+
+/// Skips the renames onto a file that's staying where it is because its own rename
+/// was skipped, as in one half of a swap. Skipping those can keep more files in
+/// place, so this repeats until none are left. Returns their "old → new" lines.
+fn skip_blocked_renames(
+    items: &[RenameItem],
+    skipped_targets: &mut HashSet<PathBuf>,
+) -> Vec<String> {
+    let mut blocked = Vec::new();
+    loop {
+        let staying: HashSet<&Path> = items
+            .iter()
+            .filter(|item| item.is_changed() && skipped_targets.contains(&item.destination()))
+            .map(|item| item.file.as_path())
+            .collect();
+        let newly_blocked: Vec<(PathBuf, String)> = planned_items(items, skipped_targets)
+            .filter(|item| staying.contains(item.destination().as_path()))
+            .map(|item| {
+                (
+                    item.destination(),
+                    format!("{} → {}", item.display, item.renamed),
+                )
+            })
+            .collect();
+        if newly_blocked.is_empty() {
+            return blocked;
+        }
+        for (destination, change) in newly_blocked {
+            skipped_targets.insert(destination);
+            blocked.push(change);
+        }
+    }
+}
+
+/// An "old → new" line for each rename in `planned_moves`.
+fn planned_changes(items: &[RenameItem], skipped_targets: &HashSet<PathBuf>) -> Vec<String> {
+    planned_items(items, skipped_targets)
+        .map(|item| format!("{} → {}", item.display, item.renamed))
+        .collect()
+}
+
+/// Sends the files about to be overwritten to the trash, then continues. Renames
+/// onto a file that couldn't be trashed are skipped.
+fn trash_overwrite_targets(s: &mut Cursive, targets: &[PathBuf]) {
+    s.pop_layer();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let mut failures = Vec::new();
+
+    for target in targets {
+        if patterns.dry_run {
+            log::info!("Would trash {}", target.to_string_lossy());
+            continue;
+        }
+        if let Err(err) = trash::delete(target) {
+            failures.push(format!("{}: {}", target.to_string_lossy(), err));
+            patterns.skipped_targets.insert(target.clone());
+        }
+    }
+
+    answer_warning(s, "overwrite_dialog", true);
+    show_failures(s, "Files could not be moved to the trash", &failures);
+}
+
+/// Closes the warnings and gives the files in each conflict distinct names with
+/// numbered suffixes, then checks the renames again.
+fn deduplicate_conflicts(s: &mut Cursive) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let open: Vec<&str> = patterns.warnings.pending.drain().collect();
+    for name in open {
+        if let Some(position) = s.screen_mut().find_layer_from_name(name) {
+            s.screen_mut().remove_layer(position);
+        }
+    }
+
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &AppState = s.user_data().unwrap();
+    let order = numbering_order(&mut table, patterns.manual_order);
+    deduplicate_names(table.borrow_items_mut(), &order, patterns);
+    drop(table);
+    refresh_preview(s);
+    refresh_changed_count(s);
+    apply_renames(s);
+}
+
+fn apply_renames(s: &mut Cursive) {
+    flush_pending_update(s);
+    clear_filter(s);
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let mut check_result = check_renames(table.borrow_items(), patterns);
+    let order = numbering_order(&mut table, patterns.manual_order);
+    let skipped = settle_conflicts(
+        table.borrow_items_mut(),
+        &order,
+        patterns,
+        &mut check_result,
+    );
+
+    patterns.existing_targets = existing_targets(table.borrow_items());
+    drop(table);
+    patterns.skipped_targets = skipped;
+    if patterns.conflict_policy == ConflictPolicy::Suffix {
+        refresh_preview(s);
+        refresh_changed_count(s);
+    }
+
+    // These renames would fail anyway, so there's no continuing past them
+    if !check_result.invalid_names.is_empty() {
+        s.add_layer(
+            Dialog::text(format!(
+                "Names contain characters that aren't allowed:\n {}",
+                check_result.invalid_names.join(",\n ")
+            ))
+            .title("Invalid Names")
+            .dismiss_button("Close"),
+        );
+    }
+    if !check_result.empty_names.is_empty() {
+        s.add_layer(
+            Dialog::text(format!(
+                "Files would be renamed to an empty name:\n {}",
+                check_result.empty_names.join(",\n ")
+            ))
+            .title("Empty Names")
+            .dismiss_button("Close"),
+        );
+    }
+    if !check_result.too_long.is_empty() {
+        s.add_layer(
+            Dialog::text(format!(
+                "Files would be renamed to paths that are too long:\n {}",
+                check_result.too_long.join(",\n ")
+            ))
+            .title("Paths Too Long")
+            .dismiss_button("Close"),
+        );
+    }
+    if !check_result.missing_directories.is_empty() {
+        s.add_layer(
+            Dialog::text(format!(
+                "Files would be moved into directories that don't exist. Turn on \
+                 \"Create directories\" in the settings to create them:\n {}",
+                check_result.missing_directories.join(",\n ")
+            ))
+            .title("Missing Directories")
+            .dismiss_button("Close"),
+        );
+    }
+    if check_result.is_blocked() {
+        return;
+    }
+
+    if !check_result.has_warnings() {
+        confirm_rename(s);
+        return;
+    }
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.warnings = WarningState::for_check(&check_result);
+
+    if !check_result.conflicting_names.is_empty() {
+        let names_message = format!(
+            "Files will be renamed to the same value:\n {}",
+            check_result.conflicting_names.join(",\n ")
+        );
+
+        let mut dialog = warning_dialog("names_dialog", "Conflicting names Error", names_message);
+        dialog
+            .get_mut()
+            .add_button("De-duplicate", deduplicate_conflicts);
+        s.add_layer(dialog);
+    }
+
+    if !check_result.unchanged_collisions.is_empty() {
+        let collisions_message = format!(
+            "Files will be renamed to the name of a file that isn't changing or can't be renamed:\n {}",
+            check_result.unchanged_collisions.join(",\n ")
+        );
+
+        s.add_layer(warning_dialog(
+            "collisions_dialog",
+            "Unchanged File Conflict",
+            collisions_message,
+        ));
+    }
+
+    if !check_result.permission_problems.is_empty() {
+        let perm_message = format!(
+            "Files cannot be renamed:\n {}",
+            check_result.permission_problems.join(",\n ")
+        );
+
+        s.add_layer(warning_dialog(
+            "perm_dialog",
+            "Permissions Error",
+            perm_message,
+        ));
+    }
+
+    if !check_result.overwrite_targets.is_empty() {
+        let overwrite_message = format!(
+            "Existing files will be overwritten:\n {}",
+            check_result.overwrite_targets.join(",\n ")
+        );
+
+        let targets: Vec<PathBuf> = check_result
+            .overwrite_targets
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let mut dialog = warning_dialog("overwrite_dialog", "Overwrite Warning", overwrite_message);
+        dialog.get_mut().add_button("Trash existing", move |s| {
+            trash_overwrite_targets(s, &targets)
+        });
+
+        s.add_layer(dialog);
+    }
+
+    if !check_result.moved_names.is_empty() {
+        let moves_message = format!(
+            "Files will be moved into another directory, which is created if missing:\n {}",
+            check_result.moved_names.join(",\n ")
+        );
+
+        s.add_layer(warning_dialog(
+            "moves_dialog",
+            "Move Warning",
+            moves_message,
+        ));
+    }
+}
+
+/// Writes the backup manifest for `moves` if the settings ask for one, returning
+/// where it went. Copies leave the originals alone, so they aren't backed up.
+fn backup_moves(patterns: &AppState, moves: &[(PathBuf, PathBuf)]) -> io::Result<Option<PathBuf>> {
+    if !patterns.backup_manifest || patterns.copy_files {
+        return Ok(None);
+    }
+    let path = match &patterns.manifest_path {
+        Some(path) => path.clone(),
+        None => config_path(&format!(
+            "backups/renames-{}.json",
+            Local::now().format("%Y%m%d-%H%M%S")
+        ))
+        .ok_or_else(|| io::Error::other("could not find a config directory"))?,
+    };
+    write_manifest(&path, moves)?;
+    Ok(Some(path))
+}
+
+/// One rename in the plan `--json` prints.
+#[derive(Debug, PartialEq, Serialize)]
+struct PlannedRename {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+/// The plan `--json` prints: the renames the patterns make, and what would stop or
+/// warn about applying them.
+#[derive(Debug, Serialize)]
+struct JsonPlan {
+    renames: Vec<PlannedRename>,
+    /// Files that couldn't be loaded
+    failed: Vec<String>,
+    /// Rows of the imported plan that matched no file
+    unmatched_rows: Vec<String>,
+    problems: CheckResult,
+    /// Whether the problems stop the renames from being applied at all
+    blocked: bool,
+}
+
+/// Works out the new names of `items` and checks them, for `--json`.
+fn json_plan(items: &mut [RenameItem], patterns: &RenamePatterns) -> JsonPlan {
+    let order: Vec<usize> = (0..items.len()).collect();
+    apply_patterns(items, &order, patterns);
+    let mut problems = check_renames(items, patterns);
+    let skipped = settle_conflicts(items, &order, patterns, &mut problems);
+    JsonPlan {
+        renames: items
+            .iter()
+            .filter(|item| item.is_changed() && !skipped.contains(&item.destination()))
+            .map(|item| PlannedRename {
+                from: item.file.clone(),
+                to: item.destination(),
+            })
+            .collect(),
+        failed: Vec::new(),
+        unmatched_rows: Vec::new(),
+        blocked: problems.is_blocked(),
+        problems,
+    }
+}
+
+/// The settings for running without the TUI: the saved ones, with the command
+/// line's on top. Prints the error and returns `None` if the find pattern doesn't
+/// compile.
+fn headless_state(args: &Args, config: &Config) -> Option<AppState> {
+    let mut patterns = AppState {
+        dry_run: args.dry_run,
+        ..AppState::default()
+    };
+    patterns.apply_config(config);
+    patterns.copy_files |= args.copy;
+    patterns.backup_manifest |= args.manifest.is_some();
+    patterns.manifest_path = args.manifest.clone();
+    // Overwriting files still needs `--force`, unless it was asked for here
+    match args.conflict_policy {
+        Some(policy) => patterns.conflict_policy = policy,
+        None if patterns.conflict_policy == ConflictPolicy::Overwrite && !args.force => {
+            patterns.conflict_policy = ConflictPolicy::Ask
+        }
+        None => {}
+    }
+    match patterns
+        .compile()
+        .and_then(|find_pat| Ok((find_pat, patterns.compile_rules()?)))
+    {
+        Ok((find_pat, rules)) => {
+            patterns.find_pat = find_pat;
+            patterns.rules = rules;
+        }
+        Err(err) => {
+            eprintln!("tui_rename: {}", short_error(&err));
+            return None;
+        }
+    }
+    Some(patterns)
+}
+
+/// Each kind of problem `check_renames` finds, with how the dialogs describe it.
+fn problem_lists(check: &CheckResult) -> [(&'static str, &[String]); 9] {
+    [
+        (
+            "Names contain characters that aren't allowed",
+            &check.invalid_names,
+        ),
+        (
+            "Files would be renamed to an empty name",
+            &check.empty_names,
+        ),
+        (
+            "Files would be renamed to paths that are too long",
+            &check.too_long,
+        ),
+        (
+            "Files would be moved into directories that don't exist",
+            &check.missing_directories,
+        ),
+        (
+            "Files will be renamed to the same value",
+            &check.conflicting_names,
+        ),
+        (
+            "Files will be renamed to the name of a file that isn't changing",
+            &check.unchanged_collisions,
+        ),
+        ("Files cannot be renamed", &check.permission_problems),
+        (
+            "Existing files will be overwritten",
+            &check.overwrite_targets,
+        ),
+        (
+            "Files will be moved into another directory",
+            &check.moved_names,
+        ),
+    ]
+}
+
+/// Makes the renames without opening the TUI, for `--apply`, printing what was done.
+/// Renames the checks warn about need `--force`, and those that can't be made stop
+/// everything. Returns the exit code, which is 1 if anything wasn't renamed.
+fn apply_without_ui(args: &Args, config: &Config) -> i32 {
+    let patterns = match headless_state(args, config) {
+        Some(patterns) => patterns,
+        None => return 2,
+    };
+
+    let (mut items, failed, unmatched_rows) = load_items(args, config);
+    for failure in &failed {
+        eprintln!("tui_rename: {}", failure);
+    }
+    for row in &unmatched_rows {
+        eprintln!("tui_rename: {}: imported but not loaded", row);
+    }
+
+    let order: Vec<usize> = (0..items.len()).collect();
+    apply_patterns(&mut items, &order, &patterns);
+    let mut check = check_renames(&items, &patterns);
+    let skipped = settle_conflicts(&mut items, &order, &patterns, &mut check);
+    for (problem, names) in problem_lists(&check) {
+        if !names.is_empty() {
+            eprintln!("tui_rename: {}:\n  {}", problem, names.join("\n  "));
+        }
+    }
+    if check.is_blocked() {
+        eprintln!("tui_rename: nothing was renamed");
+        return 1;
+    }
+    if check.has_warnings() && !args.force {
+        eprintln!("tui_rename: nothing was renamed, use --force to rename anyway");
+        return 1;
+    }
+
+    let copy = patterns.copy_files;
+    let moves = planned_moves(&items, &skipped);
+    if patterns.dry_run {
+        let verb = if copy { "copy" } else { "rename" };
+        for (from, to) in &moves {
+            println!(
+                "Would {} {} to {}",
+                verb,
+                from.to_string_lossy(),
+                to.to_string_lossy()
+            );
+        }
+        println!("Would {} {} files", verb, moves.len());
+        return 0;
+    }
+
+    let manifest = match backup_moves(&patterns, &moves) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!(
+                "tui_rename: couldn't write the backup manifest, so nothing was renamed: {}",
+                err
+            );
+            return 1;
+        }
+    };
+    let existing = existing_targets(&items);
+    let mut result = perform_renames(&mut items, &skipped, &existing, copy);
+    result.manifest = manifest;
+    println!("{}", rename_summary(&result, copy));
+    for failure in &result.failures {
+        eprintln!("tui_rename: {}", failure);
+    }
+    if result.failures.is_empty() && failed.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Prints the planned renames as JSON without opening the TUI, for `--json`.
+/// Returns the exit code, which is 1 if problems stop the renames.
+fn print_json_plan(args: &Args, config: &Config) -> i32 {
+    let patterns = match headless_state(args, config) {
+        Some(patterns) => patterns,
+        None => return 2,
+    };
+
+    let (mut items, failed, unmatched_rows) = load_items(args, config);
+    let plan = JsonPlan {
+        failed,
+        unmatched_rows,
+        ..json_plan(&mut items, &patterns)
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if let Err(err) = serde_json::to_writer_pretty(&mut out, &plan)
+        .and_then(|()| writeln!(out).map_err(serde_json::Error::io))
+    {
+        eprintln!("tui_rename: {}", err);
+        return 1;
+    }
+    if plan.blocked {
+        1
+    } else {
+        0
+    }
+}
+
+/// The newest backup manifest `backup_moves` wrote into `dir`, going by the time
+/// in its name.
+fn latest_manifest(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("renames-") && name.ends_with(".json")
+        })
+        .max()
+}
+
+/// Whether the file at `path` was changed after `timestamp`, in RFC 3339. Renames
+/// keep the time a file was modified, so only a later edit counts.
+fn modified_since(path: &Path, timestamp: &str) -> bool {
+    let modified = path.metadata().and_then(|metadata| metadata.modified());
+    match (modified, DateTime::parse_from_rfc3339(timestamp)) {
+        (Ok(modified), Ok(timestamp)) => DateTime::<Local>::from(modified) > timestamp,
+        _ => false,
+    }
+}
+
+/// Renames everything in the manifest at `path` back, for `--restore` and
+/// `--undo-last`, then marks it as restored so it isn't undone twice. Returns the
+/// exit code.
+fn restore_manifest(path: &Path) -> i32 {
+    let manifest = match read_manifest(path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("tui_rename: {}: {}", path.to_string_lossy(), err);
+            return 1;
+        }
+    };
+    if let Some(restored) = &manifest.restored {
+        eprintln!(
+            "tui_rename: the renames made at {} were already undone at {}",
+            manifest.timestamp, restored
+        );
+        return 1;
+    }
+
+    let mut moved = Vec::new();
+    let mut missing = 0;
+    for entry in manifest.renames {
+        if entry.to.exists() {
+            if modified_since(&entry.to, &manifest.timestamp) {
+                eprintln!(
+                    "tui_rename: warning: {} has been modified since it was renamed",
+                    entry.to.to_string_lossy()
+                );
+            }
+            moved.push((entry.from, entry.to));
+        } else if !entry.from.exists() {
+            eprintln!(
+                "tui_rename: {} no longer exists",
+                entry.to.to_string_lossy()
+            );
+            missing += 1;
+        }
+        // Otherwise the rename never happened, such as after a crash, so there's
+        // nothing to undo
+    }
+    let result = undo_moves(&moved);
+    println!(
+        "Restored {} files from the renames made at {}",
+        result.moved.len(),
+        manifest.timestamp
+    );
+    for failure in &result.failures {
+        eprintln!("tui_rename: {}", failure);
+    }
+    if !result.moved.is_empty() {
+        if let Err(err) = mark_restored(path) {
+            eprintln!(
+                "tui_rename: couldn't mark {} as restored: {}",
+                path.to_string_lossy(),
+                err
+            );
+        }
+    }
+    if result.failures.is_empty() && missing == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Performs the renames on the table's items, then reports how many succeeded. Large
+/// applies are run in the background by `rename_in_background`.
+fn do_rename(s: &mut Cursive) {
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    let patterns: &mut AppState = s.user_data().unwrap();
+    let skipped_targets = std::mem::take(&mut patterns.skipped_targets);
+    let existing_targets = std::mem::take(&mut patterns.existing_targets);
+    let copy = patterns.copy_files;
+    let verb = if copy { "copy" } else { "rename" };
+
+    if patterns.dry_run {
+        let moves = planned_moves(table.borrow_items(), &skipped_targets);
+        drop(table);
+        for (from, to) in &moves {
+            log::info!(
+                "Would {} {} to {}",
+                verb,
+                from.to_string_lossy(),
+                to.to_string_lossy()
+            );
+        }
+
+        s.add_layer(
+            Dialog::text(format!("Would {} {} files", verb, moves.len()))
+                .title("Finished (dry run)")
+                .button("Back", |s| {
+                    s.pop_layer();
+                })
+                .button("Finish", |s| s.quit()),
+        );
+        return;
+    }
+
+    let mut moves = planned_moves(table.borrow_items(), &skipped_targets);
+    let manifest = match backup_moves(patterns, &moves) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            drop(table);
+            s.add_layer(
+                Dialog::text(format!(
+                    "Couldn't write the backup manifest, so nothing was renamed: {}",
+                    err
+                ))
+                .title("Backup Error")
+                .dismiss_button("Close"),
+            );
+            return;
+        }
+    };
+    if moves.len() >= PROGRESS_THRESHOLD {
+        let skipped = skipped_count(table.borrow_items(), &moves);
+        drop(table);
+        let errors = create_target_directories(&mut moves);
+        rename_in_background(s, moves, errors, skipped, existing_targets, copy, manifest);
+        return;
+    }
+
+    let mut result = perform_renames(
+        table.borrow_items_mut(),
+        &skipped_targets,
+        &existing_targets,
+        copy,
+    );
+    drop(table);
+    result.manifest = manifest;
+    show_rename_result(s, result);
+}
+
+/// Performs `moves` on another thread, showing their progress in a dialog that
+/// can cancel the remaining renames.
+fn rename_in_background(
+    s: &mut Cursive,
+    moves: Vec<(PathBuf, PathBuf)>,
+    errors: Vec<(PathBuf, String)>,
+    skipped: usize,
+    existing_targets: HashSet<PathBuf>,
+    copy: bool,
+    manifest: Option<PathBuf>,
+) {
+    let total = moves.len();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let done_verb = if copy { "Copied" } else { "Renamed" };
+
+    let cancel = cancelled.clone();
+    s.add_layer(
+        Dialog::around(
+            TextView::new(format!("{} 0 of {} files", done_verb, total))
+                .with_name("rename_progress"),
+        )
+        .title(if copy { "Copying" } else { "Renaming" })
+        .button("Cancel", move |s| {
+            cancel.store(true, Ordering::Relaxed);
+            s.call_on_name("rename_progress", |v: &mut TextView| {
+                v.set_content("Cancelling...")
+            });
+        })
+        .with_name("progress_dialog"),
+    );
+    let patterns: &mut AppState = s.user_data().unwrap();
+    patterns.applying = true;
+
+    let cb_sink = s.cb_sink().clone();
+    thread::spawn(move || {
+        // Report roughly each percent, rather than sending a callback per file
+        let step = (total / 100).max(1);
+        let done = AtomicUsize::new(0);
+        let on_moved = || {
+            let count = done.fetch_add(1, Ordering::Relaxed) + 1;
+            // Once cancelled, the dialog keeps saying so until the thread finishes
+            if count.is_multiple_of(step) && !cancelled.load(Ordering::Relaxed) {
+                let _ = cb_sink.send(Box::new(move |s| {
+                    s.call_on_name("rename_progress", |v: &mut TextView| {
+                        v.set_content(format!("{} {} of {} files", done_verb, count, total))
+                    });
+                }));
+            }
+        };
+
+        let mut result = run_moves(
+            moves,
+            errors,
+            &existing_targets,
+            copy,
+            &cancelled,
+            &on_moved,
+        );
+        result.skipped = skipped;
+        result.manifest = manifest;
+        let _ = cb_sink.send(Box::new(move |s| {
+            if let Some(position) = s.screen_mut().find_layer_from_name("progress_dialog") {
+                s.screen_mut().remove_layer(position);
+            }
+            let patterns: &mut AppState = s.user_data().unwrap();
+            patterns.applying = false;
+            let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+            // Counted before the moved items are pointed at their new names
+            result.in_place = in_place_count(table.borrow_items());
+            if !copy {
+                relocate_items(table.borrow_items_mut(), &result.moved);
+            }
+            record_errors(table.borrow_items_mut(), &result);
+            drop(table);
+            show_rename_result(s, result);
+        }));
+    });
+}
+
+/// Records a finished apply so it can be undone, and reports how it went. Copies
+/// leave the originals in place, so there's nothing to undo.
+fn show_rename_result(s: &mut Cursive, result: RenameResult) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    if !result.moved.is_empty() && !patterns.copy_files {
+        patterns.history.push(result.moved.clone());
+    }
+
+    let message = rename_summary(&result, patterns.copy_files);
+    refresh_status_column(s);
+    refresh_changed_count(s);
+    s.add_layer(
+        Dialog::text(message)
+            .button("Back", |s| {
+                s.pop_layer();
+            })
+            .button("Undo", |s| {
+                s.pop_layer();
+                undo_last_apply(s);
+            })
+            .button("Finish", |s| s.quit()),
+    );
+
+    show_failures(s, "Files could not be renamed", &result.failures);
+}
+
+/// What an apply did, for telling the user.
+fn rename_summary(result: &RenameResult, copy: bool) -> String {
+    let verb = if copy { "Copied" } else { "Renamed" };
+    let mut message = format!(
+        "{} {} files, skipped {}, failed {}",
+        verb,
+        result.moved.len(),
+        result.skipped,
+        result.failures.len()
+    );
+    if result.in_place > 0 {
+        message.push_str(&format!(
+            ", {} already had their new names",
+            result.in_place
+        ));
+    }
+    if result.cancelled > 0 {
+        message.push_str(&format!(", cancelled before {} others", result.cancelled));
+    }
+    if result.cross_device > 0 {
+        message.push_str(&format!(
+            "\n{} were on another filesystem, so they were copied there and the originals removed",
+            result.cross_device
+        ));
+    }
+    if let Some(manifest) = &result.manifest {
+        message.push_str(&format!(
+            "\nThe backup manifest is at {}",
+            manifest.to_string_lossy()
+        ));
+    }
+    message
+}
+
+/// Moves the files from the most recent apply back to their original names.
+fn undo_last_apply(s: &mut Cursive) {
+    let patterns: &mut AppState = s.user_data().unwrap();
+    if patterns.applying {
+        return;
+    }
+    let moved = match patterns.history.pop() {
+        Some(moved) => moved,
+        None => {
+            s.add_layer(Dialog::text("Nothing to undo").dismiss_button("Close"));
+            return;
+        }
+    };
+
+    clear_filter(s);
+    let result = undo_moves(&moved);
+    let mut table: ViewRef<RenameView> = s.find_name("file_table").unwrap();
+    relocate_items(table.borrow_items_mut(), &result.moved);
+    record_errors(table.borrow_items_mut(), &result);
+    drop(table);
+    refresh_status_column(s);
+    refresh_changed_count(s);
+
+    s.add_layer(
+        Dialog::text(format!("Restored {} files", result.moved.len()))
+            .title("Undo")
+            .dismiss_button("Close"),
+    );
+
+    show_failures(s, "Files could not be restored", &result.failures);
+}
+
+fn show_failures(s: &mut Cursive, message: &str, failures: &[String]) {
+    if !failures.is_empty() {
+        s.add_layer(
+            Dialog::text(format!("{}:\n {}", message, failures.join(",\n ")))
+                .title("Rename Error")
+                .dismiss_button("Close"),
+        );
+    }
+}
+
+fn set_error_message(s: &mut Cursive, message: &str) {
+    s.call_on_name("error_message", |v: &mut TextView| {
+        v.set_content(message);
+    });
+}
+
+fn hide_error_message(s: &mut Cursive) {
+    set_error_message(s, "");
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty scratch directory unique to this test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tui_rename_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn item_with_rename(dir: &std::path::Path, original: &str, renamed: &str) -> RenameItem {
+        let path = dir.join(original);
+        fs::write(&path, original).unwrap();
+        let mut item = RenameItem::new(path).unwrap();
+        item.renamed = renamed.to_string();
+        item
+    }
+
+    /// The order the items were loaded in, as if the table wasn't sorted.
+    fn loaded_order(items: &[RenameItem]) -> Vec<usize> {
+        (0..items.len()).collect()
+    }
+
+    fn assert_contents(dir: &Path, expected: &[(&str, &str)]) {
+        for (name, content) in expected {
+            assert_eq!(&fs::read_to_string(dir.join(name)).unwrap(), content);
+        }
+    }
+
+    fn parse_args(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        assert_eq!(
+            parse_args(&[
+                "a.txt",
+                "-r",
+                "--import",
+                "plan.csv",
+                "--dry-run",
+                "--no-confirm-quit",
+                "--copy",
+                "--respect-gitignore",
+                "--manifest",
+                "backup.json",
+                "--",
+                "--recursive"
+            ]),
+            Ok(Args {
+                recursive: true,
+                import: Some(PathBuf::from("plan.csv")),
+                dry_run: true,
+                no_confirm_quit: true,
+                copy: true,
+                manifest: Some(PathBuf::from("backup.json")),
+                restore: None,
+                undo_last: false,
+                theme: None,
+                respect_gitignore: true,
+                hidden: false,
+                log_file: None,
+                find: None,
+                replace: None,
+                json: false,
+                preset: None,
+                apply: false,
+                force: false,
+                conflict_policy: None,
+                paths: vec!["a.txt".to_string(), "--recursive".to_string()],
+            })
+        );
+        assert!(parse_args(&["a.txt", "--import"]).is_err());
+        assert_eq!(
+            parse_args(&["--restore", "backup.json"]).unwrap().restore,
+            Some(PathBuf::from("backup.json"))
+        );
+        assert!(parse_args(&["--undo-last"]).unwrap().undo_last);
+        assert_eq!(
+            parse_args(&["--theme", "dark"]).unwrap().theme,
+            Some(ColorTheme::Dark)
+        );
+        assert!(parse_args(&["--theme", "neon"]).is_err());
+        assert_eq!(
+            parse_args(&["--on-conflict", "suffix"])
+                .unwrap()
+                .conflict_policy,
+            Some(ConflictPolicy::Suffix)
+        );
+        assert!(parse_args(&["--on-conflict", "rename"]).is_err());
+        assert!(parse_args(&["--hidden"]).unwrap().hidden);
+        assert_eq!(
+            parse_args(&["--log-file", "run.log"]).unwrap().log_file,
+            Some(PathBuf::from("run.log"))
+        );
+        let headless = parse_args(&["--json", "--find", "a", "--replace", "b"]).unwrap();
+        assert!(headless.json);
+        assert_eq!(headless.find.as_deref(), Some("a"));
+        assert_eq!(headless.replace.as_deref(), Some("b"));
+        assert!(parse_args(&["--find"]).is_err());
+        assert_eq!(
+            parse_args(&["--preset", "remove numbering"])
+                .unwrap()
+                .preset
+                .as_deref(),
+            Some("remove numbering")
+        );
+        let forced = parse_args(&["--apply", "--force"]).unwrap();
+        assert!(forced.apply && forced.force);
+    }
+
+    #[test]
+    fn config_falls_back_to_defaults() {
+        let config: Config = toml::from_str("find_pattern = \"a\"\nliteral = true").unwrap();
+        assert_eq!(config.find_pattern, "a");
+        assert!(config.literal);
+        assert!(config.stem_only);
+
+        let saved = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<Config>(&saved).unwrap(), config);
+        assert!(toml::from_str::<Config>("literal = 3").is_err());
+        let themed: Config = toml::from_str("theme = \"basic\"").unwrap();
+        assert_eq!(themed.theme, ColorTheme::Basic);
+    }
+
+    #[test]
+    fn max_path_length_can_be_saved_off() {
+        for length in [None, Some(100)] {
+            let patterns = AppState {
+                patterns: RenamePatterns {
+                    max_path_length: length,
+                    ..RenamePatterns::default()
+                },
+                ..AppState::default()
+            };
+            let saved = toml::to_string(&Config::from(&patterns)).unwrap();
+            let mut loaded = AppState::default();
+            loaded.apply_config(&toml::from_str(&saved).unwrap());
+            assert_eq!(loaded.max_path_length, length);
+        }
+    }
+
+    #[test]
+    fn rules_are_saved_with_the_config() {
+        let config = Config {
+            literal: true,
+            rules: vec![("(1)".to_string(), "1".to_string())],
+            ..Config::default()
+        };
+        let saved = toml::to_string(&config).unwrap();
+        let loaded: Config = toml::from_str(&saved).unwrap();
+        assert_eq!(loaded.rules, config.rules);
+
+        let mut patterns = AppState::default();
+        patterns.apply_config(&loaded);
+        patterns.rules = patterns.compile_rules().unwrap();
+        assert_eq!(Config::from(&patterns), config);
+        let mut item = RenameItem::new(PathBuf::from("photo (1).jpg")).unwrap();
+        item.set_pattern(&patterns, &patterns.replacements(1, &item.file));
+        assert_eq!(item.renamed, "photo 1.jpg");
+
+        // A rule that only compiles in literal mode is an error without it
+        patterns.literal = false;
+        patterns.rules_raw[0].0 = "(".to_string();
+        assert!(patterns.compile_rules().is_err());
+    }
+
+    #[test]
+    fn presets_round_trip_through_toml() {
+        let presets = default_presets();
+        let saved = toml::to_string(&presets).unwrap();
+        assert_eq!(toml::from_str::<Presets>(&saved).unwrap(), presets);
+
+        let mut patterns = AppState::default();
+        patterns.apply_config(&presets["lowercase everything"]);
+        patterns.find_pat = patterns.compile().unwrap();
+        let mut item = RenameItem::new(PathBuf::from("Holiday.JPG")).unwrap();
+        item.set_pattern(&patterns, &patterns.replacements(1, &item.file));
+        assert_eq!(item.renamed, "holiday.jpg");
+    }
+
+    #[test]
+    fn plans_round_trip_through_csv() {
+        let dir = test_dir("plan_csv");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "one, \"two\".txt"),
+            item_with_rename(&dir, "b.txt", "plain.txt"),
+        ];
+        let plan_path = dir.join("plan.csv");
+        write_plan(&plan_path, &items).unwrap();
+
+        for item in &mut items {
+            item.renamed = item.original.clone();
+        }
+        let mut plan = read_plan(&plan_path).unwrap();
+        plan.push(("elsewhere.txt".to_string(), "x.txt".to_string()));
+        let unmatched = import_plan(&mut items, plan);
+
+        assert_eq!(items[0].renamed, "one, \"two\".txt");
+        assert_eq!(items[1].renamed, "plain.txt");
+        assert!(items.iter().all(|item| item.manually_edited));
+        assert_eq!(unmatched, ["elsewhere.txt"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn csv_handles_quoted_fields() {
+        assert_eq!(
+            parse_csv("a,\"b,c\"\r\n\"multi\nline\",\"say \"\"hi\"\"\"\n"),
+            [vec!["a", "b,c"], vec!["multi\nline", "say \"hi\""]]
+        );
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn paths_are_read_one_per_line() {
+        let input = "a.txt\n\n  spaced name.txt \r\n\t\nlast.txt";
+
+        assert_eq!(
+            read_paths(input.as_bytes()),
+            ["a.txt", "spaced name.txt", "last.txt"]
+        );
+    }
+
+    #[test]
+    fn destinations_are_shown_in_full() {
+        let dir = std::path::absolute(test_dir("destination_preview")).unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let shown = |path: &str| dir.join(path).to_string_lossy().to_string();
+
+        let item = item_with_rename(&dir, "a.txt", "b.txt");
+        assert_eq!(describe_destination(&item), shown("b.txt"));
+        let item = item_with_rename(&dir, "a.txt", "sub/b.txt");
+        assert_eq!(
+            describe_destination(&item),
+            format!("{} (moved to another directory)", shown("sub/b.txt"))
+        );
+        let item = item_with_rename(&dir, "a.txt", "new/b.txt");
+        assert_eq!(
+            describe_destination(&item),
+            format!(
+                "{} (moved to another directory, which doesn't exist yet)",
+                shown("new/b.txt")
+            )
+        );
+
+        // Matching the full path gives the destination as it is
+        let mut item = item_with_rename(&dir, "a.txt", &shown("sub/c.txt"));
+        item.whole_path = true;
+        assert_eq!(
+            describe_destination(&item),
+            format!("{} (moved to another directory)", shown("sub/c.txt"))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_first_line_of_the_clipboard_is_pasted() {
+        assert_eq!(clipboard_line(r"^(\d+)\s*-"), r"^(\d+)\s*-");
+        assert_eq!(clipboard_line("IMG_\\d+\r\n"), "IMG_\\d+");
+        assert_eq!(clipboard_line("first\nsecond\n"), "first");
+        assert_eq!(clipboard_line(""), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn slow_clipboard_commands_are_given_up_on() {
+        let timeout = Duration::from_millis(200);
+        assert_eq!(
+            command_output(&["printf", "a\nb"], timeout).as_deref(),
+            Some("a\nb")
+        );
+        assert_eq!(command_output(&["false"], timeout), None);
+        assert_eq!(
+            command_output(&["tui_rename_no_such_command"], timeout),
+            None
+        );
+
+        let started = Instant::now();
+        assert_eq!(command_output(&["sleep", "5"], timeout), None);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn response_files_add_their_paths() {
+        let dir = test_dir("response_files");
+        let list = dir.join("list.txt");
+        fs::write(&list, "# photos\na.jpg\n\n  b.jpg\n#c.jpg\n").unwrap();
+        let paths = [
+            "first.txt".to_string(),
+            format!("@{}", list.to_string_lossy()),
+            format!("@{}", dir.join("missing.txt").to_string_lossy()),
+            "@".to_string(),
+        ];
+
+        let mut failed_items = Vec::new();
+        assert_eq!(
+            expand_response_files(&paths, &mut failed_items),
+            ["first.txt", "a.jpg", "b.jpg", "@"]
+        );
+        assert_eq!(failed_items.len(), 1);
+        assert!(failed_items[0].starts_with(&dir.join("missing.txt").to_string_lossy().to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn matches_are_highlighted_in_the_stem() {
+        let patterns = RenamePatterns {
+            find_pat: Regex::new("o+|t").unwrap(),
+            ..RenamePatterns::default()
+        };
+
+        let styled = highlight_matches("foot.txt", &patterns);
+        let spans: Vec<_> = styled
+            .spans()
+            .map(|span| (span.content, span.attr.effects.contains(Effect::Bold)))
+            .collect();
+
+        assert_eq!(
+            spans,
+            [("f", false), ("oo", true), ("t", true), (".txt", false)]
+        );
+    }
+
+    #[test]
+    fn changed_items_sort_first_in_their_original_order() {
+        let mut items: Vec<RenameItem> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| RenameItem::new(PathBuf::from(name)).unwrap())
+            .collect();
+        items[1].renamed = "x".to_string();
+        items[3].renamed = "y".to_string();
+
+        items.sort_by(|a, b| a.cmp(b, RenameColumn::Changed));
+        let order: Vec<&str> = items.iter().map(|item| item.original.as_str()).collect();
+        assert_eq!(order, ["b", "d", "a", "c"]);
+    }
+
+    #[test]
+    fn unchanged_files_are_left_out_of_the_confirmation() {
+        let dir = test_dir("planned_changes");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "c.txt", "c.txt"),
+            item_with_rename(&dir, "d.txt", "e.txt"),
+        ];
+
+        let skipped = HashSet::from([dir.join("e.txt")]);
+        assert_eq!(
+            planned_changes(&items, &HashSet::new()),
+            vec!["a.txt → b.txt", "d.txt → e.txt"]
+        );
+        assert_eq!(planned_changes(&items, &skipped), vec!["a.txt → b.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn help_lists_every_binding() {
+        let lines = help_lines();
+        assert_eq!(lines.len(), KEYBINDINGS.len());
+        for (line, binding) in lines.iter().zip(KEYBINDINGS) {
+            assert!(line.starts_with(binding.key));
+            assert!(line.contains(binding.description));
+        }
+    }
+
+    #[test]
+    fn applied_renames_are_no_longer_pending() {
+        let dir = test_dir("pending_count");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "c.txt", "d.txt"),
+        ];
+        assert_eq!(pending_count(&items), 2);
+
+        perform_renames(&mut items[..1], &HashSet::new(), &HashSet::new(), false);
+        assert_eq!(pending_count(&items), 1);
+
+        // Settings rename files without any find pattern
+        let mut items = vec![item_with_rename(&dir, "E.TXT", "E.TXT")];
+        let patterns = RenamePatterns {
+            lowercase_extension: true,
+            ..RenamePatterns::default()
+        };
+        apply_patterns(&mut items, &[0], &patterns);
+        assert_eq!((changed_count(&items), pending_count(&items)), (1, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counter_follows_the_sort_order() {
+        let mut patterns = RenamePatterns {
+            find_pat_raw: ".+".to_string(),
+            replace_pat: "${n}".to_string(),
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = patterns.compile().unwrap();
+        let mut items: Vec<_> = ["b.txt", "a.txt", "c.txt"]
+            .iter()
+            .map(|name| RenameItem::new(PathBuf::from(name)).unwrap())
+            .collect();
+        let renamed = |items: &[RenameItem]| -> Vec<String> {
+            items.iter().map(|item| item.renamed.clone()).collect()
+        };
+
+        let ascending = visual_order(
+            &items,
+            Some((RenameColumn::Original, std::cmp::Ordering::Less)),
+        );
+        apply_patterns(&mut items, &ascending, &patterns);
+        assert_eq!(renamed(&items), ["2.txt", "1.txt", "3.txt"]);
+
+        let descending = visual_order(
+            &items,
+            Some((RenameColumn::Original, std::cmp::Ordering::Greater)),
+        );
+        apply_patterns(&mut items, &descending, &patterns);
+        assert_eq!(renamed(&items), ["2.txt", "3.txt", "1.txt"]);
+    }
+
+    #[test]
+    fn capture_groups_are_described() {
+        let mut patterns = RenamePatterns {
+            find_pat_raw: r"(?P<year>\d{4})_(\w+)(x)?".to_string(),
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = patterns.compile().unwrap();
+
+        assert_eq!(
+            describe_captures("2023_img.jpg", &patterns),
+            r#"1 <year> "2023"  2 "img"  3 -"#
+        );
+        assert_eq!(describe_captures("holiday.jpg", &patterns), "");
+    }
+
+    #[test]
+    fn manifests_restore_the_original_names() {
+        let dir = test_dir("manifest");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "c.txt"),
+            item_with_rename(&dir, "d.txt", "e.txt"),
+        ];
+        let manifest = dir.join("backup.json");
+        write_manifest(&manifest, &planned_moves(&items, &HashSet::new())).unwrap();
+        let read = read_manifest(&manifest).unwrap();
+        assert_eq!(read.renames.len(), 3);
+        assert!(read.renames.iter().all(|entry| entry.from.is_absolute()));
+
+        // Only part of the apply happened, as if it was interrupted
+        perform_renames(&mut items[1..], &HashSet::new(), &HashSet::new(), false);
+        assert_eq!(restore_manifest(&manifest), 0);
+        assert_contents(
+            &dir,
+            &[("a.txt", "a.txt"), ("b.txt", "b.txt"), ("d.txt", "d.txt")],
+        );
+        assert!(!dir.join("c.txt").exists() && !dir.join("e.txt").exists());
+
+        // The manifest is marked so the renames aren't undone twice
+        assert!(read_manifest(&manifest).unwrap().restored.is_some());
+        assert_eq!(restore_manifest(&manifest), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_newest_manifest_is_undone_last() {
+        let dir = test_dir("latest_manifest");
+        assert_eq!(latest_manifest(&dir), None);
+        for name in [
+            "renames-20240102-090000.json",
+            "renames-20240301-120000.json",
+            "renames-20231231-235959.json",
+            "notes.json",
+        ] {
+            fs::write(dir.join(name), "{}").unwrap();
+        }
+        assert_eq!(
+            latest_manifest(&dir),
+            Some(dir.join("renames-20240301-120000.json"))
+        );
+
+        let file = dir.join("notes.json");
+        let before = Local::now() - chrono::Duration::hours(1);
+        assert!(modified_since(&file, &before.to_rfc3339()));
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+        let after = DateTime::<Local>::from(modified) + chrono::Duration::seconds(1);
+        assert!(!modified_since(&file, &after.to_rfc3339()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removing_items_keeps_the_selection_in_range() {
+        let items: Vec<_> = ["a.txt", "b.txt", "c.txt", "d.txt"]
+            .iter()
+            .map(|name| RenameItem::new(PathBuf::from(name)).unwrap())
+            .collect();
+        let mut table = RenameView::new()
+            .column(RenameColumn::Original, "Original", |c| c)
+            .items(items);
+        let selected = |table: &RenameView| {
+            table
+                .borrow_item(table.item().unwrap())
+                .unwrap()
+                .original
+                .clone()
+        };
+
+        table.set_selected_row(2);
+        remove_item_keeping_selection(&mut table, 0);
+        assert_eq!(selected(&table), "c.txt");
+
+        table.set_selected_row(2);
+        remove_item_keeping_selection(&mut table, 2);
+        assert_eq!(table.row(), Some(1));
+        assert_eq!(selected(&table), "c.txt");
+
+        table.set_selected_row(0);
+        remove_item_keeping_selection(&mut table, 0);
+        assert_eq!(selected(&table), "c.txt");
+    }
+
+    #[test]
+    fn changes_are_diffed_by_character() {
+        assert_eq!(
+            char_diff("IMG_001.JPG", "photo_001.jpg"),
+            [
+                DiffPart::Removed("IMG".to_string()),
+                DiffPart::Added("photo".to_string()),
+                DiffPart::Same("_001.".to_string()),
+                DiffPart::Removed("JPG".to_string()),
+                DiffPart::Added("jpg".to_string()),
+            ]
+        );
+        assert_eq!(
+            char_diff("same", "same"),
+            [DiffPart::Same("same".to_string())]
+        );
+
+        let plain = render_diff(&char_diff("a-b", "a_b"), false);
+        assert_eq!(plain.source(), "a[---]{+_+}b");
+
+        // Long middles are swapped whole rather than diffed
+        let (old, new) = ("ab".repeat(600), "ba".repeat(600));
+        assert_eq!(
+            char_diff(&format!("dir/{}.txt", old), &format!("dir/{}.txt", new)),
+            [
+                DiffPart::Same("dir/".to_string()),
+                DiffPart::Removed(old),
+                DiffPart::Added(new),
+                DiffPart::Same(".txt".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn natural_sort_orders_numbers_by_value() {
+        let mut names = vec![
+            "file10.txt",
+            "file2.txt",
+            "file1.txt",
+            "file02.txt",
+            "file.txt",
+            "img9b.png",
+            "img9a.png",
+            "img10.png",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            [
+                "file.txt",
+                "file1.txt",
+                "file02.txt",
+                "file2.txt",
+                "file10.txt",
+                "img9a.png",
+                "img9b.png",
+                "img10.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn filtering_hides_items_until_they_are_shown_again() {
+        let items: Vec<_> = ["a1.txt", "b1.txt", "a2.txt", "b2.txt", "c.txt"]
+            .iter()
+            .map(|name| RenameItem::new(PathBuf::from(name)).unwrap())
+            .collect();
+        let mut table = RenameView::new()
+            .column(RenameColumn::Original, "Original", |c| c)
+            .items(items);
+        let shown = |table: &mut RenameView| -> Vec<String> {
+            table
+                .borrow_items()
+                .iter()
+                .map(|item| item.original.clone())
+                .collect()
+        };
+        let mut hidden = Vec::new();
+
+        hide_items(&mut table, "A", &mut hidden);
+        assert_eq!(shown(&mut table), ["a1.txt", "a2.txt"]);
+        assert_eq!(hidden.len(), 3);
+
+        unhide_items(&mut table, &mut hidden);
+        assert_eq!(
+            shown(&mut table),
+            ["a1.txt", "b1.txt", "a2.txt", "b2.txt", "c.txt"]
+        );
+        assert!(hidden.is_empty());
+
+        hide_items(&mut table, r"/^\w2", &mut hidden);
+        assert_eq!(shown(&mut table), ["a2.txt", "b2.txt"]);
+        unhide_items(&mut table, &mut hidden);
+        hide_items(&mut table, "", &mut hidden);
+        assert_eq!(shown(&mut table).len(), 5);
+    }
+
+    #[test]
+    fn declining_half_of_a_swap_skips_the_other_half() {
+        let dir = test_dir("declined_swap");
+        let items = vec![
+            item_with_rename(&dir, "a.txt", "b.txt"),
+            item_with_rename(&dir, "b.txt", "a.txt"),
+            item_with_rename(&dir, "c.txt", "d.txt"),
+        ];
+        let mut skipped = HashSet::from([dir.join("b.txt")]);
+
+        let blocked = skip_blocked_renames(&items, &mut skipped);
+        assert_eq!(blocked.len(), 1);
+        assert!(blocked[0].ends_with("→ a.txt"));
+        assert_eq!(
+            planned_changes(&items, &skipped),
+            [format!("{} → d.txt", items[2].display)]
+        );
+    }
+
+    #[test]
+    fn sizes_are_shown_and_sorted_by_bytes() {
+        let dir = test_dir("details");
+        let small = item_with_rename(&dir, "b.txt", "b.txt");
+        let large = item_with_rename(&dir, "a_much_longer_name.txt", "a.txt");
+        assert_eq!(small.to_column(RenameColumn::Size), "5 B");
+        assert_eq!(
+            small.cmp(&large, RenameColumn::Size),
+            std::cmp::Ordering::Less
+        );
+        assert_ne!(small.to_column(RenameColumn::Modified), "?");
+
+        let missing = RenameItem::new(dir.join("missing.txt")).unwrap();
+        assert_eq!(missing.to_column(RenameColumn::Size), "?");
+        assert_eq!(missing.to_column(RenameColumn::Modified), "?");
+
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Renames every name in `names` together, as `update_renames` does.
+    fn all_renamed_with(
+        patterns: &mut RenamePatterns,
+        find: &str,
+        replace: &str,
+        names: &[&str],
+    ) -> Vec<String> {
+        patterns.find_pat_raw = find.to_string();
+        patterns.replace_pat = replace.to_string();
+        patterns.find_pat = patterns.compile().unwrap();
+
+        let mut items: Vec<_> = names
+            .iter()
+            .map(|name| RenameItem::new(PathBuf::from(name)).unwrap())
+            .collect();
+        let order = loaded_order(&items);
+        apply_patterns(&mut items, &order, patterns);
+        items.into_iter().map(|item| item.renamed).collect()
+    }
+
+    #[test]
+    fn leading_numbers_are_removed() {
+        let mut patterns = RenamePatterns::default();
+        let names = [
+            "001_intro.md",
+            "12 - song.mp3",
+            "3.notes.txt",
+            "2024report.pdf",
+            "v2.txt",
+        ];
+        assert_eq!(
+            all_renamed_with(&mut patterns, REMOVE_NUMBERING_PATTERN, "", &names),
+            ["intro.md", "song.mp3", "notes.txt", "report.pdf", "v2.txt"]
+        );
+        assert!(default_presets().contains_key("remove numbering"));
+    }
+
+    #[test]
+    fn log_level_follows_rust_log() {
+        assert_eq!(log_level(None), log::LevelFilter::Info);
+        assert_eq!(log_level(Some("warn")), log::LevelFilter::Warn);
+        assert_eq!(
+            log_level(Some("regex=trace,tui_rename=debug")),
+            log::LevelFilter::Debug
+        );
+        assert_eq!(log_level(Some("cursive=trace")), log::LevelFilter::Info);
+        assert_eq!(log_level(Some("loud")), log::LevelFilter::Info);
+    }
+
+    #[test]
+    fn browsed_files_are_added_once() {
+        let dir = test_dir("browse");
+        fs::create_dir(dir.join("sub")).unwrap();
+        let existing = item_with_rename(&dir, "b.txt", "b.txt");
+        let new = item_with_rename(&dir, "a.txt", "a.txt");
+        item_with_rename(&dir, "c.txt", "c.txt");
+
+        let labels: Vec<String> = browse_entries(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(label, _)| label)
+            .collect();
+        assert_eq!(labels, ["sub/", "a.txt", "b.txt", "c.txt"]);
+
+        let paths = vec![
+            new.file.clone(),
+            dir.join("b.txt"),
+            dir.join("sub/../a.txt"),
+        ];
+        let (added, duplicates) = new_items([&existing].into_iter(), paths);
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].file, new.file);
+        assert_eq!(duplicates, 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn json_plans_list_renames_and_problems() {
+        let dir = test_dir("json_plan");
+        let mut items = vec![
+            item_with_rename(&dir, "a.txt", "a.txt"),
+            item_with_rename(&dir, "b.txt", "b.txt"),
+        ];
+        let mut patterns = RenamePatterns {
+            find_pat_raw: "[ab]".to_string(),
+            replace_pat: "c".to_string(),
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = patterns.compile().unwrap();
+
+        let plan = json_plan(&mut items, &patterns);
+        assert_eq!(
+            plan.renames,
+            [
+                PlannedRename {
+                    from: dir.join("a.txt"),
+                    to: dir.join("c.txt"),
+                },
+                PlannedRename {
+                    from: dir.join("b.txt"),
+                    to: dir.join("c.txt"),
+                },
+            ]
+        );
+        assert!(!plan.blocked);
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(
+            json["problems"]["conflicting_names"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Planned as `--apply` would make them
+        patterns.conflict_policy = ConflictPolicy::Suffix;
+        let plan = json_plan(&mut items, &patterns);
+        let targets: Vec<_> = plan.renames.iter().map(|rename| &rename.to).collect();
+        assert_eq!(targets, [&dir.join("c.txt"), &dir.join("c (1).txt")]);
+        assert!(plan.problems.conflicting_names.is_empty());
+        // The suffix is kept as a manual edit, so it's dropped to conflict again
+        items[1].manually_edited = false;
+        patterns.conflict_policy = ConflictPolicy::Skip;
+        assert!(json_plan(&mut items, &patterns).renames.is_empty());
+        patterns.conflict_policy = ConflictPolicy::Ask;
+
+        patterns.replace_pat = "".to_string();
+        patterns.stem_only = false;
+        patterns.find_pat_raw = ".*".to_string();
+        patterns.find_pat = patterns.compile().unwrap();
+        assert!(json_plan(&mut items, &patterns).blocked);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn applying_without_the_ui_needs_force_past_warnings() {
+        let dir = test_dir("apply_without_ui");
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        let config = Config {
+            find_pattern: "a".to_string(),
+            replace_pattern: "b".to_string(),
+            ..Config::default()
+        };
+        let mut args = Args {
+            apply: true,
+            paths: vec![dir.join("a.txt").to_string_lossy().to_string()],
+            ..Args::default()
+        };
+
+        // b.txt would be overwritten, which a saved policy doesn't allow alone
+        assert_eq!(apply_without_ui(&args, &config), 1);
+        let overwriting = Config {
+            conflict_policy: ConflictPolicy::Overwrite,
+            ..config.clone()
+        };
+        assert_eq!(apply_without_ui(&args, &overwriting), 1);
+        assert_contents(&dir, &[("a.txt", "a"), ("b.txt", "b")]);
+        args.conflict_policy = Some(ConflictPolicy::Skip);
+        assert_eq!(apply_without_ui(&args, &config), 0);
+        assert_contents(&dir, &[("a.txt", "a"), ("b.txt", "b")]);
+        args.conflict_policy = None;
+
+        args.force = true;
+        assert_eq!(apply_without_ui(&args, &config), 0);
+        assert!(!dir.join("a.txt").exists());
+        assert_contents(&dir, &[("b.txt", "a")]);
+
+        let broken = Config {
+            find_pattern: "(".to_string(),
+            ..config
+        };
+        assert_eq!(apply_without_ui(&args, &broken), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn moved_rows_set_the_counter_order() {
+        let items: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|name| RenameItem::new(PathBuf::from(name)).unwrap())
+            .collect();
+        let mut table = RenameView::new()
+            .column(RenameColumn::Original, "Original", |c| c)
+            .default_column(RenameColumn::Original)
+            .items(items);
+        let names = |table: &mut RenameView| -> Vec<String> {
+            row_order(table)
+                .into_iter()
+                .map(|index| table.borrow_item(index).unwrap().original.clone())
+                .collect()
+        };
+
+        table.set_selected_row(0);
+        assert!(move_row(&mut table, 1));
+        assert_eq!(names(&mut table), ["b.txt", "a.txt", "c.txt"]);
+        assert_eq!(table.row(), Some(1));
+        assert_eq!(
+            table.borrow_item(table.item().unwrap()).unwrap().original,
+            "a.txt"
+        );
+
+        table.set_selected_row(0);
+        assert!(!move_row(&mut table, -1));
+        table.set_selected_row(2);
+        assert!(!move_row(&mut table, 1));
+        assert_eq!(table.row(), Some(2));
+
+        let mut patterns = RenamePatterns {
+            find_pat_raw: ".*".to_string(),
+            replace_pat: "${n}".to_string(),
+            stem_only: false,
+            ..RenamePatterns::default()
+        };
+        patterns.find_pat = patterns.compile().unwrap();
+        let order = row_order(&mut table);
+        apply_patterns(table.borrow_items_mut(), &order, &patterns);
+        let renamed: Vec<_> = order
+            .iter()
+            .map(|&index| table.borrow_item(index).unwrap().renamed.clone())
+            .collect();
+        assert_eq!(renamed, ["1", "2", "3"]);
+        assert_eq!(names(&mut table), ["b.txt", "a.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn spaces_and_underscores_swap() {
+        let presets = default_presets();
+        let mut patterns = AppState::default();
+        let names = ["my  holiday photo.jpg", "tab\there.txt", "a__b_c.md"];
+
+        // Renamed with the presets' own patterns and settings
+        let mut renamed_with_preset = |name: &str| {
+            let preset = &presets[name];
+            patterns.apply_config(preset);
+            all_renamed_with(
+                &mut patterns,
+                &preset.find_pattern,
+                &preset.replace_pattern,
+                &names,
+            )
+        };
+        assert_eq!(
+            renamed_with_preset("spaces to underscores"),
+            ["my_holiday_photo.jpg", "tab_here.txt", "a__b_c.md"]
+        );
+        assert_eq!(
+            renamed_with_preset("underscores to spaces"),
+            ["my  holiday photo.jpg", "tab\there.txt", "a b c.md"]
+        );
+    }
+
+    #[test]
+    fn renames_wait_for_every_warning_shown() {
+        for shown in 1..32u32 {
+            let mut check = CheckResult::default();
+            let lists = [
+                &mut check.conflicting_names,
+                &mut check.unchanged_collisions,
+                &mut check.permission_problems,
+                &mut check.overwrite_targets,
+                &mut check.moved_names,
+            ];
+            for (bit, list) in lists.into_iter().enumerate() {
+                if shown & (1 << bit) != 0 {
+                    list.push("a.txt".to_string());
+                }
+            }
+            let mut warnings = WarningState::for_check(&check);
+            let mut names: Vec<&str> = warnings.pending.iter().copied().collect();
+            names.sort();
+            assert_eq!(names.len(), shown.count_ones() as usize);
+
+            // Only the last answer goes ahead, and only the once
+            let mut continued = WarningState::for_check(&check);
+            let (last, rest) = names.split_last().unwrap();
+            for name in rest {
+                assert!(!continued.answer(name, true));
+            }
+            assert!(!continued.answer("missing_dialog", true));
+            assert!(continued.answer(last, true));
+            assert!(!continued.answer(last, true));
+
+            // Cancelling any of them stops the renames, whatever the others say
+            for cancelled in &names {
+                warnings = WarningState::for_check(&check);
+                for name in &names {
+                    assert!(!warnings.answer(name, name != cancelled));
+                }
+                assert!(warnings.cancelled && warnings.pending.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn status_line_totals_the_files() {
+        let mut patterns = RenamePatterns::default();
+        let mut items: Vec<_> = ["a.txt", "b.txt", "c.log"]
+            .iter()
+            .map(|name| RenameItem::new(PathBuf::from(name)).unwrap())
+            .collect();
+        items[0].size = Some(1024);
+        items[1].size = Some(512);
+        assert_eq!(
+            status_line(items.iter()),
+            "3 files, total size 1.5 KiB (1 unreadable), 0 will change"
+        );
+
+        patterns.find_pat_raw = "^[ab]".to_string();
+        patterns.replace_pat = "new_$0".to_string();
+        patterns.find_pat = patterns.compile().unwrap();
+        let order = loaded_order(&items);
+        apply_patterns(&mut items, &order, &patterns);
+        items[2].size = Some(0);
+        assert_eq!(
+            status_line(items[..1].iter().chain(&items[1..])),
+            "3 files, total size 1.5 KiB, 2 will change"
+        );
+    }
 }